@@ -0,0 +1,122 @@
+//Connection scaling of the reactor: opens far more connections than ThreadPool has threads,
+//keeps them all open at once, then does a subscribe round trip on every one of them.
+//
+//    cargo bench --bench reactor
+//
+//Each connection takes two file descriptors (client and server end), raise `ulimit -n`
+//for the larger runs.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use finance_app::pubsub::{Broker, PubSubHandler};
+use finance_app::websockets::{Router, WebSocketConfig};
+use finance_app::workers::Reactor;
+use socket2::{Domain, Socket, Type};
+
+const THREADS: usize = 2;
+const CONNECTIONS: [usize; 4] = [10, 100, 1000, 5000];
+
+const UPGRADE_REQUEST: &[u8] = b"GET /ws HTTP/1.1\r\n\
+    Host: 127.0.0.1\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+const WELCOME: &str = r#"{"type":"welcome"}"#;
+const SUBSCRIBE: &str = r#"{"type":"subscribe","topic":"prices:AAPL"}"#;
+const ACK: &str = r#"{"type":"ack","topic":"prices:AAPL"}"#;
+
+fn main() {
+    let listener = listen();
+    let address = listener.local_addr().unwrap();
+    let config = WebSocketConfig {
+        heartbeat: None,
+        ..WebSocketConfig::default()
+    };
+    let router = Router::new();
+    let reactor = Reactor::with_config(THREADS, std::sync::Arc::clone(&router), config).unwrap();
+    let broker = Broker::new(std::sync::Arc::clone(reactor.registry()));
+    router.route("/ws", PubSubHandler::new(broker));
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            reactor.execute(stream);
+        }
+    });
+
+    println!("reactor with {} event loop threads", THREADS);
+    for connections in CONNECTIONS {
+        match run(address, connections) {
+            Ok((handshakes, round_trip)) => println!(
+                "{:>6} connections: handshakes {:>9.2?} ({:>7.0}/s), subscribe on all {:>9.2?} ({:>7.0} msg/s)",
+                connections,
+                handshakes,
+                connections as f64 / handshakes.as_secs_f64(),
+                round_trip,
+                connections as f64 / round_trip.as_secs_f64(),
+            ),
+            Err(e) => {
+                println!("{:>6} connections: {}", connections, e);
+                break;
+            }
+        }
+    }
+}
+
+// The clients connect faster than connections are accepted, std's backlog of 128 would
+// overflow and leave the rest waiting on SYN retransmits
+fn listen() -> TcpListener {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    socket.bind(&address.into()).unwrap();
+    socket.listen(8192).unwrap();
+    socket.into()
+}
+
+// Returns how long the handshakes took, and the subscribe round trip on every connection
+fn run(address: SocketAddr, connections: usize) -> std::io::Result<(Duration, Duration)> {
+    // A masked frame with an all zero mask, so the payload goes out as is
+    let mut subscribe = vec![0x81, 0x80 | SUBSCRIBE.len() as u8, 0, 0, 0, 0];
+    subscribe.extend_from_slice(SUBSCRIBE.as_bytes());
+    let mut ack = vec![0x81, ACK.len() as u8];
+    ack.extend_from_slice(ACK.as_bytes());
+
+    let start = Instant::now();
+    let mut clients = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        let mut client = TcpStream::connect(address)?;
+        client.set_read_timeout(Some(Duration::from_secs(10)))?;
+        client.write_all(UPGRADE_REQUEST)?;
+        clients.push(client);
+    }
+    for client in &mut clients {
+        read_response(client)?;
+        client.read_exact(&mut [0; 2 + WELCOME.len()])?;
+    }
+    let handshakes = start.elapsed();
+
+    let start = Instant::now();
+    for client in &mut clients {
+        client.write_all(&subscribe)?;
+    }
+    for client in &mut clients {
+        let mut answer = vec![0; ack.len()];
+        client.read_exact(&mut answer)?;
+        assert_eq!(answer, ack);
+    }
+    Ok((handshakes, start.elapsed()))
+}
+
+fn read_response(client: &mut TcpStream) -> std::io::Result<()> {
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte)?;
+        response.push(byte[0]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 101"));
+    Ok(())
+}
@@ -1,17 +1,59 @@
-use finance_app::workers::ThreadPool;
+use finance_app::pubsub::{Broker, PubSubHandler, SessionConfig};
+use finance_app::rpc::{Dispatcher, RpcError, RpcHandler};
+use finance_app::websockets::{ConnectionInfo, Router, TlsConfig, WebSocketConfig};
+use finance_app::workers::Reactor;
 use std::net::TcpListener;
+use std::sync::Arc;
 
 fn main() -> std::io::Result<()> {
     let listener = TcpListener::bind("127.0.0.1:8080").expect("Failed to bind to address");
-    println!("WebSocket server listening on port 8080");
 
-    let pool = ThreadPool::new(4);
+    let config = WebSocketConfig::from_env();
+
+    // One event loop per core, each serving any number of connections
+    let threads = std::thread::available_parallelism().map_or(4, |threads| threads.get());
+    let router = Router::new();
+    // Serve wss when TLS_CERT_PATH and TLS_KEY_PATH point to a certificate and key
+    let reactor = match TlsConfig::from_env() {
+        Some(tls) => {
+            let tls = tls.load()?;
+            println!("WebSocket server listening on port 8080 (wss)");
+            Reactor::with_tls(threads, Arc::clone(&router), config, tls)?
+        }
+        None => {
+            println!("WebSocket server listening on port 8080 (ws, unencrypted)");
+            println!("Set TLS_CERT_PATH and TLS_KEY_PATH to serve wss");
+            Reactor::with_config(threads, Arc::clone(&router), config)?
+        }
+    };
+
+    // Clients that reconnect within the resume timeout get the events they missed
+    let broker = Broker::with_sessions(Arc::clone(reactor.registry()), SessionConfig::default());
+    let dispatcher = Dispatcher::new();
+    dispatcher.register("ping", |(): (), _| Ok("pong"));
+    let subscriptions = Arc::clone(&broker);
+    dispatcher.register(
+        "subscriptions",
+        move |(): (), connection: Option<&ConnectionInfo>| {
+            let connection =
+                connection.ok_or_else(|| RpcError::new(-32000, "Connection not registered"))?;
+            Ok(subscriptions.subscriptions(connection.id))
+        },
+    );
+
+    // Streams only, calls only, or both on one connection
+    router.route("/ws/prices", PubSubHandler::new(Arc::clone(&broker)));
+    router.route("/ws/ledger", RpcHandler::new(Arc::clone(&dispatcher)));
+    router.route(
+        "/ws",
+        RpcHandler::with_fallback(dispatcher, PubSubHandler::new(broker)),
+    );
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 println!("New connection: {:?}", stream);
-                pool.execute(stream); // Send to thread pool instead of spawning new thread
+                reactor.execute(stream);
             }
             Err(e) => {
                 eprintln!("Failed to establish a connection: {}", e);
@@ -0,0 +1,162 @@
+use finance_app::pubsub::{Broker, PubSubHandler, SessionConfig};
+use finance_app::rpc::{Dispatcher, RpcError, RpcHandler};
+use finance_app::websockets::{
+    accept_tls_async, AsyncWebSocket, ConnectionInfo, Message, Registry, Router, Session,
+    TlsConfig, WebSocketConfig,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+// Same server as the websocket binary, with a task per connection instead of a thread
+// from the pool, so the number of connections isn't bounded by the number of threads
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:8080")
+        .await
+        .expect("Failed to bind to address");
+
+    let config = Arc::new(WebSocketConfig::from_env());
+    let registry = Registry::new();
+    // Clients that reconnect within the resume timeout get the events they missed
+    let broker = Broker::with_sessions(Arc::clone(&registry), SessionConfig::default());
+    let dispatcher = Dispatcher::new();
+    dispatcher.register("ping", |(): (), _| Ok("pong"));
+    let subscriptions = Arc::clone(&broker);
+    dispatcher.register(
+        "subscriptions",
+        move |(): (), connection: Option<&ConnectionInfo>| {
+            let connection =
+                connection.ok_or_else(|| RpcError::new(-32000, "Connection not registered"))?;
+            Ok(subscriptions.subscriptions(connection.id))
+        },
+    );
+
+    // Streams only, calls only, or both on one connection
+    let router = Router::new();
+    router.route("/ws/prices", PubSubHandler::new(Arc::clone(&broker)));
+    router.route("/ws/ledger", RpcHandler::new(Arc::clone(&dispatcher)));
+    router.route(
+        "/ws",
+        RpcHandler::with_fallback(dispatcher, PubSubHandler::new(broker)),
+    );
+
+    // Serve wss when TLS_CERT_PATH and TLS_KEY_PATH point to a certificate and key
+    let tls = match TlsConfig::from_env() {
+        Some(tls) => {
+            println!("Async WebSocket server listening on port 8080 (wss)");
+            Some(tls.load()?)
+        }
+        None => {
+            println!("Async WebSocket server listening on port 8080 (ws, unencrypted)");
+            println!("Set TLS_CERT_PATH and TLS_KEY_PATH to serve wss");
+            None
+        }
+    };
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to establish a connection: {}", e);
+                continue;
+            }
+        };
+        println!("New connection: {}", addr);
+
+        let config = Arc::clone(&config);
+        let tls = tls.clone();
+        let router = Arc::clone(&router);
+        let registry = Arc::clone(&registry);
+        tokio::spawn(async move {
+            match tls {
+                // The handshake timeout covers the TLS handshake too
+                Some(tls) => {
+                    let accepted = match config.handshake_timeout {
+                        Some(timeout) => {
+                            tokio::time::timeout(timeout, accept_tls_async(stream, &tls))
+                                .await
+                                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                        }
+                        None => accept_tls_async(stream, &tls).await,
+                    };
+                    match accepted {
+                        Ok(stream) => {
+                            handle_connection(stream, addr, &config, &router, &registry).await
+                        }
+                        Err(e) => println!("TLS handshake failed: {}", e),
+                    }
+                }
+                None => handle_connection(stream, addr, &config, &router, &registry).await,
+            }
+        });
+    }
+}
+
+// Drives the handler until the connection is over. It is registered meanwhile, what is
+// pushed to it goes out while it waits for the client.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    remote_addr: SocketAddr,
+    config: &WebSocketConfig,
+    router: &Router,
+    registry: &Arc<Registry>,
+) {
+    let (mut ws, request, handler) =
+        match AsyncWebSocket::accept_routed(stream, config, router).await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+
+    if let Some(heartbeat) = config.heartbeat {
+        ws.enable_heartbeat(heartbeat);
+    }
+
+    let registration = ws.register(registry, Some(remote_addr), config.send_queue);
+    let mut session = Session::new(Some(registration.id()), &request, Arc::clone(registry));
+    handler.on_open(&mut session);
+
+    let frame = loop {
+        // What the handler queued goes out before reading the next message
+        let (messages, close) = session.take_outgoing();
+        let mut sent = Ok(());
+        for message in messages {
+            sent = ws.send_message(message).await;
+            if sent.is_err() {
+                break;
+            }
+        }
+        if let (Ok(()), Some(frame)) = (&sent, close) {
+            sent = ws.close(frame.code, &frame.reason).await;
+        }
+        if let Err(e) = sent {
+            eprintln!("Failed to send message: {}", e);
+            handler.on_error(&session, &e);
+            break None;
+        }
+
+        match ws.read_message().await {
+            Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
+                handler.on_message(&mut session, message);
+            }
+            // read_message already answered the close frame
+            Ok(Message::Close(frame)) => {
+                println!("Connection closed");
+                break frame;
+            }
+            // and the pings
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to read message: {}", e);
+                handler.on_error(&session, &e);
+                break None;
+            }
+        }
+    };
+    handler.on_close(&session, frame.as_ref());
+    drop(registration);
+}
@@ -1,3 +1,5 @@
+pub mod pubsub;
+pub mod rpc;
 pub mod tcp;
 pub mod websockets;
 pub mod workers;
@@ -0,0 +1,470 @@
+//Keeps who is subscribed to what and fans published events out to them through the registry.
+//Exact topics are looked up directly, only wildcard patterns are matched one by one.
+//With sessions, the subscriptions of a closed connection stay until its session is resumed on
+//another connection or expires, and its events are buffered meanwhile.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::iter;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_json::Value;
+
+use super::protocol::{ClientMessage, ServerMessage};
+use super::session::{SessionState, Sessions};
+use super::topic;
+use super::{ResumeError, SessionConfig};
+use crate::websockets::{ConnectionId, ConnectionInfo, Message, Registry};
+
+// Subscriptions a single connection can hold
+pub const MAX_SUBSCRIPTIONS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PubSubError {
+    BadRequest(String),
+    InvalidTopic(&'static str),
+    Forbidden,            // refused by the authorization hook of the namespace
+    NotSubscribed,        // unsubscribing from a topic the connection isn't subscribed to
+    TooManySubscriptions, // MAX_SUBSCRIPTIONS reached
+    Unavailable,          // the connection isn't registered, nothing can be pushed to it
+}
+
+impl PubSubError {
+    // Machine readable code sent in error messages
+    pub fn code(&self) -> &'static str {
+        match self {
+            PubSubError::BadRequest(_) => "bad_request",
+            PubSubError::InvalidTopic(_) => "invalid_topic",
+            PubSubError::Forbidden => "forbidden",
+            PubSubError::NotSubscribed => "not_subscribed",
+            PubSubError::TooManySubscriptions => "too_many_subscriptions",
+            PubSubError::Unavailable => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for PubSubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PubSubError::BadRequest(reason) => write!(f, "{}", reason),
+            PubSubError::InvalidTopic(reason) => write!(f, "{}", reason),
+            PubSubError::Forbidden => write!(f, "Not allowed to subscribe to this topic"),
+            PubSubError::NotSubscribed => write!(f, "Not subscribed to this topic"),
+            PubSubError::TooManySubscriptions => write!(
+                f,
+                "A connection can't hold more than {} subscriptions",
+                MAX_SUBSCRIPTIONS
+            ),
+            PubSubError::Unavailable => {
+                write!(f, "Subscriptions aren't available on this connection")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PubSubError {}
+
+// Decides whether a connection may subscribe to a pattern of the namespace it's registered for
+pub type Authorizer = Arc<dyn Fn(&ConnectionInfo, &str) -> bool + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    exact: HashMap<String, HashSet<ConnectionId>>, // topic -> subscribers
+    wildcard: HashMap<String, HashSet<ConnectionId>>, // pattern -> subscribers
+    by_connection: HashMap<ConnectionId, HashSet<String>>,
+}
+
+impl Subscriptions {
+    fn subscribers(&mut self, pattern: &str) -> &mut HashMap<String, HashSet<ConnectionId>> {
+        if topic::is_wildcard(pattern) {
+            &mut self.wildcard
+        } else {
+            &mut self.exact
+        }
+    }
+
+    fn remove(&mut self, id: ConnectionId, pattern: &str) {
+        let subscribers = self.subscribers(pattern);
+        if let Some(ids) = subscribers.get_mut(pattern) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                subscribers.remove(pattern);
+            }
+        }
+    }
+}
+
+// Where an event goes: straight to a connection, or through its session
+enum Subscriber {
+    Connection(ConnectionId),
+    Session(Arc<Mutex<SessionState>>),
+}
+
+pub struct Broker {
+    registry: Arc<Registry>,
+    subscriptions: Mutex<Subscriptions>,
+    authorizers: RwLock<HashMap<String, Authorizer>>, // by namespace
+    // Locked before subscriptions when both are
+    sessions: Option<Mutex<Sessions>>,
+}
+
+impl fmt::Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Broker")
+            .field("registry", &self.registry)
+            .field("subscriptions", &self.subscriptions)
+            .field("sessions", &self.sessions)
+            .finish()
+    }
+}
+
+impl Broker {
+    pub fn new(registry: Arc<Registry>) -> Arc<Broker> {
+        Arc::new(Broker {
+            registry,
+            subscriptions: Mutex::new(Subscriptions::default()),
+            authorizers: RwLock::new(HashMap::new()),
+            sessions: None,
+        })
+    }
+
+    /// Same as new, keeping a resumable session for every connection that
+    /// opens one
+    pub fn with_sessions(registry: Arc<Registry>, config: SessionConfig) -> Arc<Broker> {
+        Arc::new(Broker {
+            registry,
+            subscriptions: Mutex::new(Subscriptions::default()),
+            authorizers: RwLock::new(HashMap::new()),
+            sessions: Some(Mutex::new(Sessions::new(config))),
+        })
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Sets the hook deciding who may subscribe to the topics of `namespace`,
+    /// their first segment (`account` for `account:42:transactions`). It gets
+    /// the connection, with the user the server set on it, and the pattern
+    /// asked for. Namespaces without a hook are open to every connection. It's
+    /// asked again when a session is resumed, the broker is locked then and the
+    /// hook must not call back into it.
+    pub fn authorize(
+        &self,
+        namespace: &str,
+        hook: impl Fn(&ConnectionInfo, &str) -> bool + Send + Sync + 'static,
+    ) {
+        self.authorizers
+            .write()
+            .unwrap()
+            .insert(namespace.to_owned(), Arc::new(hook));
+    }
+
+    /// Subscribes a registered connection to a topic or wildcard pattern, once
+    /// the authorization hook of its namespace agreed. Subscribing twice is
+    /// the same as once.
+    pub fn subscribe(&self, id: ConnectionId, pattern: &str) -> Result<(), PubSubError> {
+        topic::validate_pattern(pattern)?;
+        let info = self.registry.get(id).ok_or(PubSubError::Unavailable)?;
+        if !self.allowed(&info, pattern) {
+            return Err(PubSubError::Forbidden);
+        }
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let patterns = subscriptions.by_connection.entry(id).or_default();
+        if patterns.contains(pattern) {
+            return Ok(());
+        }
+        if patterns.len() >= MAX_SUBSCRIPTIONS {
+            return Err(PubSubError::TooManySubscriptions);
+        }
+        patterns.insert(pattern.to_owned());
+        subscriptions
+            .subscribers(pattern)
+            .entry(pattern.to_owned())
+            .or_default()
+            .insert(id);
+        Ok(())
+    }
+
+    // Whether the hook of the namespace of `pattern`, if any, lets the connection have it
+    fn allowed(&self, info: &ConnectionInfo, pattern: &str) -> bool {
+        let authorizer = self
+            .authorizers
+            .read()
+            .unwrap()
+            .get(topic::namespace(pattern))
+            .cloned();
+        authorizer.is_none_or(|authorizer| authorizer(info, pattern))
+    }
+
+    pub fn unsubscribe(&self, id: ConnectionId, pattern: &str) -> Result<(), PubSubError> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let removed = subscriptions
+            .by_connection
+            .get_mut(&id)
+            .is_some_and(|patterns| patterns.remove(pattern));
+        if !removed {
+            return Err(PubSubError::NotSubscribed);
+        }
+        subscriptions.remove(id, pattern);
+        Ok(())
+    }
+
+    /// Issues a session to a registered connection, its id goes in the welcome
+    /// message. None when the broker doesn't keep sessions.
+    pub fn open_session(&self, id: ConnectionId) -> Option<String> {
+        let mut sessions = self.sessions.as_ref()?.lock().unwrap();
+        self.expire(&mut sessions);
+        Some(sessions.open(id))
+    }
+
+    /// Drops every subscription of a connection once it's closed, unless its
+    /// session keeps them until it's resumed or expires
+    pub fn disconnect(&self, id: ConnectionId) {
+        if let Some(sessions) = &self.sessions {
+            let mut sessions = sessions.lock().unwrap();
+            let detached = sessions.detach(id);
+            self.expire(&mut sessions);
+            if detached {
+                return;
+            }
+        }
+        self.drop_subscriptions(id);
+    }
+
+    fn drop_subscriptions(&self, id: ConnectionId) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for pattern in subscriptions.by_connection.remove(&id).unwrap_or_default() {
+            subscriptions.remove(id, &pattern);
+        }
+    }
+
+    fn expire(&self, sessions: &mut Sessions) {
+        for id in sessions.expire() {
+            self.drop_subscriptions(id);
+        }
+    }
+
+    // Hands the subscriptions of a session over to the connection resuming it
+    fn move_subscriptions(&self, from: ConnectionId, to: ConnectionId) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let patterns = subscriptions
+            .by_connection
+            .remove(&from)
+            .unwrap_or_default();
+        for pattern in &patterns {
+            subscriptions.remove(from, pattern);
+            subscriptions
+                .subscribers(pattern)
+                .entry(pattern.clone())
+                .or_default()
+                .insert(to);
+        }
+        subscriptions.by_connection.insert(to, patterns);
+    }
+
+    // Attaches a session to `connection` in place of the one it was welcomed with, then sends
+    // the answer and the events after `last_seq`. Through the registry, with the session
+    // locked, so nothing published meanwhile gets ahead of them. Only a session whose
+    // connection closed is taken over, and only if the new connection may have every one
+    // of its subscriptions: they were authorized for the connection that made them.
+    fn resume(
+        &self,
+        connection: ConnectionId,
+        request: Option<u64>,
+        session: &str,
+        last_seq: u64,
+    ) -> Result<(), ResumeError> {
+        let Some(sessions) = &self.sessions else {
+            return Err(ResumeError::UnknownSession);
+        };
+        // Closed already, there is no one to answer
+        let Some(info) = self.registry.get(connection) else {
+            return Ok(());
+        };
+        let mut sessions = sessions.lock().unwrap();
+        self.expire(&mut sessions);
+        let state = sessions.get(session).ok_or(ResumeError::UnknownSession)?;
+        let mut locked = state.lock().unwrap();
+        if locked.key != connection {
+            if locked.attached {
+                return Err(ResumeError::Attached);
+            }
+            let forbidden = self
+                .subscriptions(locked.key)
+                .into_iter()
+                .find(|pattern| !self.allowed(&info, pattern));
+            if let Some(pattern) = forbidden {
+                return Err(ResumeError::Forbidden(pattern));
+            }
+        }
+        let replay = locked.replay(last_seq)?;
+        if locked.key != connection {
+            sessions.remove(connection);
+            self.drop_subscriptions(connection);
+            let previous = sessions.attach(&state, &mut locked, connection);
+            self.move_subscriptions(previous, connection);
+        }
+        drop(sessions);
+
+        let resumed = ServerMessage::Resumed {
+            id: request,
+            session: session.to_owned(),
+            seq: locked.seq,
+        };
+        for message in iter::once(resumed.to_message()).chain(replay) {
+            // Closed already, the session is detached again
+            if self.registry.send_to(connection, message).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn subscriptions(&self, id: ConnectionId) -> Vec<String> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        match subscriptions.by_connection.get(&id) {
+            Some(patterns) => patterns.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sends an event to every connection subscribed to `topic`, directly or
+    /// through a pattern, once even if several of its subscriptions match.
+    /// Sessions number it and keep it for replay, detached ones only that.
+    /// Returns how many connections it was queued for.
+    pub fn publish(&self, topic: &str, data: Value) -> Result<usize, PubSubError> {
+        topic::validate_topic(topic)?;
+
+        let (subscribers, replay_buffer) = {
+            let sessions = self
+                .sessions
+                .as_ref()
+                .map(|sessions| sessions.lock().unwrap());
+            let subscriptions = self.subscriptions.lock().unwrap();
+            let exact = subscriptions.exact.get(topic).into_iter().flatten();
+            let wildcard = subscriptions
+                .wildcard
+                .iter()
+                .filter(|(pattern, _)| topic::matches(pattern, topic))
+                .flat_map(|(_, ids)| ids);
+            let ids: HashSet<ConnectionId> = exact.chain(wildcard).copied().collect();
+            let subscribers: Vec<Subscriber> = ids
+                .into_iter()
+                .map(|id| {
+                    match sessions
+                        .as_ref()
+                        .and_then(|sessions| sessions.by_connection(id))
+                    {
+                        Some(state) => Subscriber::Session(state),
+                        None => Subscriber::Connection(id),
+                    }
+                })
+                .collect();
+            let replay_buffer = sessions.map_or(0, |sessions| sessions.config.replay_buffer);
+            (subscribers, replay_buffer)
+        };
+        if subscribers.is_empty() {
+            return Ok(0);
+        }
+
+        let event = |seq| {
+            ServerMessage::Event {
+                topic: topic.to_owned(),
+                data: data.clone(),
+                seq,
+            }
+            .to_message()
+        };
+        let mut unsent = None;
+        let mut sent = 0;
+        let mut closed = Vec::new();
+        for subscriber in subscribers {
+            let result = match subscriber {
+                Subscriber::Connection(id) => {
+                    let message = unsent.get_or_insert_with(|| event(None)).clone();
+                    (id, self.registry.send_to(id, message))
+                }
+                Subscriber::Session(state) => {
+                    let mut state = state.lock().unwrap();
+                    let message = state.record(|seq| event(Some(seq)), replay_buffer);
+                    if !state.attached {
+                        continue;
+                    }
+                    (state.key, self.registry.send_to(state.key, message))
+                }
+            };
+            match result {
+                (_, Ok(())) => sent += 1,
+                // Closed in the meantime, the registry forgot it already
+                (id, Err(_)) => closed.push(id),
+            }
+        }
+        for id in closed {
+            self.disconnect(id);
+        }
+        Ok(sent)
+    }
+
+    /// Answers a message of the client with an ack or an error. `connection`
+    /// is None when the connection isn't registered, subscribing fails then.
+    /// Returns None for control messages, they aren't for the broker, and for
+    /// resumed sessions, answered through the registry ahead of the replay.
+    pub fn handle_message(
+        &self,
+        connection: Option<ConnectionId>,
+        message: &Message,
+    ) -> Option<Message> {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(_) => {
+                let error = PubSubError::BadRequest("Expected a JSON text message".to_owned());
+                return Some(ServerMessage::error(None, &error).to_message());
+            }
+            _ => return None,
+        };
+
+        let request = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = PubSubError::BadRequest(e.to_string());
+                return Some(ServerMessage::error(None, &error).to_message());
+            }
+        };
+        let (id, topic, result) = match request {
+            ClientMessage::Subscribe { id, topic } => {
+                let result = connection
+                    .ok_or(PubSubError::Unavailable)
+                    .and_then(|connection| self.subscribe(connection, &topic));
+                (id, topic, result)
+            }
+            ClientMessage::Unsubscribe { id, topic } => {
+                let result = connection
+                    .ok_or(PubSubError::NotSubscribed)
+                    .and_then(|connection| self.unsubscribe(connection, &topic));
+                (id, topic, result)
+            }
+            ClientMessage::Resume {
+                id,
+                session,
+                last_seq,
+            } => {
+                let Some(connection) = connection.filter(|_| self.sessions.is_some()) else {
+                    let error = PubSubError::Unavailable;
+                    return Some(ServerMessage::error(id, &error).to_message());
+                };
+                return match self.resume(connection, id, &session, last_seq) {
+                    Ok(()) => None,
+                    Err(error) => Some(ServerMessage::resync(id, &error).to_message()),
+                };
+            }
+        };
+
+        let answer = match result {
+            Ok(()) => ServerMessage::Ack { id, topic },
+            Err(error) => ServerMessage::error(id, &error),
+        };
+        Some(answer.to_message())
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use super::{Broker, ServerMessage};
+use crate::websockets::{CloseFrame, Message, Session, WebSocketHandler};
+
+// Serves the pub/sub protocol: greets the client, answers its subscriptions and drops them
+// once the connection is over, or detaches its session. Events reach the connection through
+// the registry.
+#[derive(Debug, Clone)]
+pub struct PubSubHandler {
+    broker: Arc<Broker>,
+}
+
+impl PubSubHandler {
+    pub fn new(broker: Arc<Broker>) -> Self {
+        PubSubHandler { broker }
+    }
+}
+
+impl WebSocketHandler for PubSubHandler {
+    fn on_open(&self, session: &mut Session) {
+        // With the id of its session, if the broker keeps them
+        let id = session.id().and_then(|id| self.broker.open_session(id));
+        session.send(ServerMessage::Welcome { session: id }.to_message());
+    }
+
+    fn on_message(&self, session: &mut Session, message: Message) {
+        if let Some(answer) = self.broker.handle_message(session.id(), &message) {
+            session.send(answer);
+        }
+    }
+
+    fn on_close(&self, session: &Session, _frame: Option<&CloseFrame>) {
+        if let Some(id) = session.id() {
+            self.broker.disconnect(id);
+        }
+    }
+}
@@ -0,0 +1,13 @@
+mod broker;
+mod handler;
+mod protocol;
+mod session;
+#[cfg(test)]
+mod tests;
+mod topic;
+
+pub use broker::{Authorizer, Broker, PubSubError, MAX_SUBSCRIPTIONS};
+pub use handler::PubSubHandler;
+pub use protocol::{welcome, ClientMessage, ServerMessage};
+pub use session::{ResumeError, SessionConfig};
+pub use topic::{matches, validate_pattern, validate_topic, MAX_TOPIC_LENGTH};
@@ -0,0 +1,102 @@
+//JSON messages of the pub/sub protocol, one per WebSocket text message, told apart by "type":
+//  client: {"type":"subscribe","id":1,"topic":"prices:*"}, same with "unsubscribe"
+//  server: {"type":"ack","id":1,"topic":"prices:*"} or {"type":"error","id":1,"code":..,"message":..}
+//          {"type":"event","topic":"prices:AAPL","data":{..}} for every event published
+//The id is optional and only echoed back, so the client can match answers to its requests.
+//
+//With sessions, the welcome carries the session id and events their seq, "seq":1 onwards:
+//  client: {"type":"resume","id":2,"session":"..","last_seq":41} on a new connection
+//  server: {"type":"resumed","id":2,"session":"..","seq":57} then the events 42 to 57 again,
+//          or {"type":"resync","id":2,"code":..,"message":..} when the session can't be resumed
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{PubSubError, ResumeError};
+use crate::websockets::Message;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientMessage {
+    Subscribe {
+        id: Option<u64>,
+        topic: String,
+    },
+    Unsubscribe {
+        id: Option<u64>,
+        topic: String,
+    },
+    Resume {
+        id: Option<u64>,
+        session: String,
+        last_seq: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    // Sent once the connection is open
+    Welcome {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+    },
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        topic: String,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        code: &'static str,
+        message: String,
+    },
+    Event {
+        topic: String,
+        data: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Resumed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        session: String,
+        seq: u64, // of the last event, replayed right after
+    },
+    // The session is lost, the connection keeps the one it was welcomed with
+    Resync {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    pub fn error(id: Option<u64>, error: &PubSubError) -> Self {
+        ServerMessage::Error {
+            id,
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn resync(id: Option<u64>, error: &ResumeError) -> Self {
+        ServerMessage::Resync {
+            id,
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        // Only fails on maps with non-string keys, which Value never has
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+}
+
+// Greets a client, the first message on every connection
+pub fn welcome() -> Message {
+    ServerMessage::Welcome { session: None }.to_message()
+}
@@ -0,0 +1,226 @@
+//Session resumption: every connection gets a session when it opens, the events pushed to it are
+//numbered and the last ones kept. A client that lost its connection resumes the session on a new
+//one from the last seq it saw, and gets what was published in the gap replayed.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::websockets::{ConnectionId, Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    pub replay_buffer: usize, // events kept per session, older ones can't be replayed
+    pub resume_timeout: Duration, // how long a session outlives its connection
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            replay_buffer: 256,
+            resume_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+// Why a session can't be resumed, the client has to fetch the state it shows again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResumeError {
+    UnknownSession,    // never issued, or expired
+    Attached,          // its connection is still open, a live session isn't taken over
+    Forbidden(String), // the new connection isn't allowed this subscription of the session
+    Missed(u64),       // the events after this seq aren't buffered anymore
+    Ahead(u64),        // the client claims a seq after this one, the last the session reached
+}
+
+impl ResumeError {
+    // Machine readable code sent in resync messages
+    pub fn code(&self) -> &'static str {
+        match self {
+            ResumeError::UnknownSession => "unknown_session",
+            ResumeError::Attached => "session_attached",
+            ResumeError::Forbidden(_) => "forbidden",
+            ResumeError::Missed(_) => "events_missed",
+            ResumeError::Ahead(_) => "seq_ahead",
+        }
+    }
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResumeError::UnknownSession => write!(f, "The session is unknown or expired"),
+            ResumeError::Attached => write!(f, "The session is still attached to a connection"),
+            ResumeError::Forbidden(pattern) => {
+                write!(
+                    f,
+                    "Not allowed to take over the subscription to {}",
+                    pattern
+                )
+            }
+            ResumeError::Missed(seq) => {
+                write!(f, "The events after seq {} are no longer available", seq)
+            }
+            ResumeError::Ahead(seq) => write!(f, "The session is only at seq {}", seq),
+        }
+    }
+}
+
+impl std::error::Error for ResumeError {}
+
+#[derive(Debug)]
+pub(super) struct SessionState {
+    pub id: String,
+    // The connection its subscriptions are held under, still the closed one while detached
+    pub key: ConnectionId,
+    pub attached: bool,
+    pub seq: u64,              // of the last event
+    buffer: VecDeque<Message>, // the last events, up to seq
+    detached_at: Option<Instant>,
+}
+
+impl SessionState {
+    // Numbers an event and keeps it for replay, returns the message to send
+    pub fn record(&mut self, event: impl FnOnce(u64) -> Message, capacity: usize) -> Message {
+        self.seq += 1;
+        let message = event(self.seq);
+        if capacity > 0 {
+            if self.buffer.len() == capacity {
+                self.buffer.pop_front();
+            }
+            self.buffer.push_back(message.clone());
+        }
+        message
+    }
+
+    // Returns when, None if it was detached already
+    pub fn detach(&mut self) -> Option<Instant> {
+        if !self.attached {
+            return None;
+        }
+        self.attached = false;
+        self.detached_at = Some(Instant::now());
+        self.detached_at
+    }
+
+    // The events the client didn't see after `last_seq`, if they are all still buffered
+    pub fn replay(&self, last_seq: u64) -> Result<Vec<Message>, ResumeError> {
+        if last_seq > self.seq {
+            return Err(ResumeError::Ahead(self.seq));
+        }
+        let missed = self.seq - last_seq;
+        if missed > self.buffer.len() as u64 {
+            return Err(ResumeError::Missed(last_seq));
+        }
+        let skip = self.buffer.len() - missed as usize;
+        Ok(self.buffer.iter().skip(skip).cloned().collect())
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Sessions {
+    pub config: SessionConfig,
+    by_id: HashMap<String, Arc<Mutex<SessionState>>>,
+    by_connection: HashMap<ConnectionId, Arc<Mutex<SessionState>>>, // by key
+    detached: VecDeque<(Instant, String)>,                          // oldest first
+}
+
+impl Sessions {
+    pub fn new(config: SessionConfig) -> Self {
+        Sessions {
+            config,
+            by_id: HashMap::new(),
+            by_connection: HashMap::new(),
+            detached: VecDeque::new(),
+        }
+    }
+
+    pub fn open(&mut self, connection: ConnectionId) -> String {
+        // 128 random bits, as hard to guess as the key of a WebSocket handshake
+        let id = BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>());
+        let state = Arc::new(Mutex::new(SessionState {
+            id: id.clone(),
+            key: connection,
+            attached: true,
+            seq: 0,
+            buffer: VecDeque::new(),
+            detached_at: None,
+        }));
+        self.by_id.insert(id.clone(), Arc::clone(&state));
+        self.by_connection.insert(connection, state);
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Mutex<SessionState>>> {
+        self.by_id.get(id).cloned()
+    }
+
+    pub fn by_connection(&self, connection: ConnectionId) -> Option<Arc<Mutex<SessionState>>> {
+        self.by_connection.get(&connection).cloned()
+    }
+
+    // Keeps the session of a closed connection until it's resumed or expires
+    pub fn detach(&mut self, connection: ConnectionId) -> bool {
+        let Some(state) = self.by_connection.get(&connection) else {
+            return false;
+        };
+        let mut state = state.lock().unwrap();
+        if let Some(detached_at) = state.detach() {
+            self.detached.push_back((detached_at, state.id.clone()));
+        }
+        true
+    }
+
+    // Moves a session, locked by the caller, under another connection. Returns the key it had.
+    pub fn attach(
+        &mut self,
+        state: &Arc<Mutex<SessionState>>,
+        locked: &mut SessionState,
+        connection: ConnectionId,
+    ) -> ConnectionId {
+        let previous = locked.key;
+        self.by_connection.remove(&previous);
+        locked.key = connection;
+        locked.attached = true;
+        locked.detached_at = None;
+        self.by_connection.insert(connection, Arc::clone(state));
+        previous
+    }
+
+    // Forgets the session held under a connection
+    pub fn remove(&mut self, connection: ConnectionId) {
+        if let Some(state) = self.by_connection.remove(&connection) {
+            self.by_id.remove(&state.lock().unwrap().id);
+        }
+    }
+
+    // Forgets the sessions detached for longer than the resume timeout, returns their keys
+    pub fn expire(&mut self) -> Vec<ConnectionId> {
+        let mut expired = Vec::new();
+        while let Some((detached_at, _)) = self.detached.front() {
+            if detached_at.elapsed() < self.config.resume_timeout {
+                break;
+            }
+            let (detached_at, id) = self.detached.pop_front().unwrap();
+            // Unless it was resumed since, and maybe detached again later
+            let Some(state) = self.by_id.get(&id) else {
+                continue;
+            };
+            let key = {
+                let state = state.lock().unwrap();
+                if state.detached_at != Some(detached_at) {
+                    continue;
+                }
+                state.key
+            };
+            self.by_id.remove(&id);
+            self.by_connection.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+}
@@ -0,0 +1,467 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::pubsub::{
+    matches, validate_pattern, validate_topic, Broker, ClientMessage, PubSubError, SessionConfig,
+    MAX_SUBSCRIPTIONS,
+};
+use crate::websockets::{Message, Outbox, Registration, Registry};
+
+#[derive(Default)]
+struct RecordingOutbox {
+    sent: Mutex<Vec<Message>>,
+    closed: AtomicBool,
+}
+
+impl Outbox for RecordingOutbox {
+    fn send_message(&self, message: Message) -> std::io::Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        }
+        self.sent.lock().unwrap().push(message);
+        Ok(())
+    }
+}
+
+impl RecordingOutbox {
+    // The messages sent so far, parsed
+    fn received(&self) -> Vec<Value> {
+        let sent = std::mem::take(&mut *self.sent.lock().unwrap());
+        sent.into_iter()
+            .map(|message| serde_json::from_str(&message.into_text().unwrap()).unwrap())
+            .collect()
+    }
+}
+
+fn connect(broker: &Broker) -> (Registration, Arc<RecordingOutbox>) {
+    let outbox = Arc::new(RecordingOutbox::default());
+    let registration = broker
+        .registry()
+        .register(None, None, Arc::clone(&outbox) as _);
+    (registration, outbox)
+}
+
+fn answer(broker: &Broker, connection: Option<u64>, request: Value) -> Value {
+    let message = Message::text(request.to_string());
+    let answer = broker.handle_message(connection, &message).unwrap();
+    serde_json::from_str(&answer.into_text().unwrap()).unwrap()
+}
+
+#[test]
+fn test_topic_validation() {
+    assert_eq!(validate_topic("account:42:transactions"), Ok(()));
+    assert_eq!(validate_pattern("account:*:transactions"), Ok(()));
+    assert_eq!(validate_pattern("account:42:#"), Ok(()));
+    assert_eq!(validate_pattern("prices"), Ok(()));
+
+    let invalid_topics = ["", "prices::AAPL", "prices:*", "prices:#", ":prices"];
+    for topic in invalid_topics {
+        assert!(validate_topic(topic).is_err(), "{:?}", topic);
+    }
+    let too_long = format!("prices:{}", "A".repeat(250));
+    let invalid_patterns = [
+        "*:AAPL",
+        "#",
+        "prices:#:AAPL",
+        "prices:AA*",
+        "prices:",
+        &too_long,
+    ];
+    for pattern in invalid_patterns {
+        assert!(validate_pattern(pattern).is_err(), "{:?}", pattern);
+    }
+}
+
+#[test]
+fn test_topic_matching() {
+    assert!(matches("prices:AAPL", "prices:AAPL"));
+    assert!(!matches("prices:AAPL", "prices:MSFT"));
+    assert!(matches("prices:*", "prices:AAPL"));
+    assert!(!matches("prices:*", "prices"));
+    assert!(!matches("prices:*", "prices:AAPL:ask"));
+    assert!(matches("account:*:transactions", "account:42:transactions"));
+    assert!(!matches("account:*:transactions", "account:42:balance"));
+    assert!(matches("account:42:#", "account:42:transactions"));
+    assert!(matches("account:42:#", "account:42:transactions:pending"));
+    assert!(!matches("account:42:#", "account:42"));
+    assert!(!matches("account:42:#", "account:43:transactions"));
+}
+
+#[test]
+fn test_client_messages() {
+    let request = r#"{"type":"subscribe","id":3,"topic":"prices:*"}"#;
+    assert_eq!(
+        serde_json::from_str::<ClientMessage>(request).unwrap(),
+        ClientMessage::Subscribe {
+            id: Some(3),
+            topic: "prices:*".to_owned()
+        }
+    );
+    let request = r#"{"type":"unsubscribe","topic":"prices:*"}"#;
+    assert_eq!(
+        serde_json::from_str::<ClientMessage>(request).unwrap(),
+        ClientMessage::Unsubscribe {
+            id: None,
+            topic: "prices:*".to_owned()
+        }
+    );
+    let unknown_field = r#"{"type":"subscribe","topic":"prices:*","qos":1}"#;
+    assert!(serde_json::from_str::<ClientMessage>(unknown_field).is_err());
+}
+
+#[test]
+fn test_publish_reaches_matching_subscribers_once() {
+    let broker = Broker::new(Registry::new());
+    let (first, first_outbox) = connect(&broker);
+    let (second, second_outbox) = connect(&broker);
+
+    broker.subscribe(first.id(), "prices:AAPL").unwrap();
+    broker.subscribe(first.id(), "prices:*").unwrap();
+    broker.subscribe(second.id(), "prices:MSFT").unwrap();
+    // Subscribing twice is the same as once
+    broker.subscribe(second.id(), "prices:MSFT").unwrap();
+    assert_eq!(broker.subscriptions(second.id()), vec!["prices:MSFT"]);
+
+    assert_eq!(broker.publish("prices:AAPL", json!(187.5)), Ok(1));
+    assert_eq!(broker.publish("prices:MSFT", json!(402.1)), Ok(2));
+    assert_eq!(broker.publish("rates:EUR", json!(1.08)), Ok(0));
+    assert_eq!(
+        first_outbox.received(),
+        vec![
+            json!({"type": "event", "topic": "prices:AAPL", "data": 187.5}),
+            json!({"type": "event", "topic": "prices:MSFT", "data": 402.1}),
+        ]
+    );
+    assert_eq!(
+        second_outbox.received(),
+        vec![json!({"type": "event", "topic": "prices:MSFT", "data": 402.1})]
+    );
+
+    // Wildcards are only for subscribing
+    assert!(matches!(
+        broker.publish("prices:*", json!(0)),
+        Err(PubSubError::InvalidTopic(_))
+    ));
+
+    broker.unsubscribe(first.id(), "prices:*").unwrap();
+    assert_eq!(
+        broker.unsubscribe(first.id(), "prices:*"),
+        Err(PubSubError::NotSubscribed)
+    );
+    assert_eq!(broker.publish("prices:MSFT", json!(402.3)), Ok(1));
+    assert!(first_outbox.received().is_empty());
+}
+
+#[test]
+fn test_closed_connections_lose_their_subscriptions() {
+    let broker = Broker::new(Registry::new());
+    let (open, _open_outbox) = connect(&broker);
+    let (closed, closed_outbox) = connect(&broker);
+    broker.subscribe(open.id(), "prices:*").unwrap();
+    broker.subscribe(closed.id(), "prices:*").unwrap();
+
+    // Found out on the next publish
+    closed_outbox.closed.store(true, Ordering::Relaxed);
+    assert_eq!(broker.publish("prices:AAPL", json!(187.5)), Ok(1));
+    assert!(broker.subscriptions(closed.id()).is_empty());
+
+    // Or told by the server once the connection is over
+    broker.disconnect(open.id());
+    assert_eq!(broker.publish("prices:AAPL", json!(187.6)), Ok(0));
+
+    // Only registered connections can subscribe
+    let id = open.id();
+    drop(open);
+    assert_eq!(
+        broker.subscribe(id, "prices:*"),
+        Err(PubSubError::Unavailable)
+    );
+}
+
+#[test]
+fn test_authorization_hooks() {
+    let broker = Broker::new(Registry::new());
+    broker.authorize("account", |info, pattern| {
+        info.user.as_deref() == pattern.split(':').nth(1)
+    });
+    let (alice, _outbox) = connect(&broker);
+    broker
+        .registry()
+        .set_user(alice.id(), Some("alice".to_owned()));
+
+    assert_eq!(broker.subscribe(alice.id(), "account:alice:#"), Ok(()));
+    assert_eq!(
+        broker.subscribe(alice.id(), "account:bob:#"),
+        Err(PubSubError::Forbidden)
+    );
+    assert_eq!(
+        broker.subscribe(alice.id(), "account:*:transactions"),
+        Err(PubSubError::Forbidden)
+    );
+    // Namespaces without a hook are open
+    assert_eq!(broker.subscribe(alice.id(), "prices:*"), Ok(()));
+}
+
+#[test]
+fn test_subscription_limit() {
+    let broker = Broker::new(Registry::new());
+    let (connection, _outbox) = connect(&broker);
+    for i in 0..MAX_SUBSCRIPTIONS {
+        let topic = format!("prices:T{}", i);
+        broker.subscribe(connection.id(), &topic).unwrap();
+    }
+    assert_eq!(
+        broker.subscribe(connection.id(), "prices:AAPL"),
+        Err(PubSubError::TooManySubscriptions)
+    );
+    // Still fine for what it holds already
+    assert_eq!(broker.subscribe(connection.id(), "prices:T0"), Ok(()));
+}
+
+#[test]
+fn test_handle_message_answers() {
+    let broker = Broker::new(Registry::new());
+    let (connection, _outbox) = connect(&broker);
+    let id = Some(connection.id());
+
+    let subscribe = json!({"type": "subscribe", "id": 1, "topic": "prices:*"});
+    assert_eq!(
+        answer(&broker, id, subscribe.clone()),
+        json!({"type": "ack", "id": 1, "topic": "prices:*"})
+    );
+    assert_eq!(
+        answer(
+            &broker,
+            id,
+            json!({"type": "unsubscribe", "topic": "prices:*"})
+        ),
+        json!({"type": "ack", "topic": "prices:*"})
+    );
+
+    let error = answer(
+        &broker,
+        id,
+        json!({"type": "unsubscribe", "id": 2, "topic": "prices:*"}),
+    );
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["id"], 2);
+    assert_eq!(error["code"], "not_subscribed");
+    let error = answer(
+        &broker,
+        id,
+        json!({"type": "subscribe", "id": 3, "topic": "*"}),
+    );
+    assert_eq!(error["code"], "invalid_topic");
+    let error = answer(
+        &broker,
+        id,
+        json!({"type": "publish", "topic": "prices:AAPL"}),
+    );
+    assert_eq!(error["code"], "bad_request");
+    assert_eq!(error.get("id"), None);
+
+    // Connections that aren't registered can't subscribe
+    assert_eq!(answer(&broker, None, subscribe)["code"], "unavailable");
+
+    let binary = broker.handle_message(id, &Message::Binary(vec![1, 2]));
+    let error: Value = serde_json::from_str(&binary.unwrap().into_text().unwrap()).unwrap();
+    assert_eq!(error["code"], "bad_request");
+    assert_eq!(broker.handle_message(id, &Message::Ping(Vec::new())), None);
+}
+
+fn with_sessions(replay_buffer: usize, resume_timeout: Duration) -> Arc<Broker> {
+    let config = SessionConfig {
+        replay_buffer,
+        resume_timeout,
+    };
+    Broker::with_sessions(Registry::new(), config)
+}
+
+// The answer to a resume request, None when it went through the registry
+fn resume(broker: &Broker, connection: u64, session: &str, last_seq: u64) -> Option<Value> {
+    let request = json!({"type": "resume", "id": 7, "session": session, "last_seq": last_seq});
+    let message = Message::text(request.to_string());
+    let answer = broker.handle_message(Some(connection), &message)?;
+    Some(serde_json::from_str(&answer.into_text().unwrap()).unwrap())
+}
+
+#[test]
+fn test_sessions_replay_what_was_missed() {
+    let broker = with_sessions(8, Duration::from_secs(60));
+    let (first, first_outbox) = connect(&broker);
+    let session = broker.open_session(first.id()).unwrap();
+    broker.subscribe(first.id(), "account:7:#").unwrap();
+
+    for balance in [100, 90] {
+        broker.publish("account:7:balance", json!(balance)).unwrap();
+    }
+    assert_eq!(
+        first_outbox.received(),
+        vec![
+            json!({"type": "event", "topic": "account:7:balance", "data": 100, "seq": 1}),
+            json!({"type": "event", "topic": "account:7:balance", "data": 90, "seq": 2}),
+        ]
+    );
+
+    // The client saw seq 1 only, then the connection dropped. Its session keeps the
+    // subscriptions and what is published in the gap.
+    broker.disconnect(first.id());
+    drop(first);
+    assert_eq!(broker.publish("account:7:balance", json!(80)), Ok(0));
+
+    let (second, second_outbox) = connect(&broker);
+    let fresh = broker.open_session(second.id()).unwrap();
+    assert_ne!(fresh, session);
+    assert_eq!(resume(&broker, second.id(), &session, 1), None);
+    assert_eq!(
+        second_outbox.received(),
+        vec![
+            json!({"type": "resumed", "id": 7, "session": session, "seq": 3}),
+            json!({"type": "event", "topic": "account:7:balance", "data": 90, "seq": 2}),
+            json!({"type": "event", "topic": "account:7:balance", "data": 80, "seq": 3}),
+        ]
+    );
+    assert_eq!(broker.subscriptions(second.id()), vec!["account:7:#"]);
+
+    // and goes on from there, the session the connection was welcomed with is gone
+    assert_eq!(broker.publish("account:7:balance", json!(70)), Ok(1));
+    assert_eq!(
+        second_outbox.received(),
+        vec![json!({"type": "event", "topic": "account:7:balance", "data": 70, "seq": 4})]
+    );
+    assert_eq!(
+        resume(&broker, second.id(), &fresh, 0).unwrap()["code"],
+        "unknown_session"
+    );
+
+    // Not taken over while the connection it's attached to is open
+    let (third, third_outbox) = connect(&broker);
+    broker.open_session(third.id());
+    assert_eq!(
+        resume(&broker, third.id(), &session, 4).unwrap()["code"],
+        "session_attached"
+    );
+    assert_eq!(broker.publish("account:7:balance", json!(60)), Ok(1));
+    assert!(third_outbox.received().is_empty());
+    assert_eq!(second_outbox.received()[0]["seq"], 5);
+    assert!(broker.subscriptions(third.id()).is_empty());
+
+    // Resuming it again on its own connection only replays
+    assert_eq!(resume(&broker, second.id(), &session, 4), None);
+    assert_eq!(
+        second_outbox.received(),
+        vec![
+            json!({"type": "resumed", "id": 7, "session": session, "seq": 5}),
+            json!({"type": "event", "topic": "account:7:balance", "data": 60, "seq": 5}),
+        ]
+    );
+}
+
+#[test]
+fn test_resume_authorizes_the_subscriptions_again() {
+    let broker = with_sessions(8, Duration::from_secs(60));
+    broker.authorize("account", |info, pattern| {
+        info.user.as_deref() == pattern.split(':').nth(1)
+    });
+    let (alice, _outbox) = connect(&broker);
+    broker
+        .registry()
+        .set_user(alice.id(), Some("alice".to_owned()));
+    let session = broker.open_session(alice.id()).unwrap();
+    broker.subscribe(alice.id(), "account:alice:#").unwrap();
+    broker.subscribe(alice.id(), "prices:*").unwrap();
+    broker.publish("account:alice:balance", json!(100)).unwrap();
+    broker.disconnect(alice.id());
+
+    // Someone else holding the session id gets neither the subscriptions nor the replay
+    let (bob, bob_outbox) = connect(&broker);
+    broker.registry().set_user(bob.id(), Some("bob".to_owned()));
+    broker.open_session(bob.id());
+    assert_eq!(
+        resume(&broker, bob.id(), &session, 0).unwrap(),
+        json!({
+            "type": "resync",
+            "id": 7,
+            "code": "forbidden",
+            "message": "Not allowed to take over the subscription to account:alice:#",
+        })
+    );
+    assert!(bob_outbox.received().is_empty());
+    assert!(broker.subscriptions(bob.id()).is_empty());
+
+    // The session is still there for alice
+    let (again, again_outbox) = connect(&broker);
+    broker
+        .registry()
+        .set_user(again.id(), Some("alice".to_owned()));
+    broker.open_session(again.id());
+    assert_eq!(resume(&broker, again.id(), &session, 0), None);
+    assert_eq!(again_outbox.received().len(), 2);
+    let mut subscriptions = broker.subscriptions(again.id());
+    subscriptions.sort();
+    assert_eq!(subscriptions, vec!["account:alice:#", "prices:*"]);
+}
+
+#[test]
+fn test_resume_asks_for_resync_when_events_are_lost() {
+    let broker = with_sessions(2, Duration::from_secs(60));
+    let (first, _outbox) = connect(&broker);
+    let session = broker.open_session(first.id()).unwrap();
+    broker.subscribe(first.id(), "prices:AAPL").unwrap();
+    for price in 1..=5 {
+        broker.publish("prices:AAPL", json!(price)).unwrap();
+    }
+    broker.disconnect(first.id());
+
+    let (second, _outbox) = connect(&broker);
+    broker.open_session(second.id());
+    // Only seq 4 and 5 are buffered
+    let resync = resume(&broker, second.id(), &session, 2).unwrap();
+    assert_eq!(
+        resync,
+        json!({
+            "type": "resync",
+            "id": 7,
+            "code": "events_missed",
+            "message": "The events after seq 2 are no longer available",
+        })
+    );
+    assert_eq!(
+        resume(&broker, second.id(), &session, 6).unwrap()["code"],
+        "seq_ahead"
+    );
+    assert_eq!(
+        resume(&broker, second.id(), "forged", 0).unwrap()["code"],
+        "unknown_session"
+    );
+    // A failed resume leaves the session for another try
+    assert_eq!(resume(&broker, second.id(), &session, 3), None);
+
+    // Sessions expire once detached for longer than the resume timeout
+    let broker = with_sessions(2, Duration::ZERO);
+    let (first, _outbox) = connect(&broker);
+    let session = broker.open_session(first.id()).unwrap();
+    broker.subscribe(first.id(), "prices:AAPL").unwrap();
+    broker.disconnect(first.id());
+    let (second, _outbox) = connect(&broker);
+    broker.open_session(second.id());
+    assert_eq!(
+        resume(&broker, second.id(), &session, 0).unwrap()["code"],
+        "unknown_session"
+    );
+    assert!(broker.subscriptions(first.id()).is_empty());
+
+    // Without sessions, or on connections that aren't registered
+    let broker = Broker::new(Registry::new());
+    let (connection, _outbox) = connect(&broker);
+    assert_eq!(broker.open_session(connection.id()), None);
+    assert_eq!(
+        resume(&broker, connection.id(), &session, 0).unwrap()["code"],
+        "unavailable"
+    );
+    let request = json!({"type": "resume", "session": session, "last_seq": 0});
+    assert_eq!(answer(&broker, None, request)["code"], "unavailable");
+}
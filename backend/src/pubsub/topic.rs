@@ -0,0 +1,95 @@
+//Topic names and subscription patterns. Topics are `:` separated segments like
+//`account:42:transactions`. In a pattern `*` stands for exactly one segment and a trailing `#`
+//for one or more, so `prices:*` gets every price and `account:42:#` everything of account 42.
+
+use super::PubSubError;
+
+pub const MAX_TOPIC_LENGTH: usize = 256;
+
+const SEPARATOR: char = ':';
+const ANY_SEGMENT: &str = "*";
+const ANY_SUFFIX: &str = "#";
+
+// A topic events are published to, no wildcards
+pub fn validate_topic(topic: &str) -> Result<(), PubSubError> {
+    check_length(topic)?;
+    for segment in topic.split(SEPARATOR) {
+        check_segment(segment)?;
+        if segment == ANY_SEGMENT || segment == ANY_SUFFIX {
+            return Err(PubSubError::InvalidTopic(
+                "Wildcards can only be subscribed to",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// A topic or pattern subscribed to. The first segment, the namespace authorization hooks
+// are registered for, can't be a wildcard.
+pub fn validate_pattern(pattern: &str) -> Result<(), PubSubError> {
+    check_length(pattern)?;
+    let segments: Vec<&str> = pattern.split(SEPARATOR).collect();
+    for (i, segment) in segments.iter().enumerate() {
+        check_segment(segment)?;
+        let wildcard = *segment == ANY_SEGMENT || *segment == ANY_SUFFIX;
+        if i == 0 && wildcard {
+            return Err(PubSubError::InvalidTopic(
+                "The first segment of a pattern can't be a wildcard",
+            ));
+        }
+        if *segment == ANY_SUFFIX && i != segments.len() - 1 {
+            return Err(PubSubError::InvalidTopic("# can only be the last segment"));
+        }
+    }
+    Ok(())
+}
+
+fn check_length(topic: &str) -> Result<(), PubSubError> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LENGTH {
+        return Err(PubSubError::InvalidTopic(
+            "Topics must be 1 to 256 bytes long",
+        ));
+    }
+    Ok(())
+}
+
+fn check_segment(segment: &str) -> Result<(), PubSubError> {
+    if segment.is_empty() {
+        return Err(PubSubError::InvalidTopic(
+            "Topics can't have empty segments",
+        ));
+    }
+    // Wildcards are whole segments, `prices:AA*` is most likely a mistake
+    if segment.len() > 1 && segment.contains(['*', '#']) {
+        return Err(PubSubError::InvalidTopic(
+            "Wildcards must be whole segments",
+        ));
+    }
+    Ok(())
+}
+
+pub fn is_wildcard(pattern: &str) -> bool {
+    pattern
+        .split(SEPARATOR)
+        .any(|segment| segment == ANY_SEGMENT || segment == ANY_SUFFIX)
+}
+
+// First segment, e.g. `account` for `account:42:transactions`
+pub fn namespace(topic: &str) -> &str {
+    topic.split(SEPARATOR).next().unwrap_or(topic)
+}
+
+// Whether an event published to `topic` goes to the subscribers of `pattern`
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic_segments = topic.split(SEPARATOR);
+    for segment in pattern.split(SEPARATOR) {
+        if segment == ANY_SUFFIX {
+            return topic_segments.next().is_some();
+        }
+        match topic_segments.next() {
+            Some(topic_segment) if segment == ANY_SEGMENT || segment == topic_segment => {}
+            _ => return false,
+        }
+    }
+    topic_segments.next().is_none()
+}
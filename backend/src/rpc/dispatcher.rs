@@ -0,0 +1,153 @@
+//Calls the methods registered by the server for the JSON-RPC requests of a connection and
+//answers them. A batch runs its calls in order and is answered with one array of responses,
+//leaving out the notifications.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::protocol::{Request, Response, RpcError};
+use crate::websockets::{ConnectionInfo, Message};
+
+// Calls a batch can hold, a larger one is refused as a whole
+pub const MAX_BATCH_SIZE: usize = 64;
+
+// A registered method, gets the params (null when the request had none) and the connection
+// calling it, None when the connection isn't registered
+pub type Method =
+    Arc<dyn Fn(Value, Option<&ConnectionInfo>) -> Result<Value, RpcError> + Send + Sync>;
+
+#[derive(Default)]
+pub struct Dispatcher {
+    methods: RwLock<HashMap<String, Method>>,
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let methods = self.methods.read().unwrap();
+        f.debug_struct("Dispatcher")
+            .field("methods", &methods.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Dispatcher {
+    pub fn new() -> Arc<Dispatcher> {
+        Arc::new(Dispatcher::default())
+    }
+
+    /// Registers a method, replacing the one registered under the same name.
+    /// The params are deserialized into `P`, positional ones (an array) into a
+    /// tuple and named ones (an object) into a struct, and answered with
+    /// "invalid params" if that fails. Use `()` or an `Option` for methods
+    /// called without params.
+    pub fn register<P, R, F>(&self, method: &str, handler: F)
+    where
+        P: DeserializeOwned,
+        R: Serialize,
+        F: Fn(P, Option<&ConnectionInfo>) -> Result<R, RpcError> + Send + Sync + 'static,
+    {
+        let method_fn: Method = Arc::new(move |params, connection| {
+            let params = serde_json::from_value(params).map_err(RpcError::invalid_params)?;
+            let result = handler(params, connection)?;
+            serde_json::to_value(result).map_err(RpcError::internal_error)
+        });
+        self.methods
+            .write()
+            .unwrap()
+            .insert(method.to_owned(), method_fn);
+    }
+
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Calls a registered method. A method that panics fails with an internal
+    /// error instead of taking the thread serving the connection down.
+    pub fn call(
+        &self,
+        connection: Option<&ConnectionInfo>,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, RpcError> {
+        let method_fn = self.methods.read().unwrap().get(method).cloned();
+        let method_fn = method_fn.ok_or_else(|| RpcError::method_not_found(method))?;
+        panic::catch_unwind(AssertUnwindSafe(|| method_fn(params, connection)))
+            .unwrap_or_else(|_| Err(RpcError::internal_error("the method panicked")))
+    }
+
+    /// Runs a call or batch and returns the JSON answering it, None when there
+    /// is nothing to answer (notifications only).
+    pub fn dispatch(&self, connection: Option<&ConnectionInfo>, text: &str) -> Option<String> {
+        let value = match serde_json::from_str::<Value>(text) {
+            Ok(value) => value,
+            Err(e) => {
+                let error = RpcError::parse_error(e);
+                return Some(to_json(&Response::error(Value::Null, error)));
+            }
+        };
+
+        match value {
+            Value::Array(calls) if calls.is_empty() => {
+                let error = RpcError::invalid_request("empty batch");
+                Some(to_json(&Response::error(Value::Null, error)))
+            }
+            Value::Array(calls) if calls.len() > MAX_BATCH_SIZE => {
+                let error = RpcError::invalid_request("batch too large")
+                    .with_data(Value::from(MAX_BATCH_SIZE));
+                Some(to_json(&Response::error(Value::Null, error)))
+            }
+            Value::Array(calls) => {
+                let responses: Vec<Response> = calls
+                    .into_iter()
+                    .filter_map(|call| self.run(connection, call))
+                    .collect();
+                (!responses.is_empty()).then(|| to_json(&responses))
+            }
+            call => self
+                .run(connection, call)
+                .map(|response| to_json(&response)),
+        }
+    }
+
+    /// Same as dispatch for a message of the connection, only text messages
+    /// carry requests.
+    pub fn handle_message(
+        &self,
+        connection: Option<&ConnectionInfo>,
+        message: &Message,
+    ) -> Option<Message> {
+        match message {
+            Message::Text(text) => self.dispatch(connection, text).map(Message::text),
+            Message::Binary(_) => {
+                let error = RpcError::invalid_request("expected a JSON text message");
+                Some(Message::text(to_json(&Response::error(Value::Null, error))))
+            }
+            _ => None,
+        }
+    }
+
+    // Runs a single call, None for notifications
+    fn run(&self, connection: Option<&ConnectionInfo>, call: Value) -> Option<Response> {
+        let request = match Request::from_value(call) {
+            Ok(request) => request,
+            Err(response) => return Some(response),
+        };
+        let result = self.call(connection, &request.method, request.params);
+        let id = request.id?;
+        Some(match result {
+            Ok(result) => Response::result(id, result),
+            Err(error) => Response::error(id, error),
+        })
+    }
+}
+
+// Only fails on maps with non-string keys, which Value never has
+fn to_json<T: Serialize>(response: &T) -> String {
+    serde_json::to_string(response).unwrap()
+}
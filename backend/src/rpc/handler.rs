@@ -0,0 +1,62 @@
+use std::io::Error;
+use std::sync::Arc;
+
+use super::{is_rpc, Dispatcher};
+use crate::websockets::{CloseFrame, Message, Session, WebSocketHandler};
+
+// Answers JSON-RPC calls with the dispatcher. With a fallback, the messages that aren't
+// JSON-RPC and the other callbacks go to it, so one connection serves both.
+#[derive(Clone)]
+pub struct RpcHandler {
+    dispatcher: Arc<Dispatcher>,
+    fallback: Option<Arc<dyn WebSocketHandler>>,
+}
+
+impl RpcHandler {
+    pub fn new(dispatcher: Arc<Dispatcher>) -> Self {
+        RpcHandler {
+            dispatcher,
+            fallback: None,
+        }
+    }
+
+    pub fn with_fallback(
+        dispatcher: Arc<Dispatcher>,
+        fallback: impl WebSocketHandler + 'static,
+    ) -> Self {
+        RpcHandler {
+            dispatcher,
+            fallback: Some(Arc::new(fallback)),
+        }
+    }
+}
+
+impl WebSocketHandler for RpcHandler {
+    fn on_open(&self, session: &mut Session) {
+        if let Some(fallback) = &self.fallback {
+            fallback.on_open(session);
+        }
+    }
+
+    fn on_message(&self, session: &mut Session, message: Message) {
+        if let Some(fallback) = self.fallback.as_ref().filter(|_| !is_rpc(&message)) {
+            return fallback.on_message(session, message);
+        }
+        let info = session.info();
+        if let Some(answer) = self.dispatcher.handle_message(info.as_ref(), &message) {
+            session.send(answer);
+        }
+    }
+
+    fn on_close(&self, session: &Session, frame: Option<&CloseFrame>) {
+        if let Some(fallback) = &self.fallback {
+            fallback.on_close(session, frame);
+        }
+    }
+
+    fn on_error(&self, session: &Session, error: &Error) {
+        if let Some(fallback) = &self.fallback {
+            fallback.on_error(session, error);
+        }
+    }
+}
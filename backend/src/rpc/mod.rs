@@ -0,0 +1,12 @@
+mod dispatcher;
+mod handler;
+mod protocol;
+#[cfg(test)]
+mod tests;
+
+pub use dispatcher::{Dispatcher, Method, MAX_BATCH_SIZE};
+pub use handler::RpcHandler;
+pub use protocol::{
+    is_rpc, Response, RpcError, INTERNAL_ERROR, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND,
+    PARSE_ERROR,
+};
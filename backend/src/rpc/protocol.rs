@@ -0,0 +1,170 @@
+//JSON-RPC 2.0 messages (https://www.jsonrpc.org/specification), one request or a batch of
+//them per WebSocket text message:
+//  {"jsonrpc":"2.0","method":"transactions.create","params":{..},"id":1}
+//  {"jsonrpc":"2.0","result":{..},"id":1} or {"jsonrpc":"2.0","error":{"code":..,"message":..},"id":1}
+//Requests without an id are notifications, they are run but never answered.
+
+use std::fmt;
+
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::websockets::Message;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+const VERSION: &str = "2.0";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    /// An error of the application, codes from -32768 to -32000 are reserved
+    /// for the protocol.
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn parse_error(reason: impl fmt::Display) -> Self {
+        RpcError::new(PARSE_ERROR, format!("Parse error: {}", reason))
+    }
+
+    pub fn invalid_request(reason: &str) -> Self {
+        RpcError::new(INVALID_REQUEST, format!("Invalid request: {}", reason))
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        RpcError::new(METHOD_NOT_FOUND, format!("Method not found: {}", method))
+    }
+
+    pub fn invalid_params(reason: impl fmt::Display) -> Self {
+        RpcError::new(INVALID_PARAMS, format!("Invalid params: {}", reason))
+    }
+
+    pub fn internal_error(reason: impl fmt::Display) -> Self {
+        RpcError::new(INTERNAL_ERROR, format!("Internal error: {}", reason))
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Response {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    pub id: Value, // the id of the request, null when it couldn't be read
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+impl Response {
+    pub fn result(id: Value, result: Value) -> Self {
+        Response {
+            jsonrpc: VERSION,
+            outcome: Outcome::Result(result),
+            id,
+        }
+    }
+
+    pub fn error(id: Value, error: RpcError) -> Self {
+        Response {
+            jsonrpc: VERSION,
+            outcome: Outcome::Error(error),
+            id,
+        }
+    }
+}
+
+// A request that passed validation
+#[derive(Debug)]
+pub(super) struct Request {
+    pub method: String,
+    pub params: Value,     // null when the request had none
+    pub id: Option<Value>, // None for notifications
+}
+
+impl Request {
+    // Checks a request of a call or batch, the error answers it with the id it
+    // carried if that id was valid at all
+    pub fn from_value(value: Value) -> Result<Request, Response> {
+        let Value::Object(mut object) = value else {
+            let error = RpcError::invalid_request("expected an object");
+            return Err(Response::error(Value::Null, error));
+        };
+        let id = match object.remove("id") {
+            None => None,
+            Some(id @ (Value::Null | Value::Number(_) | Value::String(_))) => Some(id),
+            Some(_) => {
+                let error = RpcError::invalid_request("id must be a string, number or null");
+                return Err(Response::error(Value::Null, error));
+            }
+        };
+        let invalid = |reason: &str| {
+            let error = RpcError::invalid_request(reason);
+            Response::error(id.clone().unwrap_or_default(), error)
+        };
+
+        if object.get("jsonrpc").and_then(Value::as_str) != Some(VERSION) {
+            return Err(invalid("jsonrpc must be \"2.0\""));
+        }
+        let method = match object.remove("method") {
+            Some(Value::String(method)) => method,
+            _ => return Err(invalid("method must be a string")),
+        };
+        let params = match object.remove("params") {
+            None => Value::Null,
+            Some(params @ (Value::Array(_) | Value::Object(_))) => params,
+            Some(_) => return Err(invalid("params must be an array or object")),
+        };
+        Ok(Request { method, params, id })
+    }
+}
+
+// Only looks for the member telling JSON-RPC requests apart
+#[derive(Deserialize)]
+struct Probe {
+    jsonrpc: Option<IgnoredAny>,
+}
+
+/// Whether a message is a JSON-RPC call or batch, rather than meant for
+/// pub/sub. Pub/sub messages are objects without a "jsonrpc" member.
+pub fn is_rpc(message: &Message) -> bool {
+    let Message::Text(text) = message else {
+        return false;
+    };
+    if text.trim_start().starts_with('[') {
+        return true;
+    }
+    serde_json::from_str::<Probe>(text).is_ok_and(|probe| probe.jsonrpc.is_some())
+}
@@ -0,0 +1,255 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::rpc::{
+    is_rpc, Dispatcher, RpcError, INTERNAL_ERROR, INVALID_PARAMS, INVALID_REQUEST, MAX_BATCH_SIZE,
+    METHOD_NOT_FOUND, PARSE_ERROR,
+};
+use crate::websockets::{ConnectionInfo, Message};
+
+#[derive(Deserialize)]
+struct NewTransaction {
+    account: u64,
+    amount: f64,
+}
+
+// A dispatcher with a few methods of a finance backend, and the total of the
+// transactions created through it
+fn dispatcher() -> (Arc<Dispatcher>, Arc<AtomicU64>) {
+    let dispatcher = Dispatcher::new();
+    let total = Arc::new(AtomicU64::new(0));
+
+    dispatcher.register("add", |(a, b): (i64, i64), _| Ok(a + b));
+    let created = Arc::clone(&total);
+    dispatcher.register(
+        "transactions.create",
+        move |transaction: NewTransaction, _| {
+            if transaction.amount <= 0.0 {
+                let error = RpcError::new(-32001, "Amount must be positive");
+                return Err(error.with_data(json!({"amount": transaction.amount})));
+            }
+            created.fetch_add(transaction.amount as u64, Ordering::Relaxed);
+            Ok(json!({"account": transaction.account, "amount": transaction.amount}))
+        },
+    );
+    dispatcher.register("whoami", |(): (), connection: Option<&ConnectionInfo>| {
+        Ok(connection.and_then(|connection| connection.user.clone()))
+    });
+    dispatcher.register("crash", |(): (), _| -> Result<(), RpcError> {
+        panic!("the method failed")
+    });
+    (dispatcher, total)
+}
+
+fn call(dispatcher: &Dispatcher, request: Value) -> Option<Value> {
+    dispatcher
+        .dispatch(None, &request.to_string())
+        .map(|answer| serde_json::from_str(&answer).unwrap())
+}
+
+fn error_code(response: &Value) -> i64 {
+    response["error"]["code"].as_i64().unwrap()
+}
+
+#[test]
+fn test_calls_are_answered_with_their_id() {
+    let (dispatcher, _) = dispatcher();
+
+    let request = json!({"jsonrpc": "2.0", "method": "add", "params": [40, 2], "id": 1});
+    assert_eq!(
+        call(&dispatcher, request),
+        Some(json!({"jsonrpc": "2.0", "result": 42, "id": 1}))
+    );
+
+    // Named params, and ids that are strings or null
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": "transactions.create",
+        "params": {"account": 42, "amount": 12.5},
+        "id": "create-1"
+    });
+    assert_eq!(
+        call(&dispatcher, request),
+        Some(json!({
+            "jsonrpc": "2.0",
+            "result": {"account": 42, "amount": 12.5},
+            "id": "create-1"
+        }))
+    );
+    let request = json!({"jsonrpc": "2.0", "method": "add", "params": [1, 1], "id": null});
+    assert_eq!(call(&dispatcher, request).unwrap()["id"], Value::Null);
+}
+
+#[test]
+fn test_notifications_run_without_an_answer() {
+    let (dispatcher, total) = dispatcher();
+
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "transactions.create",
+        "params": {"account": 42, "amount": 30.0}
+    });
+    assert_eq!(call(&dispatcher, notification), None);
+    assert_eq!(total.load(Ordering::Relaxed), 30);
+
+    // Not even when they fail
+    let notification = json!({"jsonrpc": "2.0", "method": "missing"});
+    assert_eq!(call(&dispatcher, notification), None);
+}
+
+#[test]
+fn test_standard_errors() {
+    let (dispatcher, _) = dispatcher();
+
+    let response = dispatcher.dispatch(None, r#"{"jsonrpc":"2.0","method""#);
+    let response: Value = serde_json::from_str(&response.unwrap()).unwrap();
+    assert_eq!(error_code(&response), PARSE_ERROR);
+    assert_eq!(response["id"], Value::Null);
+
+    let missing = json!({"jsonrpc": "2.0", "method": "transactions.delete", "id": 1});
+    let response = call(&dispatcher, missing).unwrap();
+    assert_eq!(error_code(&response), METHOD_NOT_FOUND);
+    assert_eq!(response["id"], 1);
+
+    let wrong_params = json!({"jsonrpc": "2.0", "method": "add", "params": ["40", 2], "id": 2});
+    assert_eq!(
+        error_code(&call(&dispatcher, wrong_params).unwrap()),
+        INVALID_PARAMS
+    );
+    let no_params = json!({"jsonrpc": "2.0", "method": "transactions.create", "id": 3});
+    assert_eq!(
+        error_code(&call(&dispatcher, no_params).unwrap()),
+        INVALID_PARAMS
+    );
+
+    let invalid_requests = [
+        json!({"jsonrpc": "1.0", "method": "add", "id": 4}),
+        json!({"method": "add", "id": 4}),
+        json!({"jsonrpc": "2.0", "method": 1, "id": 4}),
+        json!({"jsonrpc": "2.0", "method": "add", "params": 1, "id": 4}),
+        json!(4),
+    ];
+    for request in invalid_requests {
+        let response = call(&dispatcher, request.clone()).unwrap();
+        assert_eq!(error_code(&response), INVALID_REQUEST, "{}", request);
+    }
+    // Answered with the id when it could be read
+    let request = json!({"jsonrpc": "1.0", "method": "add", "id": 4});
+    assert_eq!(call(&dispatcher, request).unwrap()["id"], 4);
+    let request = json!({"jsonrpc": "2.0", "method": "add", "id": [4]});
+    assert_eq!(call(&dispatcher, request).unwrap()["id"], Value::Null);
+
+    let crash = json!({"jsonrpc": "2.0", "method": "crash", "id": 5});
+    assert_eq!(
+        error_code(&call(&dispatcher, crash).unwrap()),
+        INTERNAL_ERROR
+    );
+}
+
+#[test]
+fn test_application_errors_keep_their_data() {
+    let (dispatcher, total) = dispatcher();
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": "transactions.create",
+        "params": {"account": 42, "amount": -5.0},
+        "id": 1
+    });
+    assert_eq!(
+        call(&dispatcher, request),
+        Some(json!({
+            "jsonrpc": "2.0",
+            "error": {
+                "code": -32001,
+                "message": "Amount must be positive",
+                "data": {"amount": -5.0}
+            },
+            "id": 1
+        }))
+    );
+    assert_eq!(total.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_batches() {
+    let (dispatcher, total) = dispatcher();
+
+    let batch = json!([
+        {"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1},
+        {"jsonrpc": "2.0", "method": "transactions.create", "params": {"account": 1, "amount": 7.0}},
+        {"jsonrpc": "2.0", "method": "missing", "id": "2"},
+        {"foo": "bar"},
+        {"jsonrpc": "2.0", "method": "add", "params": [3, 4], "id": 3}
+    ]);
+    let responses = call(&dispatcher, batch).unwrap();
+    let responses = responses.as_array().unwrap();
+    // The notification isn't answered, the invalid request is with a null id
+    assert_eq!(responses.len(), 4);
+    assert_eq!(
+        responses[0],
+        json!({"jsonrpc": "2.0", "result": 3, "id": 1})
+    );
+    assert_eq!(error_code(&responses[1]), METHOD_NOT_FOUND);
+    assert_eq!(responses[1]["id"], "2");
+    assert_eq!(error_code(&responses[2]), INVALID_REQUEST);
+    assert_eq!(responses[2]["id"], Value::Null);
+    assert_eq!(
+        responses[3],
+        json!({"jsonrpc": "2.0", "result": 7, "id": 3})
+    );
+    assert_eq!(total.load(Ordering::Relaxed), 7);
+
+    // Notifications only
+    let batch = json!([{"jsonrpc": "2.0", "method": "add", "params": [1, 2]}]);
+    assert_eq!(call(&dispatcher, batch), None);
+
+    let response = call(&dispatcher, json!([])).unwrap();
+    assert_eq!(error_code(&response), INVALID_REQUEST);
+    let batch = Value::Array(vec![json!(1); MAX_BATCH_SIZE + 1]);
+    let response = call(&dispatcher, batch).unwrap();
+    assert_eq!(error_code(&response), INVALID_REQUEST);
+    assert_eq!(response["error"]["data"], MAX_BATCH_SIZE);
+}
+
+#[test]
+fn test_methods_see_the_calling_connection() {
+    let (dispatcher, _) = dispatcher();
+    let alice = ConnectionInfo {
+        id: 1,
+        remote_addr: None,
+        user: Some("alice".to_owned()),
+        subprotocol: None,
+    };
+
+    let request = Message::text(r#"{"jsonrpc":"2.0","method":"whoami","id":1}"#);
+    let answer = dispatcher.handle_message(Some(&alice), &request).unwrap();
+    assert_eq!(
+        answer.into_text().unwrap(),
+        r#"{"jsonrpc":"2.0","result":"alice","id":1}"#
+    );
+    let answer = dispatcher.handle_message(None, &request).unwrap();
+    assert_eq!(
+        answer.into_text().unwrap(),
+        r#"{"jsonrpc":"2.0","result":null,"id":1}"#
+    );
+}
+
+#[test]
+fn test_rpc_is_told_apart_from_pubsub() {
+    assert!(is_rpc(&Message::text(
+        r#"{"jsonrpc":"2.0","method":"add","id":1}"#
+    )));
+    assert!(is_rpc(&Message::text(r#" [{"jsonrpc":"2.0"}]"#)));
+    // Still for the dispatcher to answer with an error
+    assert!(is_rpc(&Message::text(r#"{"jsonrpc":"1.0"}"#)));
+
+    assert!(!is_rpc(&Message::text(
+        r#"{"type":"subscribe","topic":"jsonrpc"}"#
+    )));
+    assert!(!is_rpc(&Message::text(r#"{"type":"subscribe""#)));
+    assert!(!is_rpc(&Message::Binary(b"[]".to_vec())));
+}
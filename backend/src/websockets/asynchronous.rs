@@ -0,0 +1,408 @@
+//WebSocket over tokio: the same handshake and Protocol as the blocking WebSocket, with the
+//bytes moved by AsyncRead/AsyncWrite so one thread can serve any number of connections.
+//A registered connection sends what is pushed to it while it waits for the peer.
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_rustls::TlsAcceptor;
+
+use super::connection::READ_CHUNK_SIZE;
+use super::handshake::{self, HandshakeError};
+use super::{CloseCode, ConnectionState, Frame, Heartbeat, HeartbeatConfig, Message};
+use super::{Outbox, Overflow, OverflowPolicy, Registration, Registry, SendQueue, SendQueueConfig};
+use super::{Protocol, Request, RequestError, Role, Router, WebSocketConfig, WebSocketHandler};
+
+// A tokio tcp stream with a TLS session on top, for wss
+pub type AsyncTlsStream = tokio_rustls::server::TlsStream<TcpStream>;
+
+// Runs the TLS handshake on an accepted connection, like accept_tls
+pub async fn accept_tls_async(
+    stream: TcpStream,
+    config: &Arc<ServerConfig>,
+) -> Result<AsyncTlsStream, Error> {
+    TlsAcceptor::from(Arc::clone(config)).accept(stream).await
+}
+
+// Where the messages pushed through the registry wait for the task of the connection. The
+// task can't be waited on, so the send queue policy applies right away: with Block, as many
+// more are held as the queue takes, then the client is disconnected.
+#[derive(Debug)]
+struct AsyncOutbox {
+    pushed: Mutex<(SendQueue, bool)>, // and whether it overflowed, nothing is taken then
+    notify: Notify,                   // something was pushed
+}
+
+impl AsyncOutbox {
+    fn lock(&self) -> MutexGuard<'_, (SendQueue, bool)> {
+        self.pushed.lock().unwrap()
+    }
+
+    // Everything pushed so far, Err once the connection must be failed with 1008
+    fn take(&self) -> Result<Vec<Message>, Overflow> {
+        let (queue, overflowed) = &mut *self.lock();
+        if *overflowed {
+            return Err(Overflow::Disconnect);
+        }
+        Ok(std::iter::from_fn(|| queue.pop()).collect())
+    }
+}
+
+impl Outbox for AsyncOutbox {
+    fn send_message(&self, message: Message) -> Result<(), Error> {
+        let mut pushed = self.lock();
+        let (queue, overflowed) = &mut *pushed;
+        if *overflowed {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "The connection is closed",
+            ));
+        }
+
+        let queued = if queue.policy() == OverflowPolicy::Block && queue.is_full() {
+            queue.hold(message)
+        } else {
+            queue.push(message)
+        };
+        let sent = match queued {
+            Ok(()) => Ok(()),
+            Err(Overflow::Full) => unreachable!("full queues hold the message back"),
+            Err(Overflow::Disconnect) => {
+                queue.clear();
+                *overflowed = true;
+                Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Send queue full, the client was disconnected (close code 1008)",
+                ))
+            }
+        };
+        drop(pushed);
+        self.notify.notify_one();
+        sent
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncWebSocket<S = TcpStream> {
+    stream: S,
+    protocol: Protocol,
+    subprotocol: Option<String>,
+    heartbeat: Option<Heartbeat>,     // set by enable_heartbeat
+    outbox: Option<Arc<AsyncOutbox>>, // set by register
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWebSocket<S> {
+    pub async fn accept(stream: S) -> Result<Self, Error> {
+        AsyncWebSocket::accept_with_config(stream, &WebSocketConfig::default()).await
+    }
+
+    /// Performs the server side of the opening handshake, see
+    /// `WebSocket::accept_with_config`. The client has `config.handshake_timeout`
+    /// to send its upgrade request.
+    pub async fn accept_with_config(stream: S, config: &WebSocketConfig) -> Result<Self, Error> {
+        let (ws, _) = AsyncWebSocket::accept_request(stream, config, |_| Ok(())).await?;
+        Ok(ws)
+    }
+
+    /// Same as accept_with_config, refusing the upgrade with a 404 unless the
+    /// router has a handler for the requested path, see `WebSocket::accept_routed`.
+    pub async fn accept_routed(
+        stream: S,
+        config: &WebSocketConfig,
+        router: &Router,
+    ) -> Result<(Self, Request, Arc<dyn WebSocketHandler>), Error> {
+        let mut handler = None;
+        let (ws, request) = AsyncWebSocket::accept_request(stream, config, |request| {
+            handler = Some(router.resolve(request)?);
+            Ok(())
+        })
+        .await?;
+        Ok((ws, request, handler.unwrap()))
+    }
+
+    // Reads the upgrade request and answers it, unless `check` refuses it first
+    async fn accept_request(
+        stream: S,
+        config: &WebSocketConfig,
+        check: impl FnOnce(&Request) -> Result<(), HandshakeError>,
+    ) -> Result<(Self, Request), Error> {
+        let mut ws = AsyncWebSocket {
+            stream,
+            protocol: Protocol::with_config(Role::Server, config),
+            subprotocol: None,
+            heartbeat: None,
+            outbox: None,
+        };
+
+        let request = match config.handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, ws.read_handshake_request(config))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(RequestError::Io(Error::new(
+                        ErrorKind::TimedOut,
+                        "Client did not send its upgrade request in time",
+                    )))
+                }),
+            None => ws.read_handshake_request(config).await,
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                if let Some(error) = HandshakeError::from_request_error(&e) {
+                    ws.reject(&error).await?;
+                }
+                return Err(e.into());
+            }
+        };
+
+        let negotiated = match check(&request).and_then(|_| handshake::negotiate(&request, config))
+        {
+            Ok(negotiated) => negotiated,
+            Err(error) => {
+                ws.reject(&error).await?;
+                return Err(error.into());
+            }
+        };
+        ws.stream.write_all(negotiated.response.as_bytes()).await?;
+
+        ws.subprotocol = negotiated.subprotocol;
+        ws.protocol.open(negotiated.deflate);
+        Ok((ws, request))
+    }
+
+    // Reads until the blank line ending the headers. Whatever the client sent after it
+    // already belongs to the first frames and is handed to the protocol.
+    async fn read_handshake_request(
+        &mut self,
+        config: &WebSocketConfig,
+    ) -> Result<Request, RequestError> {
+        let limits = config.request_limits();
+        let mut received = Vec::new();
+        let mut buffer = [0; READ_CHUNK_SIZE];
+
+        loop {
+            let read = self.stream.read(&mut buffer).await?;
+            if read == 0 {
+                return Err(RequestError::UnexpectedEof);
+            }
+            received.extend_from_slice(&buffer[..read]);
+
+            if let Some((request, len)) = Request::parse_partial(&received, limits)? {
+                self.protocol.receive(&received[len..]);
+                return Ok(request);
+            }
+        }
+    }
+
+    // Answers a refused upgrade request with an HTTP error response
+    async fn reject(&mut self, error: &HandshakeError) -> Result<(), Error> {
+        self.stream
+            .write_all(error.to_response().as_bytes())
+            .await?;
+        self.protocol.abort();
+        Ok(())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.protocol.state()
+    }
+
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.protocol.is_compressed()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Pings the peer every `config.interval` while waiting in `read_message`,
+    /// which fails the connection with 1001 and returns a `TimedOut` error if a
+    /// ping isn't answered within `config.pong_timeout`.
+    pub fn enable_heartbeat(&mut self, config: HeartbeatConfig) {
+        self.heartbeat = Some(Heartbeat::new(config, Instant::now()));
+    }
+
+    // Round trip time of the last ping answered, with the heartbeat enabled
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.as_ref()?.rtt()
+    }
+
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.heartbeat.as_ref()?.last_pong()
+    }
+
+    /// Adds the connection to `registry`. The messages sent to it from there
+    /// are queued as `config` says, and sent while `read_message` waits for the
+    /// peer. It stays registered until the returned `Registration` is dropped.
+    pub fn register(
+        &mut self,
+        registry: &Arc<Registry>,
+        remote_addr: Option<SocketAddr>,
+        config: SendQueueConfig,
+    ) -> Registration {
+        let outbox = Arc::new(AsyncOutbox {
+            pushed: Mutex::new((SendQueue::new(config), false)),
+            notify: Notify::new(),
+        });
+        self.outbox = Some(Arc::clone(&outbox));
+        registry.register(remote_addr, self.subprotocol.clone(), outbox)
+    }
+
+    // Sends a text message
+    pub async fn send(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.protocol.send(payload)?;
+        self.flush().await
+    }
+
+    /// Sends a message of any kind, see `Protocol::send_message`.
+    pub async fn send_message(&mut self, message: Message) -> Result<(), Error> {
+        self.protocol.send_message(message)?;
+        self.flush().await
+    }
+
+    pub async fn send_ping(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.protocol.send_ping(payload);
+        self.flush().await
+    }
+
+    pub async fn send_pong(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.protocol.send_pong(payload);
+        self.flush().await
+    }
+
+    /// Starts the closing handshake, see `WebSocket::close`.
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Error> {
+        self.protocol.close(code, reason)?;
+        self.flush().await
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Frame, Error> {
+        self.read_with(Protocol::read_frame).await
+    }
+
+    /// Reads the next complete message, see `WebSocket::read_message`.
+    pub async fn read_message(&mut self) -> Result<Message, Error> {
+        let message = self.read_with(Protocol::read_message).await?;
+        if let (Message::Pong(payload), Some(heartbeat)) = (&message, self.heartbeat.as_mut()) {
+            heartbeat.on_pong(payload, Instant::now());
+        }
+        Ok(message)
+    }
+
+    // Writes whatever frames the protocol has queued to the stream
+    async fn flush(&mut self) -> Result<(), Error> {
+        if self.protocol.has_output() {
+            let output = self.protocol.take_output();
+            self.stream.write_all(&output).await?;
+        }
+        Ok(())
+    }
+
+    // Sends what was pushed to the connection, failing it with 1008 if more was pushed
+    // than its queue takes
+    async fn send_pushed(&mut self) -> Result<(), Error> {
+        let Some(outbox) = self.outbox.as_ref() else {
+            return Ok(());
+        };
+        match outbox.take() {
+            Ok(messages) => {
+                for message in messages {
+                    // Fails once the connection is closing, what is left is dropped then
+                    let _ = self.protocol.send_message(message);
+                }
+                self.flush().await
+            }
+            Err(_) => {
+                let _ = self
+                    .protocol
+                    .fail(CloseCode::PolicyViolation, "Send queue full");
+                let _ = self.flush().await;
+                Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Send queue full, the client was disconnected (close code 1008)",
+                ))
+            }
+        }
+    }
+
+    // Reads from the stream, None as soon as something was pushed to the connection
+    async fn read_or_pushed(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        let Some(outbox) = self.outbox.as_ref() else {
+            return self.stream.read(buffer).await.map(Some);
+        };
+        tokio::select! {
+            read = self.stream.read(buffer) => read.map(Some),
+            _ = outbox.notify.notified() => Ok(None),
+        }
+    }
+
+    // Feeds the protocol from the stream until `next` decodes something, like
+    // WebSocket::read_with. The heartbeat bounds each read instead of a socket timeout,
+    // and what is pushed to a registered connection is sent meanwhile.
+    async fn read_with<T>(
+        &mut self,
+        mut next: impl FnMut(&mut Protocol) -> Result<Option<T>, Error>,
+    ) -> Result<T, Error> {
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        loop {
+            match next(&mut self.protocol) {
+                Ok(Some(item)) => {
+                    self.flush().await?;
+                    return Ok(item);
+                }
+                Ok(None) => self.flush().await?,
+                Err(e) => {
+                    let _ = self.flush().await;
+                    return Err(e);
+                }
+            }
+
+            let timeout = match self.heartbeat.as_mut() {
+                Some(heartbeat) => Some(heartbeat.drive(&mut self.protocol, Instant::now())),
+                None => None,
+            };
+            let read = match timeout {
+                Some(Ok(timeout)) => {
+                    self.flush().await?;
+                    match tokio::time::timeout(timeout, self.read_or_pushed(&mut buffer)).await {
+                        Ok(read) => read?,
+                        // Time to ping or give up on the peer
+                        Err(_) => continue,
+                    }
+                }
+                // Sends the close frame, the peer timed out
+                Some(Err(e)) => {
+                    let _ = self.flush().await;
+                    return Err(e);
+                }
+                None => self.read_or_pushed(&mut buffer).await?,
+            };
+            let Some(read) = read else {
+                self.send_pushed().await?;
+                continue;
+            };
+
+            if read == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed by the peer",
+                ));
+            }
+            self.protocol.receive(&buffer[..read]);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+// Status codes sent in the first 2 bytes of a close frame (RFC 6455 section 7.4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    Normal,             // 1000: the purpose of the connection has been fulfilled
+    GoingAway,          // 1001: server shutting down or browser tab navigating away
+    ProtocolError,      // 1002
+    UnsupportedData,    // 1003: e.g. binary sent to an endpoint that only takes text
+    NoStatusReceived,   // 1005: never sent, reported when a close frame has no code
+    Abnormal,           // 1006: never sent, reported when the tcp connection dropped
+    InvalidPayload,     // 1007: e.g. invalid utf-8 in a text message
+    PolicyViolation,    // 1008
+    MessageTooBig,      // 1009
+    MandatoryExtension, // 1010: client expected an extension the server didn't negotiate
+    InternalError,      // 1011
+    ServiceRestart,     // 1012
+    TryAgainLater,      // 1013
+    TlsHandshake,       // 1015: never sent
+    Other(u16),         // 3000-3999 registered libraries, 4000-4999 private use
+}
+
+impl CloseCode {
+    // Some codes are reserved for reporting and must never appear in a close frame
+    pub fn is_allowed(&self) -> bool {
+        matches!(u16::from(*self), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1005 => CloseCode::NoStatusReceived,
+            1006 => CloseCode::Abnormal,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1015 => CloseCode::TlsHandshake,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::UnsupportedData => 1003,
+            CloseCode::NoStatusReceived => 1005,
+            CloseCode::Abnormal => 1006,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::TlsHandshake => 1015,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", u16::from(*self))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub fn new(code: CloseCode, reason: &str) -> Self {
+        CloseFrame {
+            code,
+            reason: reason.to_owned(),
+        }
+    }
+
+    // Parses the payload of a close frame: empty, or a 2 byte code followed by a utf-8 reason.
+    // On failure returns the code the connection should be failed with.
+    pub fn from_payload(payload: &[u8]) -> Result<Option<CloseFrame>, CloseCode> {
+        match payload.len() {
+            0 => return Ok(None),
+            1 => return Err(CloseCode::ProtocolError),
+            _ => {}
+        }
+
+        let code = CloseCode::from(u16::from_be_bytes([payload[0], payload[1]]));
+        if !code.is_allowed() {
+            return Err(CloseCode::ProtocolError);
+        }
+
+        let reason = std::str::from_utf8(&payload[2..]).map_err(|_| CloseCode::InvalidPayload)?;
+
+        Ok(Some(CloseFrame::new(code, reason)))
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = u16::from(self.code).to_be_bytes().to_vec();
+        payload.extend(self.reason.as_bytes());
+        payload
+    }
+}
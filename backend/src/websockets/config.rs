@@ -0,0 +1,142 @@
+use std::time::Duration;
+
+use super::SendQueueConfig;
+use super::{DeflateConfig, HeartbeatConfig, OriginPolicy, OverflowPolicy, RequestLimits};
+
+// Server side settings used when accepting a connection
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    pub deflate: Option<DeflateConfig>, // None disables permessage-deflate
+    pub subprotocols: Vec<String>, // e.g. "finance.v2", "finance.v1", empty to ignore the header
+    pub origin_policy: OriginPolicy,
+    // Limits checked before anything is allocated, exceeding one fails the connection with 1009
+    pub max_frame_size: usize, // payload of a single frame as sent on the wire, in bytes
+    pub max_message_size: usize, // whole message once reassembled and decompressed
+    pub max_fragments: usize,  // frames a single message can be split into
+    pub max_handshake_size: usize, // request line and headers of the upgrade request
+    pub handshake_timeout: Option<Duration>, // for the client to send its upgrade request
+    pub heartbeat: Option<HeartbeatConfig>, // None never pings and waits on silent peers forever
+    // Outbound messages of the writer half and the reactor, a WebSocket that isn't split
+    // writes synchronously and has no queue
+    pub send_queue: SendQueueConfig,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            deflate: Some(DeflateConfig::default()),
+            subprotocols: Vec::new(),
+            origin_policy: OriginPolicy::AllowAny,
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+            max_fragments: 4096,
+            max_handshake_size: 64 << 10,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            heartbeat: Some(HeartbeatConfig::default()),
+            send_queue: SendQueueConfig::default(),
+        }
+    }
+}
+
+impl WebSocketConfig {
+    // Limits for parsing the upgrade request
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_size: self.max_handshake_size,
+            ..RequestLimits::default()
+        }
+    }
+
+    /// Reads the settings the websocket binaries take from the environment, see
+    /// `.env`. Unset or empty variables keep the defaults, except the allowed
+    /// origins which default to the frontend's vite dev server.
+    pub fn from_env() -> Self {
+        let defaults = WebSocketConfig::default();
+        let heartbeat = HeartbeatConfig::default();
+        WebSocketConfig {
+            origin_policy: origin_policy(),
+            max_frame_size: limit("WS_MAX_FRAME_SIZE", defaults.max_frame_size),
+            max_message_size: limit("WS_MAX_MESSAGE_SIZE", defaults.max_message_size),
+            max_fragments: limit("WS_MAX_FRAGMENTS", defaults.max_fragments),
+            max_handshake_size: limit("WS_MAX_HANDSHAKE_SIZE", defaults.max_handshake_size),
+            heartbeat: Some(HeartbeatConfig {
+                interval: seconds("WS_PING_INTERVAL", heartbeat.interval),
+                pong_timeout: seconds("WS_PONG_TIMEOUT", heartbeat.pong_timeout),
+            }),
+            send_queue: SendQueueConfig {
+                capacity: limit("WS_SEND_QUEUE_CAPACITY", defaults.send_queue.capacity),
+                policy: overflow_policy(defaults.send_queue.policy),
+                ..defaults.send_queue
+            },
+            ..defaults
+        }
+    }
+
+    // Picks the first protocol in the client's Sec-WebSocket-Protocol list that we support
+    pub fn select_subprotocol(&self, offered: &[&str]) -> Option<String> {
+        offered
+            .iter()
+            .find(|protocol| self.subprotocols.iter().any(|p| p == *protocol))
+            .map(|protocol| protocol.to_string())
+    }
+}
+
+// Pages allowed to open a connection, comma separated in WS_ALLOWED_ORIGINS ("*" for any,
+// empty for none). Defaults to the frontend's vite dev server.
+fn origin_policy() -> OriginPolicy {
+    let origins = std::env::var("WS_ALLOWED_ORIGINS")
+        .unwrap_or_else(|_| "http://localhost:5173,http://127.0.0.1:5173".to_owned());
+
+    match origins.trim() {
+        "*" => OriginPolicy::AllowAny,
+        "" => OriginPolicy::AllowNone,
+        origins => OriginPolicy::AllowList(
+            origins
+                .split(',')
+                .map(|origin| origin.trim().to_owned())
+                .collect(),
+        ),
+    }
+}
+
+// Size limit in bytes from the environment, e.g. WS_MAX_MESSAGE_SIZE=1048576
+fn limit(name: &str, default: usize) -> usize {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number, got {:?}", name, value)),
+        _ => default,
+    }
+}
+
+// Heartbeat timing in seconds from the environment, e.g. WS_PONG_TIMEOUT=5
+fn seconds(name: &str, default: Duration) -> Duration {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .unwrap_or_else(|_| panic!("{} must be a number of seconds, got {:?}", name, value)),
+        _ => default,
+    }
+}
+
+// What to do when a client's send queue is full, from WS_SEND_QUEUE_POLICY: block,
+// drop-oldest, drop-newest or disconnect
+fn overflow_policy(default: OverflowPolicy) -> OverflowPolicy {
+    match std::env::var("WS_SEND_QUEUE_POLICY") {
+        Ok(value) => match value.trim() {
+            "" => default,
+            "block" => OverflowPolicy::Block,
+            "drop-oldest" => OverflowPolicy::DropOldest,
+            "drop-newest" => OverflowPolicy::DropNewest,
+            "disconnect" => OverflowPolicy::Disconnect,
+            value => panic!(
+                "WS_SEND_QUEUE_POLICY must be block, drop-oldest, drop-newest or disconnect, got {:?}",
+                value
+            ),
+        },
+        Err(_) => default,
+    }
+}
@@ -1,121 +1,271 @@
-use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::io::{BufReader, Error, Read, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
-use sha1::{Digest, Sha1};
 
-use super::Frame;
-use super::Request;
-use crate::websockets::{
-    OpCode, OpCode::Binary, OpCode::ConnectionClosed, OpCode::Continuation, OpCode::Ping,
-    OpCode::Pong, OpCode::Text,
-};
+use super::handshake::{self, generate_accept_key, HandshakeError};
+use super::request::{self, Headers};
+use super::{CloseCode, ConnectionState, Frame, Message, OpCode, Protocol, Role};
+use super::{Heartbeat, HeartbeatConfig, ReadTimeout};
+use super::{MessageReader, MessageWriter, TryClone, WebSocketReader, WebSocketWriter};
+use super::{Request, RequestError, RequestLimits, Router, WebSocketHandler};
+use super::{SendQueue, SendQueueConfig, WebSocketConfig};
 
+// Works over any byte stream: a plain TcpStream, or a TLS session for wss. The
+// framing itself is done by Protocol, this only moves bytes between it and the stream.
 #[derive(Debug)]
-pub struct WebSocket {
-    stream: TcpStream,
-    state: ConnectionState,
+pub struct WebSocket<S = TcpStream> {
+    stream: S,
+    protocol: Protocol,
+    subprotocol: Option<String>, // application protocol agreed on during the handshake
+    heartbeat: Option<(Heartbeat, SetReadTimeout<S>)>, // set by enable_heartbeat
+    send_queue: SendQueueConfig, // for the writer half, once split
 }
 
-#[derive(Debug)]
-enum ConnectionState {
-    Connecting,
-    Connected,
-    Closing,
-    Closed,
-    Bitchass,
+// ReadTimeout::set_read_timeout of the stream, kept so reads can wake up for the heartbeat
+// without every WebSocket method requiring a stream that supports timeouts
+pub(super) type SetReadTimeout<S> = fn(&S, Option<Duration>) -> Result<(), Error>;
+
+// How much is read from the stream at once, frames larger than this take several reads
+pub(super) const READ_CHUNK_SIZE: usize = 4096;
+
+impl WebSocket<TcpStream> {
+    /// Opens a client connection to a `ws://host[:port][/path]` url and performs
+    /// the opening handshake. Frames sent on the returned socket are masked.
+    /// For wss, connect the TLS stream yourself and use `WebSocket::client`.
+    pub fn connect(url: &str) -> Result<Self, Error> {
+        let (host, port, path) = parse_ws_url(url)?;
+        let stream = TcpStream::connect((host.as_str(), port))?;
+        // IPv6 addresses keep their brackets in the Host header
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        WebSocket::client(stream, &authority, &path)
+    }
 }
-//fix this to put the handshakes in the handshake.rs
-impl WebSocket {
-    pub fn new(stream: TcpStream) -> Self {
+
+impl<S: Read + Write> WebSocket<S> {
+    pub fn new(stream: S) -> Self {
+        WebSocket::with_role(stream, Role::Server)
+    }
+
+    pub(crate) fn with_role(stream: S, role: Role) -> Self {
         WebSocket {
             stream,
-            state: ConnectionState::Connecting,
+            protocol: Protocol::new(role),
+            subprotocol: None,
+            heartbeat: None,
+            send_queue: SendQueueConfig::default(),
         }
     }
 
-    pub fn accept(stream: TcpStream) -> Result<Self, Error> {
-        let mut ws = WebSocket::new(stream);
+    pub fn accept(stream: S) -> Result<Self, Error> {
+        WebSocket::accept_with_config(stream, &WebSocketConfig::default())
+    }
 
-        let request = ws.read_handshake_request()?;
+    pub fn accept_with_config(stream: S, config: &WebSocketConfig) -> Result<Self, Error> {
+        let (ws, _) = WebSocket::accept_request(stream, config, |_| Ok(()))?;
+        Ok(ws)
+    }
+
+    /// Same as accept_with_config, but refuses the upgrade with a 404 unless the
+    /// router has a handler for the requested path. Returns the request and
+    /// its handler with the socket.
+    pub fn accept_routed(
+        stream: S,
+        config: &WebSocketConfig,
+        router: &Router,
+    ) -> Result<(Self, Request, Arc<dyn WebSocketHandler>), Error> {
+        let mut handler = None;
+        let (ws, request) = WebSocket::accept_request(stream, config, |request| {
+            handler = Some(router.resolve(request)?);
+            Ok(())
+        })?;
+        Ok((ws, request, handler.unwrap()))
+    }
+
+    // Reads the upgrade request and answers it, unless `check` refuses it first
+    fn accept_request(
+        stream: S,
+        config: &WebSocketConfig,
+        check: impl FnOnce(&Request) -> Result<(), HandshakeError>,
+    ) -> Result<(Self, Request), Error> {
+        let mut ws = WebSocket {
+            stream,
+            protocol: Protocol::with_config(Role::Server, config),
+            subprotocol: None,
+            heartbeat: None,
+            send_queue: config.send_queue,
+        };
 
-        let client_key = request.get_header("Sec-WebSocket-Key").ok_or(Error::new(
-            std::io::ErrorKind::Other,
-            "Not a WebSocket upgrade request".to_owned(),
-        ))?;
+        let request = match ws.read_handshake_request(config.request_limits()) {
+            Ok(request) => request,
+            Err(e) => {
+                if let Some(error) = HandshakeError::from_request_error(&e) {
+                    ws.reject(&error)?;
+                }
+                return Err(e.into());
+            }
+        };
 
-        // // Generate accept key using handshake.rs
-        let accept_key = generate_accept_key(client_key);
+        let negotiated = match check(&request).and_then(|_| handshake::negotiate(&request, config))
+        {
+            Ok(negotiated) => negotiated,
+            Err(error) => {
+                ws.reject(&error)?;
+                return Err(error.into());
+            }
+        };
 
         // Send back handshake response
-        ws.write_handshake_response(&accept_key)?;
+        ws.write_all(negotiated.response.as_bytes())?;
 
-        ws.state = ConnectionState::Connected;
-        Ok(ws)
+        ws.subprotocol = negotiated.subprotocol;
+        ws.protocol.open(negotiated.deflate);
+        Ok((ws, request))
     }
 
-    pub fn write_handshake_response(&mut self, accept_key: &str) -> Result<(), Error> {
-        let response = format!(
-            "HTTP/1.1 101 Switching Protocols\r\n\
-            Connection: Upgrade\r\n\
+    /// Performs the client side of the opening handshake over an already connected
+    /// stream, `host` is sent in the Host header. Frames sent on the returned socket are masked.
+    pub fn client(stream: S, host: &str, path: &str) -> Result<Self, Error> {
+        let mut ws = WebSocket::with_role(stream, Role::Client);
+
+        let key = BASE64_STANDARD.encode(rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+            Host: {}\r\n\
             Upgrade: websocket\r\n\
-            Sec-WebSocket-Accept: {}\r\n\r\n",
-            accept_key
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: {}\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
         );
+        ws.write_all(request.as_bytes())?;
 
-        self.stream.write_all(response.as_bytes())?;
+        let (status_line, headers) = ws.read_handshake_response(RequestLimits::default())?;
+        if !status_line.starts_with("HTTP/1.1 101") {
+            return Err(Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("Server refused the upgrade: {}", status_line),
+            ));
+        }
 
-        Ok(())
+        let has_token = |name: &str, token: &str| {
+            headers
+                .get_list(name)
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case(token))
+        };
+        if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Server response is missing the Upgrade headers",
+            ));
+        }
+        if headers.get("Sec-WebSocket-Accept") != Some(generate_accept_key(&key).as_str()) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Server sent the wrong Sec-WebSocket-Accept key",
+            ));
+        }
+        // We offer no extension or subprotocol, so the server can't pick one
+        if headers.contains("Sec-WebSocket-Extensions")
+            || headers.contains("Sec-WebSocket-Protocol")
+        {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Server selected an extension or subprotocol that wasn't offered",
+            ));
+        }
+
+        ws.protocol.open(None);
+        Ok(ws)
     }
 
-    pub fn send_pong(&mut self, payload: Vec<u8>) -> Result<(), Error> {
-        let frame = Frame::new(OpCode::Pong, payload);
-        self.write_all(&frame.to_bytes())
+    // Reads the status line and headers of the server's handshake response, a server
+    // sending endless headers is cut off like a client would be
+    fn read_handshake_response(
+        &mut self,
+        limits: RequestLimits,
+    ) -> Result<(String, Headers), Error> {
+        // A 1 byte buffer so none of the frames that may follow are consumed
+        let mut reader = BufReader::with_capacity(1, &mut self.stream);
+        request::parse_response(&mut reader, limits).map_err(|e| match e {
+            RequestError::Io(e) => e,
+            e => Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid handshake response: {}", e),
+            ),
+        })
     }
-    pub fn send_ping(&mut self, payload: Vec<u8>) -> Result<(), Error> {
-        let frame = Frame::new(OpCode::Ping, payload);
-        self.write_all(&frame.to_bytes())
+
+    pub fn state(&self) -> ConnectionState {
+        self.protocol.state()
     }
 
-    pub fn read_handshake_request(&mut self) -> Result<Request, Error> {
-        let mut buffer = Vec::new();
-        let mut headers = Vec::new();
-        let mut reader = BufReader::new(&mut self.stream);
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
 
-        // Read headers line by line until we hit an empty line
-        loop {
-            let mut line = String::new();
-            reader.read_line(&mut line);
+    pub fn is_compressed(&self) -> bool {
+        self.protocol.is_compressed()
+    }
 
-            // HTTP headers end with \r\n\r\n, so an empty line (just \r\n) marks the end
-            if line == "\r\n" || line == "\n" {
-                break;
-            }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
 
-            buffer.extend(line.as_bytes());
-            headers.push(line.trim().to_string());
-        }
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
 
-        // Parse into a Request struct
-        let request = Request {
-            headers,
-            raw: buffer,
-        };
+    pub fn write_handshake_response(
+        &mut self,
+        accept_key: &str,
+        headers: &[(&str, String)],
+    ) -> Result<(), Error> {
+        let response = handshake::response(accept_key, headers);
+        self.stream.write_all(response.as_bytes())?;
 
-        // Validate it's a valid WebSocket upgrade request
-        if !request
-            .headers
-            .iter()
-            .any(|h| h.contains("Upgrade: websocket"))
-        {
-            return Err(Error::new(
-                std::io::ErrorKind::Other,
-                "Not a WebSocket upgrade request".to_owned(),
-            ));
-        }
+        Ok(())
+    }
+
+    pub fn send_pong(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.protocol.send_pong(payload);
+        self.flush()
+    }
+    pub fn send_ping(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.protocol.send_ping(payload);
+        self.flush()
+    }
 
-        Ok(request)
+    /// Starts the closing handshake by sending a close frame with the given
+    /// status code and reason. The connection moves to `Closing` until the peer
+    /// echoes the close frame, which `read_message` picks up.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Error> {
+        self.protocol.close(code, reason)?;
+        self.flush()
+    }
+
+    pub fn read_handshake_request(
+        &mut self,
+        limits: RequestLimits,
+    ) -> Result<Request, RequestError> {
+        // A 1 byte buffer so the reader never pulls in frames sent right after the request
+        let mut reader = BufReader::with_capacity(1, &mut self.stream);
+        Request::parse_with_limits(&mut reader, limits)
+    }
+
+    // Answers a refused upgrade request with an HTTP error response
+    fn reject(&mut self, error: &HandshakeError) -> Result<(), Error> {
+        self.write_all(error.to_response().as_bytes())?;
+        self.protocol.abort();
+        Ok(())
     }
 
     pub fn read_exact(&mut self, num: usize) -> Result<Vec<u8>, std::io::Error> {
@@ -142,89 +292,259 @@ impl WebSocket {
         Ok(())
     }
 
+    // Writes whatever frames the protocol has queued to the stream
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.protocol.has_output() {
+            let output = self.protocol.take_output();
+            self.write_all(&output)?;
+        }
+        Ok(())
+    }
+
     pub fn read_frame(&mut self) -> Result<Frame, std::io::Error> {
-        // Read first 2 bytes (header)
-        let header = self.read_exact(2)?;
-
-        // Parse header using Frame::parse
-        let frame = Frame::parse(
-            (header[0] & 0x80) != 0, // fin
-            match header[0] & 0x0F {
-                // opcode
-                0x0 => Continuation,
-                0x1 => Text,
-                0x2 => Binary,
-                0x8 => ConnectionClosed,
-                0x9 => Ping,
-                0xA => Pong,
-                _ => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Invalid opcode",
+        self.read_with(Protocol::read_frame)
+    }
+
+    /// Reads the next complete message, see `Protocol::read_message`. Control
+    /// messages are returned as soon as they are read, even in the middle of a
+    /// fragmented message. Pings are answered and closes echoed before returning.
+    pub fn read_message(&mut self) -> Result<Message, std::io::Error> {
+        let message = self.read_with(Protocol::read_message)?;
+        if let Message::Pong(payload) = &message {
+            self.pong_received(payload);
+        }
+        Ok(message)
+    }
+
+    /// Sends a message of any kind, see `Protocol::send_message`. Written right
+    /// away, blocking until the stream took all of it: there is no send queue
+    /// on a WebSocket of its own. Split it for a bounded queue with an
+    /// overflow policy, or serve it from the Reactor, which has one too.
+    pub fn send_message(&mut self, message: Message) -> Result<(), Error> {
+        self.protocol.send_message(message)?;
+        self.flush()
+    }
+
+    // Feeds the protocol from the stream until `next` decodes something, sending
+    // the frames it queues on the way (close echoes, or the close frame of a failure)
+    fn read_with<T>(
+        &mut self,
+        mut next: impl FnMut(&mut Protocol) -> Result<Option<T>, Error>,
+    ) -> Result<T, Error> {
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        loop {
+            match next(&mut self.protocol) {
+                Ok(Some(item)) => {
+                    self.flush()?;
+                    return Ok(item);
+                }
+                Ok(None) => self.flush()?,
+                Err(e) => {
+                    let _ = self.flush();
+                    return Err(e);
+                }
+            }
+            self.keep_alive()?;
+
+            let read = match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "Connection closed by the peer",
                     ))
                 }
-            },
-            (header[1] & 0x80) != 0,   // mask
-            (header[1] & 0x7F) as u64, // payload_len
-        )?;
-
-        // Handle extended payload lengths
-        let actual_payload_len = if frame.payload_len == 126 {
-            let len_bytes = self.read_exact(2)?;
-            u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as u64
-        } else if frame.payload_len == 127 {
-            let len_bytes = self.read_exact(8)?;
-            u64::from_be_bytes(len_bytes.try_into().unwrap())
-        } else {
-            frame.payload_len
-        };
+                Ok(read) => read,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                // The read timeout set by keep_alive expired, time to ping or give up on the peer
+                Err(ref e)
+                    if self.heartbeat.is_some()
+                        && matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            self.protocol.receive(&buffer[..read]);
+        }
+    }
 
-        // Read mask key if needed
-        let mask_key: Option<[u8; 4]> = if frame.mask {
-            let mask_bytes = self.read_exact(4)?;
-            Some(mask_bytes.try_into().unwrap())
-        } else {
-            None
-        };
+    // Sends a text message, written right away like send_message
+    pub fn send(&mut self, payload: Vec<u8>) -> Result<(), std::io::Error> {
+        self.protocol.send(payload)?;
+        self.flush()
+    }
+
+    /// Waits for the next Text or Binary message and returns a reader over its
+    /// payload, which streams it in frame by frame instead of buffering it.
+    /// Pings received before it are answered; if the peer closes the connection
+    /// instead, the close is echoed and a `ConnectionAborted` error returned.
+    pub fn message_reader(&mut self) -> Result<MessageReader<'_, S>, Error> {
+        let frame = self.read_fragment()?;
+        Ok(MessageReader::new(
+            self,
+            frame.op_code,
+            frame.payload,
+            frame.fin,
+        ))
+    }
 
-        // Read and unmask payload
-        let mut payload = self.read_exact(actual_payload_len as usize)?;
-        if let Some(mask_key) = mask_key {
-            for i in 0..payload.len() {
-                payload[i] ^= mask_key[i % 4];
+    /// Starts a text message written in fragments, see `MessageWriter`.
+    pub fn text_writer(&mut self) -> MessageWriter<'_, S> {
+        MessageWriter::new(self, OpCode::Text)
+    }
+
+    /// Starts a binary message written in fragments, see `MessageWriter`.
+    pub fn binary_writer(&mut self) -> MessageWriter<'_, S> {
+        MessageWriter::new(self, OpCode::Binary)
+    }
+
+    // Reads the next frame of a streamed message, skipping the control frames in between
+    pub(super) fn read_fragment(&mut self) -> Result<Frame, Error> {
+        loop {
+            let frame = self.read_with(Protocol::read_fragment)?;
+            match frame.op_code {
+                OpCode::Ping => {}
+                OpCode::Pong => self.pong_received(&frame.payload),
+                OpCode::ConnectionClosed => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        "Connection closed by the peer",
+                    ))
+                }
+                _ => return Ok(frame),
             }
         }
+    }
 
-        Ok(Frame {
-            fin: frame.fin,
-            op_code: frame.op_code,
-            mask: frame.mask,
-            payload_len: actual_payload_len,
-            mask_key,
-            payload,
-        })
+    // Sends the ping that is due, or fails the connection if the last one went
+    // unanswered, then times the next read out when the heartbeat needs to run again
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        let Some((heartbeat, set_read_timeout)) = self.heartbeat.as_mut() else {
+            return Ok(());
+        };
+        let set_read_timeout = *set_read_timeout;
+        let timeout = heartbeat.drive(&mut self.protocol, Instant::now());
+
+        // Sends the ping, or the close frame when the peer timed out
+        match timeout {
+            Ok(timeout) => {
+                self.flush()?;
+                set_read_timeout(&self.stream, Some(timeout))
+            }
+            Err(e) => {
+                let _ = self.flush();
+                Err(e)
+            }
+        }
     }
 
-    pub fn send(&mut self, payload: Vec<u8>) -> Result<(), std::io::Error> {
-        let frame = Frame::new(OpCode::Text, payload);
-        self.write_all(&frame.to_bytes())
+    fn pong_received(&mut self, payload: &[u8]) {
+        if let Some((heartbeat, _)) = self.heartbeat.as_mut() {
+            heartbeat.on_pong(payload, Instant::now());
+        }
+    }
+
+    // Round trip time of the last ping answered, with the heartbeat enabled
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.as_ref()?.0.rtt()
+    }
+
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.heartbeat.as_ref()?.0.last_pong()
+    }
+
+    pub(super) fn send_fragment(
+        &mut self,
+        op_code: OpCode,
+        payload: Vec<u8>,
+        fin: bool,
+    ) -> Result<(), Error> {
+        self.protocol.send_fragment(op_code, payload, fin)?;
+        self.flush()
+    }
+
+    // Fails the connection, the close frame is sent if the stream still takes it
+    pub(super) fn fail(&mut self, code: CloseCode, reason: &str) -> Error {
+        let error = self.protocol.fail(code, reason);
+        let _ = self.flush();
+        error
+    }
+}
+
+impl<S: Read + Write + ReadTimeout> WebSocket<S> {
+    /// Pings the peer every `config.interval` and fails the connection with
+    /// 1001 if a ping isn't answered within `config.pong_timeout`. Pings go out
+    /// while blocked reading: reads time out when the next ping is due, so
+    /// `read_message` returns a `TimedOut` error for a peer gone silent instead
+    /// of blocking forever.
+    pub fn enable_heartbeat(&mut self, config: HeartbeatConfig) {
+        self.heartbeat = Some((
+            Heartbeat::new(config, Instant::now()),
+            <S as ReadTimeout>::set_read_timeout,
+        ));
     }
 }
-pub fn generate_accept_key(client_key: &str) -> String {
-    /*
-    Additionally, the server can decide on extension/subprotocol requests here;
-     see Miscellaneous for details.
-     The Sec-WebSocket-Accept header is important in that the server must derive
-     it from the Sec-WebSocket-Key that the client sent to it. To get it, concatenate
-     the client's Sec-WebSocket-Key and the string "258EAFA5-E914-47DA-95CA-C5AB0DC85B11" t
-     ogether (it's a "magic string"),
-     take the SHA-1 hash of the result, and return the base64 encoding of that hash. */
-    let magic_string = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-    let to_hash = format!("{}{}", client_key, magic_string);
-
-    let mut hasher = Sha1::new();
-    hasher.update(to_hash.as_bytes());
-    let result = hasher.finalize();
-
-    BASE64_STANDARD.encode(result)
+
+impl<S: Read + Write + TryClone + Send + 'static> WebSocket<S> {
+    /// Splits the connection into a reader half and a writer half over two
+    /// handles to the stream, so messages can be pushed from other threads
+    /// while one is blocked in `read_message`. The writer can be cloned, and
+    /// every handle sees the same close state. Sent messages are written by a
+    /// thread of their own from a queue bounded by `config.send_queue`. A TLS
+    /// stream is split once wrapped in a `SharedTlsStream`.
+    pub fn split(self) -> Result<(WebSocketReader<S>, WebSocketWriter), Error> {
+        let writer = self.stream.try_clone()?;
+        super::split::halves(
+            self.stream,
+            writer,
+            self.protocol,
+            SendQueue::new(self.send_queue),
+            self.heartbeat,
+        )
+    }
+}
+
+// Splits a ws:// url into host, port and path. An IPv6 host is written in brackets, it's
+// returned without them.
+pub(super) fn parse_ws_url(url: &str) -> Result<(String, u16, String), Error> {
+    let invalid_url = || {
+        Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid url: {}", url),
+        )
+    };
+
+    let rest = url.strip_prefix("ws://").ok_or_else(invalid_url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.strip_prefix('[') {
+        // The colons of the address aren't the one before the port
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(invalid_url)?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid_url)?)),
+            }
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            Some(_) => return Err(invalid_url()),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid_url())?,
+        None => 80,
+    };
+    if host.is_empty() {
+        return Err(invalid_url());
+    }
+
+    Ok((host.to_owned(), port, path.to_owned()))
 }
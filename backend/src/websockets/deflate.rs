@@ -0,0 +1,253 @@
+//permessage-deflate extension (RFC 7692): negotiation and compression of message payloads
+
+use std::io::{Error, ErrorKind};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+// Every message compressed with a sync flush ends with these 4 bytes, they are
+// removed before sending and added back before inflating (RFC 7692 section 7.2.1)
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+// What the server is willing to agree to
+#[derive(Debug, Clone)]
+pub struct DeflateConfig {
+    pub compression_level: u32,           // 0 (none) to 9 (best)
+    pub server_no_context_takeover: bool, // reset our compressor after every message
+    pub client_no_context_takeover: bool, // ask the client to reset its compressor
+    pub server_max_window_bits: u8,       // largest LZ77 window we compress with (9-15)
+    pub client_max_window_bits: u8,       // largest window we ask the client to use (8-15)
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            compression_level: 6,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+        }
+    }
+}
+
+// Parameters both sides agreed on during the handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: Option<u8>, // None when the client didn't offer the parameter
+}
+
+impl DeflateParams {
+    // Value of the Sec-WebSocket-Extensions response header
+    pub fn to_header(&self) -> String {
+        let mut header = EXTENSION_NAME.to_owned();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < 15 {
+            header.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+impl DeflateConfig {
+    // Picks the first permessage-deflate offer from the client's Sec-WebSocket-Extensions
+    // list that we can accept. Returns None if there is none, the connection then
+    // simply goes on without compression.
+    pub fn negotiate(&self, offers: &[&str]) -> Option<DeflateParams> {
+        offers
+            .iter()
+            .filter_map(|offer| {
+                let mut parts = offer.split(';').map(str::trim);
+                if parts.next()? != EXTENSION_NAME {
+                    return None;
+                }
+                self.accept_offer(parts)
+            })
+            .next()
+    }
+
+    fn accept_offer<'a>(&self, params: impl Iterator<Item = &'a str>) -> Option<DeflateParams> {
+        let mut server_no_context_takeover = false;
+        let mut client_no_context_takeover = false;
+        let mut server_max_window_bits = None;
+        let mut client_max_window_bits = None;
+
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+
+            // An offer with an unknown, duplicated or malformed parameter is declined
+            match (name, value) {
+                ("server_no_context_takeover", None) if !server_no_context_takeover => {
+                    server_no_context_takeover = true
+                }
+                ("client_no_context_takeover", None) if !client_no_context_takeover => {
+                    client_no_context_takeover = true
+                }
+                ("server_max_window_bits", Some(value)) if server_max_window_bits.is_none() => {
+                    server_max_window_bits = Some(parse_window_bits(value)?)
+                }
+                // Without a value the client only tells us it supports the parameter
+                ("client_max_window_bits", value) if client_max_window_bits.is_none() => {
+                    client_max_window_bits = Some(match value {
+                        Some(value) => parse_window_bits(value)?,
+                        None => 15,
+                    })
+                }
+                _ => return None,
+            }
+        }
+
+        let server_bits = server_max_window_bits
+            .unwrap_or(15)
+            .min(self.server_max_window_bits);
+        // zlib can't compress with a window of 8 bits, decline rather than break the client
+        if server_bits < 9 {
+            return None;
+        }
+
+        Some(DeflateParams {
+            server_no_context_takeover: server_no_context_takeover
+                || self.server_no_context_takeover,
+            client_no_context_takeover: client_no_context_takeover
+                || self.client_no_context_takeover,
+            server_max_window_bits: server_bits,
+            client_max_window_bits: client_max_window_bits
+                .map(|bits| bits.min(self.client_max_window_bits)),
+        })
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(bits @ 8..=15) => Some(bits),
+        _ => None,
+    }
+}
+
+// Compresses outgoing and inflates incoming message payloads for one connection
+pub struct PerMessageDeflate {
+    params: DeflateParams,
+    compress: Compress,
+    decompress: Decompress,
+    compression_level: u32,
+}
+
+impl std::fmt::Debug for PerMessageDeflate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PerMessageDeflate")
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl PerMessageDeflate {
+    pub fn new(params: DeflateParams, compression_level: u32) -> Self {
+        PerMessageDeflate {
+            compress: new_compress(compression_level, params.server_max_window_bits),
+            // Inflating with the largest window works whatever window the client used
+            decompress: Decompress::new_with_window_bits(false, 15),
+            compression_level,
+            params,
+        }
+    }
+
+    pub fn params(&self) -> &DeflateParams {
+        &self.params
+    }
+
+    // Compresses a whole message payload
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let start = self.compress.total_in();
+        let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            output.reserve(payload.len() - consumed + 64);
+            let status = self
+                .compress
+                .compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            // The flush is complete once all input is consumed and there was output space left
+            let consumed = (self.compress.total_in() - start) as usize;
+            if status == Status::BufError
+                || (consumed == payload.len() && output.len() < output.capacity())
+            {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+
+        if self.params.server_no_context_takeover {
+            self.compress =
+                new_compress(self.compression_level, self.params.server_max_window_bits);
+        }
+
+        Ok(output)
+    }
+
+    // Inflates the payload of one frame of a compressed message, fin marks the last frame.
+    // Stops once more than max_size bytes came out, so a small frame can't inflate into
+    // gigabytes: the caller sees the output is over its limit and fails the connection.
+    pub fn decompress(
+        &mut self,
+        chunk: &[u8],
+        fin: bool,
+        max_size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let limit = max_size.saturating_add(1);
+        let mut output = Vec::with_capacity((chunk.len() * 2 + 64).min(limit));
+
+        let mut input = chunk.to_vec();
+        if fin {
+            input.extend(DEFLATE_TRAILER);
+        }
+
+        let start = self.decompress.total_in();
+        while output.len() < limit {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            output.reserve_exact(output.capacity().max(64).min(limit - output.len()));
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            if status != Status::Ok || (consumed == input.len() && output.len() < output.capacity())
+            {
+                break;
+            }
+        }
+
+        if fin && self.params.client_no_context_takeover {
+            self.decompress = Decompress::new_with_window_bits(false, 15);
+        }
+
+        Ok(output)
+    }
+}
+
+fn new_compress(level: u32, window_bits: u8) -> Compress {
+    Compress::new_with_window_bits(Compression::new(level), false, window_bits)
+}
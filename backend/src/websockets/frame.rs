@@ -1,6 +1,7 @@
 #[derive(Debug)]
 pub struct Frame {
     pub fin: bool,
+    pub rsv1: bool, // set on the first frame of a message compressed with permessage-deflate
     pub op_code: OpCode, //tells what king of frame we have: 0x1 text, 0x2 binary, 0x8 connection closed, 0x9 ping, 0xA pong
     pub mask: bool,
     pub payload_len: u64,
@@ -18,10 +19,37 @@ pub enum OpCode {
     Pong = 0xA,
 }
 
+impl OpCode {
+    // Close, Ping and Pong are control frames, they have the high bit of the opcode set
+    pub fn is_control(&self) -> bool {
+        (*self as u8) & 0x8 != 0
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = std::io::Error;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0x0 => Ok(OpCode::Continuation),
+            0x1 => Ok(OpCode::Text),
+            0x2 => Ok(OpCode::Binary),
+            0x8 => Ok(OpCode::ConnectionClosed),
+            0x9 => Ok(OpCode::Ping),
+            0xA => Ok(OpCode::Pong),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid opcode",
+            )),
+        }
+    }
+}
+
 impl Frame {
     pub fn new(opcode: OpCode, payload: Vec<u8>) -> Self {
         Frame {
             fin: true,
+            rsv1: false,
             op_code: opcode,
             mask: false,
             payload_len: payload.len() as u64,
@@ -30,6 +58,15 @@ impl Frame {
         }
     }
 
+    // Text payloads are validated by WebSocket::read_message, so this only
+    // returns None for frames that aren't text
+    pub fn into_text(self) -> Option<String> {
+        match self.op_code {
+            OpCode::Text => String::from_utf8(self.payload).ok(),
+            _ => None,
+        }
+    }
+
     pub fn parse(
         fin: bool,
         opcode: OpCode,
@@ -38,7 +75,8 @@ impl Frame {
     ) -> Result<Frame, std::io::Error> {
         Ok(Frame {
             fin,
-            op_code: OpCode::from(opcode),
+            rsv1: false,
+            op_code: opcode,
             mask: masked,
             payload_len,
             mask_key: None,
@@ -49,8 +87,11 @@ impl Frame {
     pub(crate) fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        // First byte: FIN bit and opcode
+        // First byte: FIN bit, RSV1 and opcode
         let mut first_byte = if self.fin { 0x80 } else { 0x00 };
+        if self.rsv1 {
+            first_byte |= 0x40;
+        }
         first_byte |= self.op_code as u8;
         bytes.push(first_byte);
 
@@ -0,0 +1,154 @@
+//Application side of a connection: the servers accept the upgrade, then hand every message
+//to the handler routed for the request path. Handlers answer through the Session, which the
+//server sends from once the callback returns, and push later through the registry.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Error;
+use std::sync::{Arc, RwLock};
+
+use super::{
+    CloseCode, CloseFrame, ConnectionId, ConnectionInfo, HandshakeError, Message, Registry, Request,
+};
+
+/// Serves the connections upgraded on a path of the Router. The callbacks of a
+/// connection are called one at a time, from the thread serving it.
+pub trait WebSocketHandler: Send + Sync {
+    /// Once the handshake is done, before any message of the client
+    fn on_open(&self, _session: &mut Session) {}
+
+    /// For every text and binary message, pings and pongs are answered by the
+    /// protocol
+    fn on_message(&self, session: &mut Session, message: Message);
+
+    /// Once the connection is over, with the close frame of the client if it
+    /// sent one. Also called after on_error.
+    fn on_close(&self, _session: &Session, _frame: Option<&CloseFrame>) {}
+
+    /// When reading from or writing to the connection failed
+    fn on_error(&self, _session: &Session, _error: &Error) {}
+}
+
+// One connection as its handler sees it
+pub struct Session {
+    id: Option<ConnectionId>, // None when the connection isn't registered
+    path: String,
+    query: Vec<(String, String)>,
+    registry: Arc<Registry>,
+    outgoing: Vec<Message>,
+    close: Option<CloseFrame>,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &self.id)
+            .field("path", &self.path)
+            .field("outgoing", &self.outgoing.len())
+            .field("close", &self.close)
+            .finish()
+    }
+}
+
+impl Session {
+    pub fn new(id: Option<ConnectionId>, request: &Request, registry: Arc<Registry>) -> Self {
+        Session {
+            id,
+            path: request.path.clone(),
+            query: request.query.clone(),
+            registry,
+            outgoing: Vec::new(),
+            close: None,
+        }
+    }
+
+    // Where the connection is registered, None if it can't be pushed to
+    pub fn id(&self) -> Option<ConnectionId> {
+        self.id
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // First value of a query parameter of the upgrade request
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The connection as registered, with the user the server set on it
+    pub fn info(&self) -> Option<ConnectionInfo> {
+        self.id.and_then(|id| self.registry.get(id))
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    /// Queues a message, sent in order once the callback returns
+    pub fn send(&mut self, message: Message) {
+        self.outgoing.push(message);
+    }
+
+    /// Starts the close handshake once the messages queued before went out
+    pub fn close(&mut self, code: CloseCode, reason: &str) {
+        self.close = Some(CloseFrame::new(code, reason));
+    }
+
+    // What the handler asked for, for the server to carry out
+    pub fn take_outgoing(&mut self) -> (Vec<Message>, Option<CloseFrame>) {
+        (std::mem::take(&mut self.outgoing), self.close.take())
+    }
+}
+
+/// Picks the handler of a connection by the path of its upgrade request
+/// (`/ws/prices` for `GET /ws/prices?since=10 HTTP/1.1`). Paths are matched
+/// exactly, upgrades to any other path are refused with a 404.
+#[derive(Default)]
+pub struct Router {
+    routes: RwLock<HashMap<String, Arc<dyn WebSocketHandler>>>,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("paths", &self.paths())
+            .finish()
+    }
+}
+
+impl Router {
+    pub fn new() -> Arc<Router> {
+        Arc::new(Router::default())
+    }
+
+    /// Serves `path` with `handler`, replacing the handler it had. Connections
+    /// already open keep theirs.
+    pub fn route(&self, path: &str, handler: impl WebSocketHandler + 'static) {
+        self.routes
+            .write()
+            .unwrap()
+            .insert(path.to_owned(), Arc::new(handler));
+    }
+
+    pub fn remove(&self, path: &str) -> bool {
+        self.routes.write().unwrap().remove(path).is_some()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.routes.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn handler(&self, path: &str) -> Option<Arc<dyn WebSocketHandler>> {
+        self.routes.read().unwrap().get(path).cloned()
+    }
+
+    // The handler for an upgrade request, or the 404 refusing it
+    pub fn resolve(&self, request: &Request) -> Result<Arc<dyn WebSocketHandler>, HandshakeError> {
+        self.handler(&request.path)
+            .ok_or_else(|| HandshakeError::NotFound(request.path.clone()))
+    }
+}
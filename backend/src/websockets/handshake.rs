@@ -0,0 +1,234 @@
+//Opening handshake: validation of the client's upgrade request and HTTP error responses
+
+use std::fmt;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use super::{OriginPolicy, PerMessageDeflate, Request, RequestError, WebSocketConfig};
+
+pub const SUPPORTED_VERSION: &str = "13";
+
+// Why an upgrade request was refused, each maps to the HTTP response sent back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    BadRequest(String), // 400
+    Forbidden(String),  // 403
+    NotFound(String),   // 404, no handler is routed for the path
+    UnsupportedVersion, // 426, tells the client which version we speak
+}
+
+impl HandshakeError {
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            HandshakeError::BadRequest(_) => (400, "Bad Request"),
+            HandshakeError::Forbidden(_) => (403, "Forbidden"),
+            HandshakeError::NotFound(_) => (404, "Not Found"),
+            HandshakeError::UnsupportedVersion => (426, "Upgrade Required"),
+        }
+    }
+
+    // Only malformed requests get a 400, there is no one to answer when reading failed
+    pub fn from_request_error(e: &RequestError) -> Option<HandshakeError> {
+        match e {
+            RequestError::Io(_) | RequestError::UnexpectedEof => None,
+            e => Some(HandshakeError::BadRequest(e.to_string())),
+        }
+    }
+
+    // Full HTTP response for the error, the connection is closed after sending it
+    pub fn to_response(&self) -> String {
+        let (code, reason) = self.status();
+        let body = self.to_string();
+
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n",
+            code,
+            reason,
+            body.len()
+        );
+        if let HandshakeError::UnsupportedVersion = self {
+            response.push_str(&format!("Sec-WebSocket-Version: {}\r\n", SUPPORTED_VERSION));
+        }
+        response.push_str("\r\n");
+        response.push_str(&body);
+        response
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::BadRequest(reason) | HandshakeError::Forbidden(reason) => {
+                write!(f, "{}", reason)
+            }
+            HandshakeError::NotFound(path) => write!(f, "No WebSocket endpoint at {}", path),
+            HandshakeError::UnsupportedVersion => write!(
+                f,
+                "Unsupported WebSocket version, supported versions: {}",
+                SUPPORTED_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for std::io::Error {
+    fn from(e: HandshakeError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// Checks the page that opened the connection is allowed to by the policy
+pub fn check_origin(request: &Request, policy: &OriginPolicy) -> Result<(), HandshakeError> {
+    let origin = match request.headers.get_all("Origin") {
+        [] => None,
+        [origin] => Some(origin.as_str()),
+        _ => {
+            return Err(HandshakeError::BadRequest(
+                "Duplicate Origin header".to_owned(),
+            ))
+        }
+    };
+
+    if !policy.allows(origin) {
+        return Err(HandshakeError::Forbidden(format!(
+            "Origin {} is not allowed",
+            origin.unwrap_or_default()
+        )));
+    }
+    Ok(())
+}
+
+// Checks the request is a valid WebSocket upgrade (RFC 6455 section 4.2.1),
+// returns the client's Sec-WebSocket-Key
+pub fn validate_request(request: &Request) -> Result<&str, HandshakeError> {
+    let bad_request = |reason: &str| Err(HandshakeError::BadRequest(reason.to_owned()));
+
+    if request.method != "GET" {
+        return bad_request("WebSocket upgrades must use GET");
+    }
+    if request.version != "HTTP/1.1" {
+        return bad_request("WebSocket upgrades must use HTTP/1.1");
+    }
+    if !request.headers.contains("Host") {
+        return bad_request("Missing Host header");
+    }
+
+    let has_token = |name: &str, token: &str| {
+        request
+            .headers
+            .get_list(name)
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(token))
+    };
+    if !has_token("Upgrade", "websocket") {
+        return bad_request("Not a WebSocket upgrade request");
+    }
+    if !has_token("Connection", "upgrade") {
+        return bad_request("Connection header must contain Upgrade");
+    }
+
+    match request.headers.get_all("Sec-WebSocket-Version") {
+        [] => return bad_request("Missing Sec-WebSocket-Version header"),
+        [version] if version == SUPPORTED_VERSION => {}
+        _ => return Err(HandshakeError::UnsupportedVersion),
+    }
+
+    // The key is a random 16 byte value encoded in base64
+    let key = match request.headers.get_all("Sec-WebSocket-Key") {
+        [key] => key.as_str(),
+        [] => return bad_request("Missing Sec-WebSocket-Key header"),
+        _ => return bad_request("Duplicate Sec-WebSocket-Key header"),
+    };
+    match BASE64_STANDARD.decode(key) {
+        Ok(bytes) if bytes.len() == 16 => Ok(key),
+        _ => bad_request("Sec-WebSocket-Key must be 16 bytes encoded in base64"),
+    }
+}
+
+// What the server agreed on for an upgrade request, before the response is sent
+#[derive(Debug)]
+pub(crate) struct Negotiated {
+    pub response: String, // the 101 Switching Protocols response
+    pub deflate: Option<PerMessageDeflate>,
+    pub subprotocol: Option<String>,
+}
+
+// Validates an upgrade request against the config and agrees on the extensions and
+// subprotocol, shared by the blocking and async servers
+pub(crate) fn negotiate(
+    request: &Request,
+    config: &WebSocketConfig,
+) -> Result<Negotiated, HandshakeError> {
+    let client_key = validate_request(request)?;
+    check_origin(request, &config.origin_policy)?;
+
+    let mut headers = Vec::new();
+    let mut deflate = None;
+
+    // Agree on permessage-deflate if the client offered it and it's enabled
+    if let Some(deflate_config) = &config.deflate {
+        let offers = request.headers.get_list("Sec-WebSocket-Extensions");
+        if let Some(params) = deflate_config.negotiate(&offers) {
+            headers.push(("Sec-WebSocket-Extensions", params.to_header()));
+            deflate = Some(PerMessageDeflate::new(
+                params,
+                deflate_config.compression_level,
+            ));
+        }
+    }
+
+    // Agree on the application protocol, the header is left out if none matches
+    let offered = request.headers.get_list("Sec-WebSocket-Protocol");
+    let subprotocol = config.select_subprotocol(&offered);
+    if let Some(protocol) = &subprotocol {
+        headers.push(("Sec-WebSocket-Protocol", protocol.clone()));
+    }
+
+    Ok(Negotiated {
+        response: response(&generate_accept_key(client_key), &headers),
+        deflate,
+        subprotocol,
+    })
+}
+
+// The 101 response accepting the upgrade, with the negotiated headers
+pub(crate) fn response(accept_key: &str, headers: &[(&str, String)]) -> String {
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Accept: {}\r\n",
+        accept_key
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response
+}
+
+pub fn generate_accept_key(client_key: &str) -> String {
+    /*
+    Additionally, the server can decide on extension/subprotocol requests here;
+     see Miscellaneous for details.
+     The Sec-WebSocket-Accept header is important in that the server must derive
+     it from the Sec-WebSocket-Key that the client sent to it. To get it, concatenate
+     the client's Sec-WebSocket-Key and the string "258EAFA5-E914-47DA-95CA-C5AB0DC85B11" t
+     ogether (it's a "magic string"),
+     take the SHA-1 hash of the result, and return the base64 encoding of that hash. */
+    let magic_string = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let to_hash = format!("{}{}", client_key, magic_string);
+
+    let mut hasher = Sha1::new();
+    hasher.update(to_hash.as_bytes());
+    let result = hasher.finalize();
+
+    BASE64_STANDARD.encode(result)
+}
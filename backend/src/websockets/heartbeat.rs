@@ -0,0 +1,181 @@
+//Keepalive: periodic pings, a deadline for the pong, and round trip time measurement. Once
+//we sent a close frame, the same deadline applies to the peer's echo.
+//Time is passed in, so the same logic can drive a blocking socket or an event loop.
+
+use std::io::{Error, ErrorKind};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use rustls::StreamOwned;
+
+use super::{CloseCode, ConnectionState, Protocol};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,     // time between two pings
+    pub pong_timeout: Duration, // how long the peer has to answer a ping or our close frame
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// What the connection should do next, returned by Heartbeat::poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeartbeatAction {
+    Wait,          // nothing to do before Heartbeat::deadline
+    Ping(Vec<u8>), // send a ping with this payload
+    PongTimedOut,  // the last ping went unanswered, disconnect the peer
+}
+
+#[derive(Debug)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    next_ping: Instant,
+    pending: Option<(u64, Instant)>, // id of the ping waiting for its pong, and when it was sent
+    next_id: u64,                    // pings carry an increasing id so pongs can be matched
+    last_pong: Option<Instant>,
+    rtt: Option<Duration>,    // round trip time of the last answered ping
+    closing: Option<Instant>, // when our close frame was sent, the echo is due pong_timeout later
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig, now: Instant) -> Self {
+        Heartbeat {
+            config,
+            next_ping: now + config.interval,
+            pending: None,
+            next_id: 0,
+            last_pong: None,
+            rtt: None,
+            closing: None,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> HeartbeatAction {
+        if let Some((_, sent)) = self.pending {
+            if now >= sent + self.config.pong_timeout {
+                return HeartbeatAction::PongTimedOut;
+            }
+            return HeartbeatAction::Wait;
+        }
+        if now < self.next_ping {
+            return HeartbeatAction::Wait;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending = Some((id, now));
+        self.next_ping = now + self.config.interval;
+        HeartbeatAction::Ping(id.to_be_bytes().to_vec())
+    }
+
+    // Records a pong, it answers our ping if it echoes its payload. Other pongs
+    // (unsolicited ones are allowed as a one-way keepalive) only update last_pong.
+    pub fn on_pong(&mut self, payload: &[u8], now: Instant) {
+        self.last_pong = Some(now);
+        if let Some((id, sent)) = self.pending {
+            if payload == id.to_be_bytes() {
+                self.rtt = Some(now - sent);
+                self.pending = None;
+            }
+        }
+    }
+
+    // Starts the deadline for the close echo, drive starts it otherwise the first time it
+    // runs on a closing connection
+    pub fn on_close(&mut self, now: Instant) {
+        self.pending = None;
+        self.closing.get_or_insert(now);
+    }
+
+    // Runs poll on a connection: queues the ping that is due, or fails the connection
+    // with 1001 if the last one went unanswered. Once our close frame is sent, aborts
+    // the connection if the peer didn't echo it in time. Returns how long until the next
+    // run, to use as the read timeout (never zero, sockets take that as no timeout at all).
+    pub fn drive(&mut self, protocol: &mut Protocol, now: Instant) -> Result<Duration, Error> {
+        if protocol.state() == ConnectionState::Closing {
+            // Nothing may follow our close frame, no more pings
+            self.on_close(now);
+        }
+        if let Some(closing) = self.closing {
+            if protocol.state() == ConnectionState::Closing
+                && now >= closing + self.config.pong_timeout
+            {
+                protocol.abort();
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Peer did not answer the close frame in time",
+                ));
+            }
+            let timeout = self.deadline().saturating_duration_since(now);
+            return Ok(timeout.max(Duration::from_millis(1)));
+        }
+
+        match self.poll(now) {
+            HeartbeatAction::Wait => {}
+            HeartbeatAction::Ping(payload)
+                if matches!(
+                    protocol.state(),
+                    ConnectionState::Connecting | ConnectionState::Connected
+                ) =>
+            {
+                protocol.send_ping(payload)
+            }
+            // The connection is closed, the ping is skipped until the next one
+            HeartbeatAction::Ping(_) => self.pending = None,
+            HeartbeatAction::PongTimedOut => {
+                protocol.fail(CloseCode::GoingAway, "Pong timeout");
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Peer did not answer the ping in time",
+                ));
+            }
+        }
+
+        let timeout = self.deadline().saturating_duration_since(now);
+        Ok(timeout.max(Duration::from_millis(1)))
+    }
+
+    // When poll has something to do next
+    pub fn deadline(&self) -> Instant {
+        if let Some(closing) = self.closing {
+            return closing + self.config.pong_timeout;
+        }
+        match self.pending {
+            Some((_, sent)) => sent + self.config.pong_timeout,
+            None => self.next_ping,
+        }
+    }
+
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.last_pong
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+// Streams whose reads can time out, so a blocked read wakes up to send the next ping
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+// wss, on either end of the connection
+impl<C, T: std::io::Read + std::io::Write + ReadTimeout> ReadTimeout for StreamOwned<C, T> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
@@ -0,0 +1,98 @@
+//Messages as the application sees them, framing details left out
+
+use std::io::{Error, ErrorKind};
+
+use super::{CloseFrame, Frame, OpCode};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>), // answered automatically, returned so the application can see it
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>), // None when the close frame carried no status code
+}
+
+impl Message {
+    pub fn text(text: impl Into<String>) -> Message {
+        Message::Text(text.into())
+    }
+
+    pub fn binary(data: impl Into<Vec<u8>>) -> Message {
+        Message::Binary(data.into())
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, Message::Text(_))
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Message::Binary(_))
+    }
+
+    pub fn is_close(&self) -> bool {
+        matches!(self, Message::Close(_))
+    }
+
+    // None for anything but a text message
+    pub fn into_text(self) -> Option<String> {
+        match self {
+            Message::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    // Payload bytes of any message, a close message gives its code and reason
+    pub fn into_data(self) -> Vec<u8> {
+        match self {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+            Message::Close(close_frame) => close_frame
+                .map(|close_frame| close_frame.to_payload())
+                .unwrap_or_default(),
+        }
+    }
+
+    // Converts a complete message or control frame decoded by the protocol, which
+    // already validated text and close payloads
+    pub(crate) fn from_frame(frame: Frame) -> Result<Message, Error> {
+        match frame.op_code {
+            OpCode::Text => String::from_utf8(frame.payload)
+                .map(Message::Text)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            OpCode::Binary => Ok(Message::Binary(frame.payload)),
+            OpCode::Ping => Ok(Message::Ping(frame.payload)),
+            OpCode::Pong => Ok(Message::Pong(frame.payload)),
+            OpCode::ConnectionClosed => CloseFrame::from_payload(&frame.payload)
+                .map(Message::Close)
+                .map_err(|code| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid close frame (close code {})", code),
+                    )
+                }),
+            OpCode::Continuation => Err(Error::new(
+                ErrorKind::InvalidData,
+                "A continuation frame is not a message",
+            )),
+        }
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
@@ -1,14 +1,50 @@
+#[cfg(feature = "async")]
+mod asynchronous;
+mod close;
+mod config;
 mod connection;
+mod deflate;
 // mod constants;
 mod frame;
-// mod handshake;
+mod handler;
+mod handshake;
+mod heartbeat;
+mod message;
+mod origin;
+mod protocol;
+mod queue;
+mod registry;
 pub mod request;
+mod split;
+mod stream;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod utf8;
 
 use std::io::Error;
 
+#[cfg(feature = "async")]
+pub use asynchronous::{accept_tls_async, AsyncTlsStream, AsyncWebSocket};
+pub use close::{CloseCode, CloseFrame};
+pub use config::WebSocketConfig;
 pub use connection::WebSocket;
+pub use deflate::{DeflateConfig, DeflateParams, PerMessageDeflate};
 pub use frame::{Frame, OpCode};
-pub use request::Request;
+pub use handler::{Router, Session, WebSocketHandler};
+pub(crate) use handshake::negotiate;
+pub use handshake::{check_origin, generate_accept_key, validate_request, HandshakeError};
+pub use heartbeat::{Heartbeat, HeartbeatAction, HeartbeatConfig, ReadTimeout};
+pub use message::Message;
+pub use origin::OriginPolicy;
+pub use protocol::{ConnectionState, Protocol, Role};
+pub use queue::{Overflow, OverflowPolicy, QueueMetrics, SendQueue, SendQueueConfig};
+pub use registry::{ConnectionId, ConnectionInfo, Outbox, Registration, Registry};
+pub use request::{Headers, Request, RequestError, RequestLimits};
+pub use split::{TryClone, WebSocketReader, WebSocketWriter};
+pub use stream::{MessageReader, MessageWriter, FRAGMENT_SIZE};
+pub use tls::{accept_tls, SharedTlsStream, TlsConfig, TlsStream};
+pub use utf8::Utf8Validator;
 
 // Re-export main types
 pub type Result<T> = std::result::Result<T, Error>;
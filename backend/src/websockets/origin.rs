@@ -0,0 +1,52 @@
+//Origin checks protecting against cross-site WebSocket hijacking: browsers send the
+//page's cookies with every upgrade request, whatever site the page comes from
+
+// Which pages may open a connection, based on the Origin header browsers send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginPolicy {
+    AllowAny,
+    // Exact origins like "https://app.example.com" or wildcard subdomains like
+    // "https://*.example.com" (which doesn't match https://example.com itself)
+    AllowList(Vec<String>),
+    AllowNone, // no browser page at all, only clients that don't send an Origin
+}
+
+impl OriginPolicy {
+    // Requests without an Origin header don't come from a browser, so they can't
+    // carry a hijacked session and are let through by every policy
+    pub fn allows(&self, origin: Option<&str>) -> bool {
+        let Some(origin) = origin else {
+            return true;
+        };
+
+        match self {
+            OriginPolicy::AllowAny => true,
+            OriginPolicy::AllowList(patterns) => patterns
+                .iter()
+                .any(|pattern| origin_matches(pattern, origin)),
+            OriginPolicy::AllowNone => false,
+        }
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let origin = origin.to_ascii_lowercase();
+
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return pattern == origin;
+    };
+
+    // Whatever is left in front of ".domain" must be one or more subdomain labels
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .and_then(|host| host.strip_suffix(domain))
+        .and_then(|subdomain| subdomain.strip_suffix('.'))
+        .is_some_and(|subdomain| {
+            !subdomain.is_empty()
+                && subdomain
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        })
+}
@@ -0,0 +1,626 @@
+//Sans-IO WebSocket protocol: turns received bytes into frames and messages, and queues the
+//bytes to send, without ever touching a socket. WebSocket drives it over a blocking stream.
+
+use std::io::{Error, ErrorKind};
+
+use super::{CloseCode, CloseFrame, Frame, Message, OpCode, PerMessageDeflate};
+use super::{Utf8Validator, WebSocketConfig};
+use crate::websockets::OpCode::{Binary, ConnectionClosed, Continuation, Ping, Pong, Text};
+
+// Which end of the connection we are, decides who masks frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Closing, // we sent a close frame and are waiting for the peer to echo it
+    Closed,
+}
+
+// The opening handshake is left to the caller, the protocol takes over once it's done
+#[derive(Debug)]
+pub struct Protocol {
+    role: Role,
+    state: ConnectionState,
+    input: Vec<u8>,           // received bytes that don't make up a whole frame yet
+    output: Vec<u8>,          // encoded frames waiting to be written to the transport
+    fragments: Option<Frame>, // first frame of a fragmented message, payload grows with each continuation
+    fragment_count: usize,    // frames received so far for the fragmented message
+    streaming: bool, // the fragmented message is handed out frame by frame instead of buffered
+    utf8: Utf8Validator, // validates text messages fragment by fragment
+    deflate: Option<PerMessageDeflate>, // set when permessage-deflate was negotiated
+    max_frame_size: usize,
+    max_message_size: usize,
+    max_fragments: usize,
+}
+
+impl Protocol {
+    pub fn new(role: Role) -> Self {
+        Protocol::with_config(role, &WebSocketConfig::default())
+    }
+
+    // Takes the frame and message size limits from the config
+    pub fn with_config(role: Role, config: &WebSocketConfig) -> Self {
+        Protocol {
+            role,
+            state: ConnectionState::Connecting,
+            input: Vec::new(),
+            output: Vec::new(),
+            fragments: None,
+            fragment_count: 0,
+            streaming: false,
+            utf8: Utf8Validator::new(),
+            deflate: None,
+            max_frame_size: config.max_frame_size,
+            max_message_size: config.max_message_size,
+            max_fragments: config.max_fragments,
+        }
+    }
+
+    /// Marks the opening handshake as done, with the compression it negotiated if any.
+    pub fn open(&mut self, deflate: Option<PerMessageDeflate>) {
+        self.deflate = deflate;
+        self.state = ConnectionState::Connected;
+    }
+
+    // The handshake was refused, no frame is ever exchanged
+    pub(crate) fn abort(&mut self) {
+        self.state = ConnectionState::Closed;
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.deflate.is_some()
+    }
+
+    /// Hands bytes read from the transport to the protocol, they are decoded by
+    /// the next call to `read_frame` or `read_message`.
+    pub fn receive(&mut self, data: &[u8]) {
+        self.input.extend_from_slice(data);
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Takes the bytes queued for the peer: frames sent by the caller, close
+    /// echoes and the close frame of a failed connection.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // Sends a text message
+    pub fn send(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        self.send_data(OpCode::Text, payload)
+    }
+
+    /// Sends any kind of message. Closing with `Message::Close` starts the
+    /// closing handshake like `close`, `None` sends a close frame without a code.
+    pub fn send_message(&mut self, message: Message) -> Result<(), Error> {
+        match message {
+            Message::Text(text) => self.send_data(OpCode::Text, text.into_bytes()),
+            Message::Binary(data) => self.send_data(OpCode::Binary, data),
+            Message::Ping(data) => self
+                .check_control_payload(&data)
+                .map(|_| self.send_ping(data)),
+            Message::Pong(data) => self
+                .check_control_payload(&data)
+                .map(|_| self.send_pong(data)),
+            Message::Close(Some(close_frame)) => self.close(close_frame.code, &close_frame.reason),
+            Message::Close(None) => {
+                if self.state == ConnectionState::Connected {
+                    self.write_frame(Frame::new(OpCode::ConnectionClosed, Vec::new()));
+                    self.state = ConnectionState::Closing;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Control frames carry at most 125 bytes
+    fn check_control_payload(&self, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > 125 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Ping and pong payloads are limited to 125 bytes",
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        if matches!(
+            self.state,
+            ConnectionState::Closing | ConnectionState::Closed
+        ) {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "Cannot send after the close frame was sent",
+            ));
+        }
+        Ok(())
+    }
+
+    // Once closed or failed, whatever the peer sent after is ignored
+    fn check_readable(&self) -> Result<(), Error> {
+        if self.state == ConnectionState::Closed {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "The connection is closed",
+            ));
+        }
+        Ok(())
+    }
+
+    fn send_data(&mut self, op_code: OpCode, payload: Vec<u8>) -> Result<(), Error> {
+        self.check_open()?;
+        let mut frame = Frame::new(op_code, payload);
+
+        // Data messages are compressed whole and marked with RSV1
+        if let Some(deflate) = self.deflate.as_mut() {
+            frame.payload = deflate.compress(&frame.payload)?;
+            frame.payload_len = frame.payload.len() as u64;
+            frame.rsv1 = true;
+        }
+
+        self.write_frame(frame);
+        Ok(())
+    }
+
+    /// Sends one frame of a fragmented message: `op_code` is Text or Binary for
+    /// the first frame and Continuation for the ones after it, `fin` marks the
+    /// last. Fragments are never compressed.
+    pub fn send_fragment(
+        &mut self,
+        op_code: OpCode,
+        payload: Vec<u8>,
+        fin: bool,
+    ) -> Result<(), Error> {
+        self.check_open()?;
+        let mut frame = Frame::new(op_code, payload);
+        frame.fin = fin;
+
+        self.write_frame(frame);
+        Ok(())
+    }
+
+    pub fn send_ping(&mut self, payload: Vec<u8>) {
+        self.write_frame(Frame::new(OpCode::Ping, payload))
+    }
+
+    pub fn send_pong(&mut self, payload: Vec<u8>) {
+        self.write_frame(Frame::new(OpCode::Pong, payload))
+    }
+
+    /// Starts the closing handshake by queueing a close frame with the given
+    /// status code and reason. The connection moves to `Closing` until the peer
+    /// echoes the close frame, which `read_message` picks up.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), Error> {
+        check_close(code, reason)?;
+        if matches!(
+            self.state,
+            ConnectionState::Closing | ConnectionState::Closed
+        ) {
+            return Ok(());
+        }
+
+        self.send_close(&CloseFrame::new(code, reason));
+        self.state = ConnectionState::Closing;
+        Ok(())
+    }
+
+    fn send_close(&mut self, close_frame: &CloseFrame) {
+        self.write_frame(Frame::new(
+            OpCode::ConnectionClosed,
+            close_frame.to_payload(),
+        ))
+    }
+
+    // Handles a close frame from the peer: echoes it back if the peer started the
+    // closing handshake, or completes the handshake if we started it
+    fn receive_close(&mut self, frame: &Frame) -> Result<(), Error> {
+        let close_frame = match CloseFrame::from_payload(&frame.payload) {
+            Ok(close_frame) => close_frame,
+            Err(code) => return Err(self.fail(code, "Invalid close frame")),
+        };
+
+        if self.state == ConnectionState::Closing {
+            self.state = ConnectionState::Closed;
+            return Ok(());
+        }
+
+        // Echo the status code we received, a close without a code gets an empty reply
+        self.state = ConnectionState::Closed;
+        let payload = close_frame
+            .map(|close_frame| CloseFrame::new(close_frame.code, "").to_payload())
+            .unwrap_or_default();
+        self.write_frame(Frame::new(OpCode::ConnectionClosed, payload));
+        Ok(())
+    }
+
+    /// Fails the connection: queues a close frame with the given code and
+    /// returns the error to hand back to the caller.
+    pub fn fail(&mut self, code: CloseCode, reason: &str) -> Error {
+        if matches!(
+            self.state,
+            ConnectionState::Connecting | ConnectionState::Connected
+        ) {
+            self.send_close(&CloseFrame::new(code, reason));
+        }
+        self.state = ConnectionState::Closed;
+
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} (close code {})", reason, code),
+        )
+    }
+
+    /// Decodes the next frame from the received bytes, `None` until all of it
+    /// has arrived. The header is validated as soon as its first 2 bytes are in,
+    /// so a bad frame fails the connection without waiting for its payload.
+    pub fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.input.len() < 2 {
+            return Ok(None);
+        }
+        let header = [self.input[0], self.input[1]];
+
+        // RSV2 and RSV3 must be 0 since we don't negotiate any extension that defines them,
+        // RSV1 marks a compressed message once permessage-deflate is negotiated
+        let rsv1 = header[0] & 0x40 != 0;
+        if header[0] & 0x30 != 0 || (rsv1 && self.deflate.is_none()) {
+            return Err(self.fail(CloseCode::ProtocolError, "Reserved bits set"));
+        }
+
+        let op_code = match OpCode::try_from(header[0] & 0x0F) {
+            Ok(op_code) => op_code,
+            Err(_) => return Err(self.fail(CloseCode::ProtocolError, "Invalid opcode")),
+        };
+
+        let frame = Frame::parse(
+            (header[0] & 0x80) != 0,   // fin
+            op_code,                   // opcode
+            (header[1] & 0x80) != 0,   // mask
+            (header[1] & 0x7F) as u64, // payload_len
+        )?;
+
+        // Only the first frame of a data message can be marked compressed (RFC 7692 section 6)
+        if rsv1 && !matches!(frame.op_code, Text | Binary) {
+            return Err(self.fail(CloseCode::ProtocolError, "RSV1 set on a non-data frame"));
+        }
+
+        // Clients must mask every frame they send, servers must not (RFC 6455 section 5.1)
+        match (self.role, frame.mask) {
+            (Role::Server, false) => {
+                return Err(self.fail(CloseCode::ProtocolError, "Client frame is not masked"))
+            }
+            (Role::Client, true) => {
+                return Err(self.fail(CloseCode::ProtocolError, "Server frame is masked"))
+            }
+            _ => {}
+        }
+
+        // Control frames can't be fragmented and carry at most 125 bytes (section 5.5)
+        if frame.op_code.is_control() {
+            if !frame.fin {
+                return Err(self.fail(CloseCode::ProtocolError, "Fragmented control frame"));
+            }
+            if frame.payload_len > 125 {
+                return Err(self.fail(CloseCode::ProtocolError, "Control frame too long"));
+            }
+        }
+
+        // Extended payload length and mask key follow the first 2 bytes
+        let length_bytes = match frame.payload_len {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let header_len = 2 + length_bytes + if frame.mask { 4 } else { 0 };
+        if self.input.len() < header_len {
+            return Ok(None);
+        }
+
+        let length = &self.input[2..2 + length_bytes];
+        let actual_payload_len = match length_bytes {
+            2 => u16::from_be_bytes([length[0], length[1]]) as u64,
+            8 => {
+                let len = u64::from_be_bytes(length.try_into().unwrap());
+
+                // The most significant bit of a 64 bit length must be 0
+                if len & (1 << 63) != 0 {
+                    return Err(self.fail(CloseCode::ProtocolError, "Invalid payload length"));
+                }
+                len
+            }
+            _ => frame.payload_len,
+        };
+
+        // Refuse oversized frames and messages before waiting for (and buffering) their payload
+        self.check_limits(frame.op_code, rsv1, actual_payload_len)?;
+
+        let mask_key: Option<[u8; 4]> = frame
+            .mask
+            .then(|| self.input[2 + length_bytes..header_len].try_into().unwrap());
+
+        let Some(frame_len) = usize::try_from(actual_payload_len)
+            .ok()
+            .and_then(|len| len.checked_add(header_len))
+        else {
+            return Err(self.fail(CloseCode::MessageTooBig, "Frame too large"));
+        };
+        if self.input.len() < frame_len {
+            return Ok(None);
+        }
+
+        // Take the payload out of the buffer and unmask it
+        let mut payload = self.input[header_len..frame_len].to_vec();
+        self.input.drain(..frame_len);
+        if let Some(mask_key) = mask_key {
+            for i in 0..payload.len() {
+                payload[i] ^= mask_key[i % 4];
+            }
+        }
+
+        Ok(Some(Frame {
+            fin: frame.fin,
+            rsv1,
+            op_code: frame.op_code,
+            mask: frame.mask,
+            payload_len: actual_payload_len,
+            mask_key,
+            payload,
+        }))
+    }
+
+    // Fails with 1009 if a data frame of this length would break the frame, message or
+    // fragment limits. Compressed messages are checked again once inflated.
+    fn check_limits(&mut self, op_code: OpCode, rsv1: bool, payload_len: u64) -> Result<(), Error> {
+        if payload_len > self.max_frame_size as u64 {
+            return Err(self.fail(CloseCode::MessageTooBig, "Frame too large"));
+        }
+
+        let (buffered, compressed) = match (op_code, self.fragments.as_ref()) {
+            // A streamed message is never buffered whole, only its frames are limited
+            (Continuation, Some(_)) if self.streaming => return Ok(()),
+            (Continuation, Some(message)) => {
+                if self.fragment_count >= self.max_fragments {
+                    return Err(self.fail(CloseCode::MessageTooBig, "Too many fragments"));
+                }
+                (message.payload.len() as u64, message.rsv1)
+            }
+            (Text | Binary, _) => (0, rsv1),
+            _ => return Ok(()),
+        };
+        if !compressed && buffered + payload_len > self.max_message_size as u64 {
+            return Err(self.fail(CloseCode::MessageTooBig, "Message too large"));
+        }
+        Ok(())
+    }
+
+    /// Decodes the next complete message from the received bytes, `None` until
+    /// one is available. A Text/Binary frame with `fin == false` is buffered
+    /// together with the Continuation frames that follow it, and the whole
+    /// message is returned once the final fragment arrives. Control messages
+    /// (Ping, Pong, Close) may be interleaved with the fragments and are returned
+    /// as soon as they are decoded. Pings are answered with a pong and closes
+    /// echoed, the replies are queued in the output.
+    pub fn read_message(&mut self) -> Result<Option<Message>, Error> {
+        self.check_readable()?;
+        let Some(frame) = self.read_message_frame()? else {
+            return Ok(None);
+        };
+
+        if matches!(frame.op_code, Ping) {
+            self.answer_ping(&frame);
+        }
+        Message::from_frame(frame).map(Some)
+    }
+
+    // Nothing may follow our close frame, so pings are only answered until we send it
+    fn answer_ping(&mut self, ping: &Frame) {
+        if matches!(
+            self.state,
+            ConnectionState::Connecting | ConnectionState::Connected
+        ) {
+            self.send_pong(ping.payload.clone());
+        }
+    }
+
+    // Reassembles fragmented messages, see read_message
+    fn read_message_frame(&mut self) -> Result<Option<Frame>, Error> {
+        // The rest of a message that was streamed until now is buffered, within the limits
+        self.streaming = false;
+        while let Some(mut frame) = self.read_frame()? {
+            match frame.op_code {
+                Text | Binary => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(
+                            CloseCode::ProtocolError,
+                            "New data frame received before the fragmented message was finished",
+                        ));
+                    }
+                    frame.payload = self.decode_fragment(
+                        frame.op_code,
+                        frame.rsv1,
+                        frame.payload,
+                        frame.fin,
+                        0,
+                    )?;
+                    frame.payload_len = frame.payload.len() as u64;
+
+                    if frame.fin {
+                        frame.rsv1 = false;
+                        return Ok(Some(frame));
+                    }
+                    self.fragments = Some(frame);
+                    self.fragment_count = 1;
+                }
+                Continuation => {
+                    let Some(mut message) = self.fragments.take() else {
+                        return Err(self.fail(
+                            CloseCode::ProtocolError,
+                            "Continuation frame received without a message to continue",
+                        ));
+                    };
+                    let payload = self.decode_fragment(
+                        message.op_code,
+                        message.rsv1,
+                        frame.payload,
+                        frame.fin,
+                        message.payload.len(),
+                    )?;
+                    message.payload.extend(payload);
+                    message.payload_len = message.payload.len() as u64;
+
+                    if frame.fin {
+                        message.fin = true;
+                        message.rsv1 = false;
+                        return Ok(Some(message));
+                    }
+                    self.fragments = Some(message);
+                    self.fragment_count += 1;
+                }
+                ConnectionClosed => {
+                    self.receive_close(&frame)?;
+                    return Ok(Some(frame));
+                }
+                Ping | Pong => return Ok(Some(frame)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Like `read_message`, but hands out data messages frame by frame instead of
+    /// buffering them, for messages too large to hold in memory. The first frame
+    /// is Text or Binary, the rest are Continuation frames, `fin` marks the last.
+    /// Payloads are inflated and text validated, as with `read_message`; the
+    /// message size and fragment limits don't apply, the frame size limit does.
+    /// Control frames are returned as they arrive, pings are answered.
+    pub fn read_fragment(&mut self) -> Result<Option<Frame>, Error> {
+        self.check_readable()?;
+        let Some(mut frame) = self.read_frame()? else {
+            return Ok(None);
+        };
+
+        let (op_code, compressed) = match (frame.op_code, self.fragments.as_ref()) {
+            (Text | Binary, None) => (frame.op_code, frame.rsv1),
+            (Continuation, Some(message)) => (message.op_code, message.rsv1),
+            (Text | Binary, Some(_)) => {
+                return Err(self.fail(
+                    CloseCode::ProtocolError,
+                    "New data frame received before the fragmented message was finished",
+                ))
+            }
+            (Continuation, None) => {
+                return Err(self.fail(
+                    CloseCode::ProtocolError,
+                    "Continuation frame received without a message to continue",
+                ))
+            }
+            (ConnectionClosed, _) => {
+                self.receive_close(&frame)?;
+                return Ok(Some(frame));
+            }
+            (Ping, _) => {
+                self.answer_ping(&frame);
+                return Ok(Some(frame));
+            }
+            (Pong, _) => return Ok(Some(frame)),
+        };
+
+        frame.payload = self.decode_fragment(op_code, compressed, frame.payload, frame.fin, 0)?;
+        frame.payload_len = frame.payload.len() as u64;
+        frame.rsv1 = false;
+
+        // Remember the message type and compression for its continuation frames
+        if frame.fin {
+            self.fragments = None;
+        } else if self.fragments.is_none() {
+            let mut message = Frame::new(op_code, Vec::new());
+            message.rsv1 = compressed;
+            self.fragments = Some(message);
+            self.fragment_count = 1;
+        } else {
+            self.fragment_count += 1;
+        }
+        self.streaming = !frame.fin;
+
+        Ok(Some(frame))
+    }
+
+    // Turns the payload of one fragment into message data: inflates it if the message
+    // is compressed, then validates text as it arrives, so invalid utf-8 fails the
+    // connection right away instead of once the whole message is buffered.
+    // `buffered` is how much of the message was decoded before this fragment.
+    fn decode_fragment(
+        &mut self,
+        op_code: OpCode,
+        compressed: bool,
+        payload: Vec<u8>,
+        fin: bool,
+        buffered: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let payload = match (compressed, self.deflate.as_mut()) {
+            (true, Some(deflate)) => {
+                let remaining = self.max_message_size.saturating_sub(buffered);
+                match deflate.decompress(&payload, fin, remaining) {
+                    Ok(payload) if payload.len() > remaining => {
+                        return Err(self.fail(CloseCode::MessageTooBig, "Message too large"))
+                    }
+                    Ok(payload) => payload,
+                    Err(_) => {
+                        return Err(self.fail(CloseCode::InvalidPayload, "Invalid compressed data"))
+                    }
+                }
+            }
+            _ => payload,
+        };
+
+        if matches!(op_code, Text) && (!self.utf8.feed(&payload) || (fin && !self.utf8.finish())) {
+            self.utf8 = Utf8Validator::new();
+            return Err(self.fail(CloseCode::InvalidPayload, "Text message is not valid UTF-8"));
+        }
+
+        Ok(payload)
+    }
+
+    // Queues a frame for the peer, clients mask every frame with a fresh random key
+    fn write_frame(&mut self, mut frame: Frame) {
+        if self.role == Role::Client {
+            frame.mask = true;
+            frame.mask_key = Some(rand::random());
+        }
+        self.output.extend(frame.to_bytes());
+    }
+}
+
+// Checks a close frame can be sent with this code and reason
+pub(crate) fn check_close(code: CloseCode, reason: &str) -> Result<(), Error> {
+    if !code.is_allowed() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Close code {} cannot be sent in a close frame", code),
+        ));
+    }
+    // Control frame payloads are limited to 125 bytes, 2 of them are the code
+    if reason.len() > 123 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Close reason is longer than 123 bytes",
+        ));
+    }
+    Ok(())
+}
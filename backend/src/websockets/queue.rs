@@ -0,0 +1,198 @@
+//Bounded queue of messages waiting to be written, so a slow client can't make the sender
+//wait on its socket. What happens when it's full is up to the OverflowPolicy. Messages are
+//only encoded once taken out: with permessage-deflate every compressed message depends on
+//the ones before it, so one that was compressed can't be dropped anymore.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::Message;
+
+// What to do with a message sent while the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Block,      // wait for the writer to make room, or be held if the sender can't wait
+    DropOldest, // discard the message that has been waiting the longest, e.g. a stale tick
+    DropNewest, // discard the message being sent
+    Disconnect, // fail the connection with 1008, the client can't keep up
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SendQueueConfig {
+    pub capacity: usize, // messages waiting to be written, control frames don't count
+    pub policy: OverflowPolicy,
+    // How long the close frame of a Disconnect gets to go out before the socket is shut down,
+    // the event loop gives every closing connection that long to flush
+    pub disconnect_timeout: Duration,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        SendQueueConfig {
+            capacity: 1024,
+            // A slow client loses stale messages instead of stalling whoever broadcasts
+            policy: OverflowPolicy::DropOldest,
+            disconnect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+// Snapshot of a queue, for monitoring slow clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueMetrics {
+    pub depth: usize,      // messages waiting right now, held ones included
+    pub bytes: usize,      // and the size of their payloads
+    pub peak_depth: usize, // most messages ever waiting at once
+    pub sent: u64,         // messages handed to the writer
+    pub dropped: u64,      // messages discarded by DropOldest or DropNewest
+}
+
+// Why a message wasn't queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Full,       // Block: try again once the writer made room, or hold it
+    Disconnect, // Disconnect, or Block with no room left to hold: fail the connection
+}
+
+#[derive(Debug)]
+pub struct SendQueue {
+    config: SendQueueConfig,
+    entries: VecDeque<(Message, bool)>, // and whether it's a control message
+    messages: usize,                    // entries that aren't control frames
+    held: VecDeque<Message>,            // Block: waiting for room, see hold
+    metrics: QueueMetrics,
+}
+
+impl SendQueue {
+    pub fn new(config: SendQueueConfig) -> Self {
+        SendQueue {
+            config,
+            entries: VecDeque::new(),
+            messages: 0,
+            held: VecDeque::new(),
+            metrics: QueueMetrics::default(),
+        }
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.config.policy
+    }
+
+    pub fn config(&self) -> &SendQueueConfig {
+        &self.config
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.messages >= self.config.capacity
+    }
+
+    pub fn has_held(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    // Queues a data message, applying the policy if the queue is full
+    pub fn push(&mut self, message: Message) -> Result<(), Overflow> {
+        if self.is_full() {
+            match self.config.policy {
+                OverflowPolicy::Block => return Err(Overflow::Full),
+                OverflowPolicy::Disconnect => return Err(Overflow::Disconnect),
+                OverflowPolicy::DropNewest => {
+                    self.metrics.dropped += 1;
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    // Control frames stay, dropping a pong or close would break the protocol
+                    if let Some(index) = self.entries.iter().position(|(_, control)| !control) {
+                        let (dropped, _) = self.entries.remove(index).unwrap();
+                        self.metrics.bytes -= payload_len(&dropped);
+                        self.messages -= 1;
+                        self.metrics.dropped += 1;
+                    }
+                }
+            }
+        }
+
+        self.messages += 1;
+        self.push_entry(message, false);
+        Ok(())
+    }
+
+    // Block policy, for a sender that can't wait for the writer: keeps the message until the
+    // queue has room, after the ones held before it. Only `capacity` of them are held, a
+    // client that still doesn't read is disconnected as with the Disconnect policy.
+    pub fn hold(&mut self, message: Message) -> Result<(), Overflow> {
+        if self.held.len() >= self.config.capacity {
+            return Err(Overflow::Disconnect);
+        }
+        self.metrics.bytes += payload_len(&message);
+        self.held.push_back(message);
+        self.update_depth();
+        Ok(())
+    }
+
+    // Queues a close (or ping) that must go out after the messages already queued, the
+    // capacity doesn't apply to it
+    pub fn push_control(&mut self, message: Message) {
+        self.push_entry(message, true);
+    }
+
+    fn push_entry(&mut self, message: Message, control: bool) {
+        self.metrics.bytes += payload_len(&message);
+        self.entries.push_back((message, control));
+        self.update_depth();
+    }
+
+    fn update_depth(&mut self) {
+        self.metrics.depth = self.messages + self.held.len();
+        self.metrics.peak_depth = self.metrics.peak_depth.max(self.metrics.depth);
+    }
+
+    // Takes the next message for the writer
+    pub fn pop(&mut self) -> Option<Message> {
+        let (message, control) = self.entries.pop_front()?;
+        if !control {
+            self.messages -= 1;
+            self.metrics.sent += 1;
+            // Held messages only wait while the queue is full, the first one takes this place
+            if let Some(held) = self.held.pop_front() {
+                self.messages += 1;
+                self.entries.push_back((held, false));
+            }
+        }
+        self.metrics.bytes -= payload_len(&message);
+        self.update_depth();
+        Some(message)
+    }
+
+    // Drops every data message still waiting, when the connection is failed
+    pub fn clear(&mut self) {
+        self.metrics.dropped += (self.messages + self.held.len()) as u64;
+        self.entries.retain(|(_, control)| *control);
+        self.messages = 0;
+        self.held.clear();
+        self.metrics.bytes = self
+            .entries
+            .iter()
+            .map(|(message, _)| payload_len(message))
+            .sum();
+        self.metrics.depth = 0;
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.metrics
+    }
+}
+
+fn payload_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(close_frame) => close_frame
+            .as_ref()
+            .map_or(0, |close_frame| 2 + close_frame.reason.len()),
+    }
+}
@@ -0,0 +1,188 @@
+//Registry of the live connections, so any part of the backend can reach them: each one is
+//registered with an id and what is known about its client, and messages are pushed through
+//its Outbox. Entries go away with their Registration, or as soon as a send to them fails.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::{Message, WebSocketWriter};
+
+pub type ConnectionId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub remote_addr: Option<SocketAddr>,
+    pub user: Option<String>, // set once the client authenticated, see Registry::set_user
+    pub subprotocol: Option<String>,
+}
+
+// Where the messages for a registered connection go, it must not wait on the socket
+pub trait Outbox: Send + Sync {
+    fn send_message(&self, message: Message) -> Result<(), Error>;
+}
+
+impl Outbox for WebSocketWriter {
+    fn send_message(&self, message: Message) -> Result<(), Error> {
+        WebSocketWriter::send_message(self, message)
+    }
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    outbox: Arc<dyn Outbox>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    connections: RwLock<HashMap<ConnectionId, Entry>>,
+    next_id: AtomicU64,
+}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Registry")
+            .field("connections", &self.connections())
+            .finish()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Arc<Registry> {
+        Arc::new(Registry::default())
+    }
+
+    /// Adds a connection, it stays registered until the returned
+    /// `Registration` is dropped or a message can't be sent to it anymore.
+    pub fn register(
+        self: &Arc<Self>,
+        remote_addr: Option<SocketAddr>,
+        subprotocol: Option<String>,
+        outbox: Arc<dyn Outbox>,
+    ) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = ConnectionInfo {
+            id,
+            remote_addr,
+            user: None,
+            subprotocol,
+        };
+        self.connections
+            .write()
+            .unwrap()
+            .insert(id, Entry { info, outbox });
+
+        Registration {
+            registry: Arc::clone(self),
+            id,
+        }
+    }
+
+    fn unregister(&self, id: ConnectionId) {
+        self.connections.write().unwrap().remove(&id);
+    }
+
+    // Records who is on the other end, false if the connection is gone
+    pub fn set_user(&self, id: ConnectionId, user: Option<String>) -> bool {
+        match self.connections.write().unwrap().get_mut(&id) {
+            Some(entry) => {
+                entry.info.user = user;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        let connections = self.connections.read().unwrap();
+        connections.get(&id).map(|entry| entry.info.clone())
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.read().unwrap();
+        connections
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends a message to one connection, `NotFound` if it isn't registered.
+    pub fn send_to(&self, id: ConnectionId, message: Message) -> Result<(), Error> {
+        let outbox = {
+            let connections = self.connections.read().unwrap();
+            let entry = connections.get(&id).ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, "No connection registered with this id")
+            })?;
+            Arc::clone(&entry.outbox)
+        };
+
+        let sent = outbox.send_message(message);
+        if sent.is_err() {
+            self.unregister(id);
+        }
+        sent
+    }
+
+    // Sends a message to every connection, returns how many it was queued for
+    pub fn broadcast(&self, message: Message) -> usize {
+        self.multicast(|_| true, message)
+    }
+
+    /// Sends a message to the connections `filter` selects, returns how many
+    /// it was queued for. Connections failing to take it are unregistered.
+    pub fn multicast(&self, filter: impl Fn(&ConnectionInfo) -> bool, message: Message) -> usize {
+        // Sent without the lock, an outbox may wait for room in its queue
+        let outboxes: Vec<(ConnectionId, Arc<dyn Outbox>)> = {
+            let connections = self.connections.read().unwrap();
+            connections
+                .values()
+                .filter(|entry| filter(&entry.info))
+                .map(|entry| (entry.info.id, Arc::clone(&entry.outbox)))
+                .collect()
+        };
+
+        let mut sent = 0;
+        for (id, outbox) in outboxes {
+            match outbox.send_message(message.clone()) {
+                Ok(()) => sent += 1,
+                Err(_) => self.unregister(id),
+            }
+        }
+        sent
+    }
+}
+
+/// Keeps a connection in its registry, dropping it unregisters the
+/// connection. Held by whatever serves the connection until it closes.
+#[derive(Debug)]
+pub struct Registration {
+    registry: Arc<Registry>,
+    id: ConnectionId,
+}
+
+impl Registration {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.unregister(self.id);
+    }
+}
@@ -1,15 +1,349 @@
+//HTTP/1.1 request parsing for the opening handshake
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{BufRead, Read};
+
+// Limits protecting the parser from clients sending endless headers
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    pub max_line_length: usize, // request line or a single header line, in bytes
+    pub max_headers: usize,
+    pub max_size: usize, // request line and all headers together, in bytes
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_line_length: 8192,
+            max_headers: 100,
+            max_size: 64 << 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Io(std::io::Error),
+    UnexpectedEof, // connection closed before the blank line ending the headers
+    LineTooLong,
+    TooManyHeaders,
+    TooLarge,        // request line and headers together exceed the size limit
+    InvalidEncoding, // request line or header isn't valid utf-8
+    InvalidRequestLine(String),
+    InvalidHeader(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Io(e) => write!(f, "Failed to read request: {}", e),
+            RequestError::UnexpectedEof => {
+                write!(f, "Connection closed in the middle of the request")
+            }
+            RequestError::LineTooLong => write!(f, "Request line or header is too long"),
+            RequestError::TooManyHeaders => write!(f, "Request has too many headers"),
+            RequestError::TooLarge => write!(f, "Request headers are too large"),
+            RequestError::InvalidEncoding => write!(f, "Request is not valid UTF-8"),
+            RequestError::InvalidRequestLine(line) => write!(f, "Invalid request line: {}", line),
+            RequestError::InvalidHeader(line) => write!(f, "Invalid header: {}", line),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+impl From<RequestError> for std::io::Error {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Io(e) => e,
+            RequestError::UnexpectedEof => {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e.to_string())
+            }
+            e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// Header names are case-insensitive and a header can appear more than once
+#[derive(Debug, Default, Clone)]
+pub struct Headers {
+    map: HashMap<String, Vec<String>>, // lowercase name -> values in the order received
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.map
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(value.to_owned());
+    }
+
+    // First value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).first().map(String::as_str)
+    }
+
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.map
+            .get(&name.to_ascii_lowercase())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // Comma separated list headers (Connection, Sec-WebSocket-Protocol, ...) split
+    // into their elements, across every occurrence of the header
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.map.contains_key(&name.to_ascii_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Request {
-    pub headers: Vec<String>,
+    pub method: String,
+    pub path: String, // target without the query string, e.g. /ws/prices
+    pub query: Vec<(String, String)>, // decoded query parameters in order
+    pub version: String, // e.g. HTTP/1.1
+    pub headers: Headers,
     pub raw: Vec<u8>,
 }
 
 impl Request {
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Request, RequestError> {
+        Request::parse_with_limits(reader, RequestLimits::default())
+    }
+
+    // Reads a request line and headers up to the blank line that ends them
+    pub fn parse_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: RequestLimits,
+    ) -> Result<Request, RequestError> {
+        let mut raw = Vec::new();
+
+        let request_line = read_line(reader, limits.max_line_length, &mut raw)?;
+        let mut request = parse_request_line(&request_line)?;
+        read_headers(reader, limits, &mut raw, &mut request.headers)?;
+
+        request.raw = raw;
+        Ok(request)
+    }
+
+    // Parses a request from the bytes received so far, for non-blocking sockets. Returns
+    // None until the blank line ending the headers has arrived, then the request and how
+    // many bytes it took up, whatever follows already belongs to the frames.
+    pub fn parse_partial(
+        received: &[u8],
+        limits: RequestLimits,
+    ) -> Result<Option<(Request, usize)>, RequestError> {
+        let mut rest = received;
+        match Request::parse_with_limits(&mut rest, limits) {
+            Ok(request) => Ok(Some((request, received.len() - rest.len()))),
+            // The parser only sees whole lines, a line that never ends is cut off here
+            Err(RequestError::UnexpectedEof)
+                if received.len() <= limits.max_size + limits.max_line_length =>
+            {
+                Ok(None)
+            }
+            Err(RequestError::UnexpectedEof) => Err(RequestError::TooLarge),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn get_header(&self, name: &str) -> Option<&str> {
-        self.headers
+        self.headers.get(name)
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
             .iter()
-            .find(|h| h.to_lowercase().starts_with(&name.to_lowercase()))
-            .map(|h| h.split(':').nth(1))
-            .flatten()
-            .map(|s| s.trim())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads the status line and headers of the server's answer to an upgrade
+/// request, bounded by the same limits as requests
+pub fn parse_response<R: BufRead>(
+    reader: &mut R,
+    limits: RequestLimits,
+) -> Result<(String, Headers), RequestError> {
+    let mut raw = Vec::new();
+    let status_line = read_line(reader, limits.max_line_length, &mut raw)?;
+    let mut headers = Headers::new();
+    read_headers(reader, limits, &mut raw, &mut headers)?;
+    Ok((status_line, headers))
+}
+
+// Reads header lines up to the blank line that ends them
+fn read_headers<R: BufRead>(
+    reader: &mut R,
+    limits: RequestLimits,
+    raw: &mut Vec<u8>,
+    headers: &mut Headers,
+) -> Result<(), RequestError> {
+    loop {
+        let line = read_line(reader, limits.max_line_length, raw)?;
+        // A single line is bounded by max_line_length, so this overshoots by one line at most
+        if raw.len() > limits.max_size {
+            return Err(RequestError::TooLarge);
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        if headers.len() == limits.max_headers {
+            return Err(RequestError::TooManyHeaders);
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| RequestError::InvalidHeader(line.clone()))?;
+        // No whitespace allowed in or around the name, this also rejects obsolete line folding
+        if !is_token(name) {
+            return Err(RequestError::InvalidHeader(line));
+        }
+        headers.insert(name, value.trim());
+    }
+}
+
+// Reads one line without its line ending (CRLF, or a bare LF from lenient clients)
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    max_length: usize,
+    raw: &mut Vec<u8>,
+) -> Result<String, RequestError> {
+    let mut line = Vec::new();
+    // +2 leaves room for the \r\n after a line of exactly max_length bytes
+    reader
+        .take(max_length as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+    raw.extend(&line);
+
+    if !line.ends_with(b"\n") {
+        return Err(if line.len() > max_length {
+            RequestError::LineTooLong
+        } else {
+            RequestError::UnexpectedEof
+        });
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
     }
+    if line.len() > max_length {
+        return Err(RequestError::LineTooLong);
+    }
+
+    String::from_utf8(line).map_err(|_| RequestError::InvalidEncoding)
+}
+
+// "GET /ws/prices?symbol=AAPL HTTP/1.1" -> method, path, query and version, headers are added after
+fn parse_request_line(line: &str) -> Result<Request, RequestError> {
+    let invalid = || RequestError::InvalidRequestLine(line.to_owned());
+
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+
+    if !is_token(method) || !target.starts_with('/') {
+        return Err(invalid());
+    }
+    let digits = version.strip_prefix("HTTP/").ok_or_else(invalid)?;
+    if !matches!(digits.as_bytes(), [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, parse_query(query).ok_or_else(invalid)?),
+        None => (target, Vec::new()),
+    };
+
+    Ok(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        version: version.to_owned(),
+        headers: Headers::new(),
+        raw: Vec::new(),
+    })
+}
+
+fn parse_query(query: &str) -> Option<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+// Decodes %XX escapes and + as space, None if an escape or the result is invalid
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // from_str_radix alone would take a sign, as in %+1
+                let hex = bytes.get(i + 1..i + 3)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+// Methods and header names are tokens (RFC 7230 section 3.2.6)
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
 }
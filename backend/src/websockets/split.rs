@@ -0,0 +1,374 @@
+//Reader and writer halves of a WebSocket, so one thread can push messages while another
+//is blocked reading. Both share the protocol (and with it the close state) behind a lock
+//that is never held while waiting on the socket. Sent messages go through a bounded queue
+//drained by a thread of their own, so a slow client never blocks the sender on its socket.
+//A client disconnected for not keeping up gets a bounded time for the close frame, then the
+//socket is shut down, which also ends a write blocked on it.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::connection::{SetReadTimeout, READ_CHUNK_SIZE};
+use super::protocol::check_close;
+use super::{CloseCode, CloseFrame, ConnectionState, Heartbeat, Message, Protocol};
+use super::{Overflow, OverflowPolicy, QueueMetrics, SendQueue};
+
+// Streams that can be opened twice, one handle for each half, and shut down from any handle
+pub trait TryClone: Sized {
+    fn try_clone(&self) -> Result<Self, Error>;
+
+    // Ends the connection for every handle, blocked reads and writes return
+    fn shutdown(&self) -> Result<(), Error>;
+}
+
+impl TryClone for TcpStream {
+    fn try_clone(&self) -> Result<Self, Error> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// What the halves and the writer thread share
+#[derive(Debug)]
+struct Shared {
+    protocol: Protocol,
+    queue: SendQueue,
+    closing: bool,            // a close is queued, nothing can be sent after it
+    error: Option<ErrorKind>, // writing to the socket failed
+    dropped: bool,            // every half is gone, the writer stops once it sent everything
+    finished: bool,           // the writer thread stopped
+}
+
+impl Shared {
+    fn state(&self) -> ConnectionState {
+        match self.protocol.state() {
+            ConnectionState::Connecting | ConnectionState::Connected if self.closing => {
+                ConnectionState::Closing
+            }
+            state => state,
+        }
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        if let Some(kind) = self.error {
+            return Err(Error::new(kind, "Writing to the connection failed"));
+        }
+        if matches!(
+            self.state(),
+            ConnectionState::Closing | ConnectionState::Closed
+        ) {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                "Cannot send after the close frame was sent",
+            ));
+        }
+        Ok(())
+    }
+
+    // Encoded bytes to write next: the frames the protocol queued itself (pongs, pings,
+    // close echoes) go first, then the next message of the queue
+    fn next_output(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if self.protocol.has_output() {
+                return Some(self.protocol.take_output());
+            }
+            let message = self.queue.pop()?;
+            // Fails once the connection is closed, what is still queued is dropped then
+            let _ = self.protocol.send_message(message);
+        }
+    }
+}
+
+struct Channel {
+    shared: Mutex<Shared>,
+    changed: Condvar, // something was queued for the writer, or it made room in the queue
+    shutdown: Mutex<Option<Box<dyn FnOnce() + Send>>>, // TryClone::shutdown of a third handle
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("shared", &self.shared)
+            .finish()
+    }
+}
+
+impl Channel {
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+
+    // Waits up to `timeout` for the writer thread to send the close frame of a failed
+    // connection, then shuts the socket down whether it got out or not
+    fn shut_down_after(&self, timeout: Duration) {
+        let shared = self.lock();
+        let (shared, _) = self
+            .changed
+            .wait_timeout_while(shared, timeout, |shared| !shared.finished)
+            .unwrap();
+        drop(shared);
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            shutdown();
+        }
+    }
+}
+
+// Held by every half, the writer thread stops once the last one is dropped
+#[derive(Debug)]
+struct Handle(Arc<Channel>);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.0.lock().dropped = true;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Receiving half from `WebSocket::split`. Pings are answered and closes
+/// echoed through the writer thread, and the heartbeat, if enabled before
+/// splitting, keeps running while `read_message` waits.
+#[derive(Debug)]
+pub struct WebSocketReader<S = TcpStream> {
+    stream: S,
+    handle: Arc<Handle>,
+    heartbeat: Option<(Heartbeat, SetReadTimeout<S>)>,
+}
+
+/// Sending half from `WebSocket::split`. Cloning it gives another handle to
+/// the same connection, so any number of threads can push messages. Text and
+/// binary messages wait in a bounded queue, see `SendQueueConfig`.
+#[derive(Debug, Clone)]
+pub struct WebSocketWriter {
+    handle: Arc<Handle>,
+}
+
+// Builds the halves from a stream and a handle to it for the writer thread
+pub(super) fn halves<S: Read + Write + TryClone + Send + 'static>(
+    reader: S,
+    writer: S,
+    protocol: Protocol,
+    queue: SendQueue,
+    heartbeat: Option<(Heartbeat, SetReadTimeout<S>)>,
+) -> Result<(WebSocketReader<S>, WebSocketWriter), Error> {
+    let closer = reader.try_clone()?;
+    let channel = Arc::new(Channel {
+        shared: Mutex::new(Shared {
+            protocol,
+            queue,
+            closing: false,
+            error: None,
+            dropped: false,
+            finished: false,
+        }),
+        changed: Condvar::new(),
+        shutdown: Mutex::new(Some(Box::new(move || {
+            let _ = closer.shutdown();
+        }))),
+    });
+    let handle = Arc::new(Handle(Arc::clone(&channel)));
+
+    thread::spawn(move || {
+        write_queued(writer, &channel);
+        channel.lock().finished = true;
+        channel.changed.notify_all();
+    });
+    Ok((
+        WebSocketReader {
+            stream: reader,
+            handle: Arc::clone(&handle),
+            heartbeat,
+        },
+        WebSocketWriter { handle },
+    ))
+}
+
+// The writer thread: sends what is queued until the connection is closed or dropped
+fn write_queued<S: Write>(mut stream: S, channel: &Channel) {
+    let mut shared = channel.lock();
+    loop {
+        let Some(output) = shared.next_output() else {
+            if shared.dropped || shared.protocol.state() == ConnectionState::Closed {
+                return;
+            }
+            shared = channel.changed.wait(shared).unwrap();
+            continue;
+        };
+
+        drop(shared);
+        let written = stream.write_all(&output);
+        shared = channel.lock();
+
+        // Wakes the senders waiting for room, or for the error
+        channel.changed.notify_all();
+        if let Err(e) = written {
+            shared.error = Some(e.kind());
+            shared.protocol.abort();
+            shared.queue.clear();
+            return;
+        }
+    }
+}
+
+impl<S: Read + Write> WebSocketReader<S> {
+    pub fn state(&self) -> ConnectionState {
+        self.handle.0.lock().state()
+    }
+
+    /// Reads the next complete message, like `WebSocket::read_message`.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        let mut read = 0;
+
+        loop {
+            let message = {
+                let channel = &self.handle.0;
+                let mut shared = channel.lock();
+                shared.protocol.receive(&buffer[..read]);
+                let message = shared.protocol.read_message();
+                // Pongs, close echoes and the close frame of a failure
+                if shared.protocol.has_output() {
+                    channel.changed.notify_all();
+                }
+                message?
+            };
+
+            if let Some(message) = message {
+                if let (Message::Pong(payload), Some((heartbeat, _))) =
+                    (&message, self.heartbeat.as_mut())
+                {
+                    heartbeat.on_pong(payload, Instant::now());
+                }
+                return Ok(message);
+            }
+            self.keep_alive()?;
+
+            read = match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed by the peer",
+                    ))
+                }
+                Ok(read) => read,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => 0,
+                // The read timeout set by keep_alive expired
+                Err(ref e)
+                    if self.heartbeat.is_some()
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    0
+                }
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    // Same as WebSocket::keep_alive, with the ping going through the writer thread
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        let Some((heartbeat, set_read_timeout)) = self.heartbeat.as_mut() else {
+            return Ok(());
+        };
+
+        let channel = &self.handle.0;
+        let mut shared = channel.lock();
+        let timeout = heartbeat.drive(&mut shared.protocol, Instant::now());
+        // The peer didn't echo our close frame, there is nothing left to send it
+        let aborted = timeout.is_err() && !shared.protocol.has_output();
+        drop(shared);
+        channel.changed.notify_all();
+        if aborted {
+            if let Some(shutdown) = channel.shutdown.lock().unwrap().take() {
+                shutdown();
+            }
+        }
+        set_read_timeout(&self.stream, Some(timeout?))
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.heartbeat.as_ref()?.0.rtt()
+    }
+}
+
+impl WebSocketWriter {
+    pub fn state(&self) -> ConnectionState {
+        self.handle.0.lock().state()
+    }
+
+    // Sends a text message
+    pub fn send(&self, payload: Vec<u8>) -> Result<(), Error> {
+        let text =
+            String::from_utf8(payload).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.send_message(Message::Text(text))
+    }
+
+    /// Queues a message for the writer thread. When the queue is full, the
+    /// policy decides: `Block` waits for room, `DropOldest` and `DropNewest`
+    /// discard a message and return `Ok`, and `Disconnect` fails the connection
+    /// with 1008 and returns a `ConnectionAborted` error. The socket is shut down
+    /// once the close frame went out, or after `disconnect_timeout` if the
+    /// client doesn't read it. Pings and pongs skip
+    /// the queue, and a close goes out after the messages already queued.
+    pub fn send_message(&self, message: Message) -> Result<(), Error> {
+        let channel = &self.handle.0;
+        let mut shared = channel.lock();
+        shared.check_open()?;
+
+        match message {
+            Message::Ping(_) | Message::Pong(_) => shared.protocol.send_message(message)?,
+            Message::Close(close_frame) => {
+                if let Some(close_frame) = &close_frame {
+                    check_close(close_frame.code, &close_frame.reason)?;
+                }
+                shared.closing = true;
+                shared.queue.push_control(Message::Close(close_frame));
+            }
+            Message::Text(_) | Message::Binary(_) => {
+                while shared.queue.policy() == OverflowPolicy::Block && shared.queue.is_full() {
+                    shared = channel.changed.wait(shared).unwrap();
+                    shared.check_open()?;
+                }
+
+                match shared.queue.push(message) {
+                    Ok(()) => {}
+                    Err(Overflow::Full) => {
+                        return Err(Error::new(ErrorKind::WouldBlock, "Send queue is full"))
+                    }
+                    Err(Overflow::Disconnect) => {
+                        shared.queue.clear();
+                        shared
+                            .protocol
+                            .fail(CloseCode::PolicyViolation, "Send queue full");
+                        channel.changed.notify_all();
+                        let timeout = shared.queue.config().disconnect_timeout;
+                        let channel = Arc::clone(channel);
+                        thread::spawn(move || channel.shut_down_after(timeout));
+                        return Err(Error::new(
+                            ErrorKind::ConnectionAborted,
+                            "Send queue full, the client was disconnected (close code 1008)",
+                        ));
+                    }
+                }
+            }
+        }
+
+        channel.changed.notify_all();
+        Ok(())
+    }
+
+    /// Queues a close frame after the messages already queued, the reader half
+    /// picks up the peer's echo.
+    pub fn close(&self, code: CloseCode, reason: &str) -> Result<(), Error> {
+        self.send_message(Message::Close(Some(CloseFrame::new(code, reason))))
+    }
+
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.handle.0.lock().queue.metrics()
+    }
+}
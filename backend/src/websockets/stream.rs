@@ -0,0 +1,180 @@
+//Streaming of large messages: reading a message as it arrives and writing one in fragments,
+//without ever holding the whole payload in memory
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use super::{CloseCode, OpCode, Utf8Validator, WebSocket};
+
+// Payload size of the frames MessageWriter sends, also the most it buffers
+pub const FRAGMENT_SIZE: usize = 64 << 10;
+
+/// Reads the payload of one Text or Binary message as its frames arrive, from
+/// `WebSocket::message_reader`. Pings received in the middle are answered, and
+/// `read` returns 0 once the last frame was consumed. Read it to the end before
+/// reading the next message from the socket: dropping it before that fails the
+/// connection with 1011, the rest of the message can't be delivered anymore.
+pub struct MessageReader<'a, S: Read + Write> {
+    ws: &'a mut WebSocket<S>,
+    op_code: OpCode, // Text or Binary
+    chunk: Vec<u8>,  // payload of the frame being read
+    position: usize, // bytes of chunk already handed out
+    fin: bool,       // chunk is the last frame of the message
+}
+
+impl<'a, S: Read + Write> MessageReader<'a, S> {
+    // Starts from the first frame of the message
+    pub(super) fn new(
+        ws: &'a mut WebSocket<S>,
+        op_code: OpCode,
+        payload: Vec<u8>,
+        fin: bool,
+    ) -> Self {
+        MessageReader {
+            ws,
+            op_code,
+            chunk: payload,
+            position: 0,
+            fin,
+        }
+    }
+
+    pub fn op_code(&self) -> OpCode {
+        self.op_code
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self.op_code, OpCode::Text)
+    }
+}
+
+impl<S: Read + Write> Read for MessageReader<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        // Frames can be empty, keep going until there is something to hand out
+        while self.position == self.chunk.len() {
+            if self.fin {
+                return Ok(0);
+            }
+            let frame = self.ws.read_fragment()?;
+            self.chunk = frame.payload;
+            self.position = 0;
+            self.fin = frame.fin;
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Writes one Text or Binary message as a series of frames of `FRAGMENT_SIZE`
+/// bytes, from `WebSocket::text_writer` or `WebSocket::binary_writer`. Call
+/// `finish` to send the last frame; dropping the writer does it too but
+/// ignores errors. Fragments are sent uncompressed. Text that turns out not to
+/// be valid UTF-8 after fragments of it were sent fails the connection.
+pub struct MessageWriter<'a, S: Read + Write> {
+    ws: &'a mut WebSocket<S>,
+    op_code: OpCode, // of the next frame, Continuation once the first one is sent
+    buffer: Vec<u8>,
+    utf8: Option<Utf8Validator>, // set for text messages
+    finished: bool,
+}
+
+impl<'a, S: Read + Write> MessageWriter<'a, S> {
+    pub(super) fn new(ws: &'a mut WebSocket<S>, op_code: OpCode) -> Self {
+        MessageWriter {
+            ws,
+            op_code,
+            buffer: Vec::with_capacity(FRAGMENT_SIZE),
+            utf8: matches!(op_code, OpCode::Text).then(Utf8Validator::new),
+            finished: false,
+        }
+    }
+
+    /// Sends what is left in the buffer as the last frame of the message.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.finish_message()
+    }
+
+    fn finish_message(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        // The text may end in the middle of a code point, the last frame must not be sent then
+        if self.utf8.as_mut().is_some_and(|utf8| !utf8.finish()) {
+            return Err(self.abort("Text message ends in the middle of a UTF-8 code point"));
+        }
+        self.send_buffer(true)
+    }
+
+    // Gives up on a text message that isn't valid UTF-8. Once fragments of it went out
+    // the message can't be ended, nothing else may be sent on the connection then, so
+    // it's failed with 1011: the invalid text came from this side.
+    fn abort(&mut self, reason: &str) -> Error {
+        self.finished = true;
+        if matches!(self.op_code, OpCode::Continuation) {
+            return self.ws.fail(CloseCode::InternalError, reason);
+        }
+        Error::new(ErrorKind::InvalidInput, reason)
+    }
+
+    fn send_buffer(&mut self, fin: bool) -> Result<(), Error> {
+        let payload = std::mem::replace(&mut self.buffer, Vec::with_capacity(FRAGMENT_SIZE));
+        self.ws.send_fragment(self.op_code, payload, fin)?;
+        self.op_code = OpCode::Continuation;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Write for MessageWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.finished {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The message was already finished",
+            ));
+        }
+
+        let len = buf.len().min(FRAGMENT_SIZE - self.buffer.len());
+        if self
+            .utf8
+            .as_mut()
+            .is_some_and(|utf8| !utf8.feed(&buf[..len]))
+        {
+            return Err(self.abort("Text message is not valid UTF-8"));
+        }
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == FRAGMENT_SIZE {
+            self.send_buffer(false)?;
+        }
+        Ok(len)
+    }
+
+    // Sends what is buffered so far as a frame of its own
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.finished && !self.buffer.is_empty() {
+            self.send_buffer(false)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Drop for MessageReader<'_, S> {
+    fn drop(&mut self) {
+        if !self.fin {
+            self.ws.fail(
+                CloseCode::InternalError,
+                "Streamed message was not read to the end",
+            );
+        }
+    }
+}
+
+impl<S: Read + Write> Drop for MessageWriter<'_, S> {
+    fn drop(&mut self) {
+        let _ = self.finish_message();
+    }
+}
@@ -0,0 +1,2251 @@
+use std::io::{Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use crate::websockets::AsyncWebSocket;
+use crate::websockets::{
+    accept_tls, check_origin, validate_request, CloseCode, CloseFrame, ConnectionState,
+    DeflateConfig, DeflateParams, Frame, HandshakeError, Heartbeat, HeartbeatAction,
+    HeartbeatConfig, Message, OpCode, OriginPolicy, Outbox, Overflow, OverflowPolicy,
+    PerMessageDeflate, Protocol, QueueMetrics, Registry, Request, RequestError, RequestLimits,
+    Role, Router, SendQueue, SendQueueConfig, Session, TlsConfig, Utf8Validator, WebSocket,
+    WebSocketConfig, WebSocketHandler, FRAGMENT_SIZE,
+};
+
+// Returns a server side WebSocket and the raw client socket connected to it
+fn socket_pair() -> (WebSocket, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    (WebSocket::new(server), client)
+}
+
+// Runs the opening handshake with the given extra request headers,
+// returns the server side WebSocket, the client socket and the server's response
+fn handshake(headers: &str, config: &WebSocketConfig) -> (WebSocket, TcpStream, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let request = format!(
+        "GET / HTTP/1.1\r\n\
+        Host: 127.0.0.1\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        {}\r\n",
+        headers
+    );
+    client.write_all(request.as_bytes()).unwrap();
+
+    let ws = WebSocket::accept_with_config(server, config).unwrap();
+
+    // Read the response up to the blank line ending the headers
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        client.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+
+    (ws, client, String::from_utf8(response).unwrap())
+}
+
+// Sends a raw upgrade request, returns the result of accept and everything the server answered
+fn raw_handshake(request: &str, config: &WebSocketConfig) -> (std::io::Result<WebSocket>, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    client.write_all(request.as_bytes()).unwrap();
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let result = WebSocket::accept_with_config(server, config);
+
+    let mut response = String::new();
+    if result.is_err() {
+        client.read_to_string(&mut response).unwrap();
+    }
+    (result, response)
+}
+
+// Builds a masked frame the way a browser would send it
+fn client_frame(fin: bool, op_code: OpCode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Frame::new(op_code, payload.to_vec());
+    frame.fin = fin;
+    frame.mask = true;
+    frame.mask_key = Some([0x37, 0xfa, 0x21, 0x3d]);
+    frame.to_bytes()
+}
+
+// Reads a short unmasked frame sent by the server, returns (first byte, payload)
+fn read_server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).unwrap();
+    assert_eq!(header[1] & 0x80, 0, "server frames must not be masked");
+
+    let mut payload = vec![0; (header[1] & 0x7F) as usize];
+    client.read_exact(&mut payload).unwrap();
+    (header[0], payload)
+}
+
+#[test]
+fn test_read_unfragmented_message() {
+    let (mut ws, mut client) = socket_pair();
+
+    client
+        .write_all(&client_frame(true, OpCode::Text, b"Hello"))
+        .unwrap();
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message, Message::text("Hello"));
+}
+
+#[test]
+fn test_reassemble_fragmented_message() {
+    let (mut ws, mut client) = socket_pair();
+
+    client
+        .write_all(&client_frame(false, OpCode::Binary, b"Hel"))
+        .unwrap();
+    client
+        .write_all(&client_frame(false, OpCode::Continuation, b"lo "))
+        .unwrap();
+    client
+        .write_all(&client_frame(true, OpCode::Continuation, b"world"))
+        .unwrap();
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message, Message::binary(b"Hello world".to_vec()));
+}
+
+#[test]
+fn test_control_frame_between_fragments() {
+    let (mut ws, mut client) = socket_pair();
+
+    client
+        .write_all(&client_frame(false, OpCode::Text, b"Hel"))
+        .unwrap();
+    client
+        .write_all(&client_frame(true, OpCode::Ping, b"ping"))
+        .unwrap();
+    client
+        .write_all(&client_frame(true, OpCode::Continuation, b"lo"))
+        .unwrap();
+
+    let ping = ws.read_message().unwrap();
+    assert_eq!(ping, Message::Ping(b"ping".to_vec()));
+    // Answered before read_message returned
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x8A);
+    assert_eq!(payload, b"ping");
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message, Message::text("Hello"));
+}
+
+#[test]
+fn test_continuation_without_start_is_rejected() {
+    let (mut ws, mut client) = socket_pair();
+
+    client
+        .write_all(&client_frame(true, OpCode::Continuation, b"lo"))
+        .unwrap();
+
+    assert!(ws.read_message().is_err());
+}
+
+#[test]
+fn test_close_code_conversion() {
+    assert_eq!(CloseCode::from(1000), CloseCode::Normal);
+    assert_eq!(CloseCode::from(1009), CloseCode::MessageTooBig);
+    assert_eq!(CloseCode::from(4000), CloseCode::Other(4000));
+    assert_eq!(u16::from(CloseCode::InvalidPayload), 1007);
+
+    assert!(CloseCode::Normal.is_allowed());
+    assert!(CloseCode::Other(3000).is_allowed());
+    assert!(!CloseCode::NoStatusReceived.is_allowed());
+    assert!(!CloseCode::Abnormal.is_allowed());
+    assert!(!CloseCode::Other(2000).is_allowed());
+}
+
+#[test]
+fn test_parse_close_payload() {
+    assert_eq!(CloseFrame::from_payload(&[]), Ok(None));
+    assert_eq!(
+        CloseFrame::from_payload(&[0x03, 0xE9, b'b', b'y', b'e']),
+        Ok(Some(CloseFrame::new(CloseCode::GoingAway, "bye")))
+    );
+    assert_eq!(
+        CloseFrame::from_payload(&[0x03]),
+        Err(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        CloseFrame::from_payload(&[0x03, 0xED]), // 1005
+        Err(CloseCode::ProtocolError)
+    );
+    assert_eq!(
+        CloseFrame::from_payload(&[0x03, 0xE8, 0xFF]),
+        Err(CloseCode::InvalidPayload)
+    );
+}
+
+#[test]
+fn test_client_initiated_close_is_echoed() {
+    let (mut ws, mut client) = socket_pair();
+
+    let close_frame = CloseFrame::new(CloseCode::GoingAway, "bye");
+    client
+        .write_all(&client_frame(
+            true,
+            OpCode::ConnectionClosed,
+            &close_frame.to_payload(),
+        ))
+        .unwrap();
+
+    let message = ws.read_message().unwrap();
+    assert!(message.is_close());
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(payload, vec![0x03, 0xE9]);
+}
+
+#[test]
+fn test_server_initiated_close() {
+    let (mut ws, mut client) = socket_pair();
+
+    ws.close(CloseCode::Normal, "done").unwrap();
+    assert_eq!(ws.state(), ConnectionState::Closing);
+    assert!(ws.send(b"too late".to_vec()).is_err());
+
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(
+        CloseFrame::from_payload(&payload),
+        Ok(Some(CloseFrame::new(CloseCode::Normal, "done")))
+    );
+
+    client
+        .write_all(&client_frame(true, OpCode::ConnectionClosed, &payload))
+        .unwrap();
+    ws.read_message().unwrap();
+    assert_eq!(ws.state(), ConnectionState::Closed);
+}
+
+#[test]
+fn test_invalid_close_code_fails_connection() {
+    let (mut ws, mut client) = socket_pair();
+
+    client
+        .write_all(&client_frame(true, OpCode::ConnectionClosed, &[0x03, 0xED]))
+        .unwrap();
+
+    assert!(ws.read_message().is_err());
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    let (_, payload) = read_server_frame(&mut client);
+    assert_eq!(payload[..2], [0x03, 0xEA]); // 1002
+}
+
+// Sends raw bytes from the client and expects the server to fail the connection with the given code
+fn assert_fails_with(bytes: &[u8], code: CloseCode) {
+    let (mut ws, mut client) = socket_pair();
+
+    client.write_all(bytes).unwrap();
+
+    assert!(ws.read_message().is_err());
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(
+        CloseFrame::from_payload(&payload).unwrap().unwrap().code,
+        code
+    );
+}
+
+#[test]
+fn test_unmasked_client_frame_is_rejected() {
+    let frame = Frame::new(OpCode::Text, b"Hello".to_vec());
+    assert_fails_with(&frame.to_bytes(), CloseCode::ProtocolError);
+}
+
+#[test]
+fn test_reserved_bits_are_rejected() {
+    let mut bytes = client_frame(true, OpCode::Text, b"Hello");
+    bytes[0] |= 0x40; // RSV1
+    assert_fails_with(&bytes, CloseCode::ProtocolError);
+}
+
+#[test]
+fn test_reserved_opcode_is_rejected() {
+    let mut bytes = client_frame(true, OpCode::Text, b"Hello");
+    bytes[0] = 0x83;
+    assert_fails_with(&bytes, CloseCode::ProtocolError);
+}
+
+#[test]
+fn test_invalid_control_frames_are_rejected() {
+    assert_fails_with(
+        &client_frame(false, OpCode::Ping, b"ping"),
+        CloseCode::ProtocolError,
+    );
+    assert_fails_with(
+        &client_frame(true, OpCode::Ping, &[0; 126]),
+        CloseCode::ProtocolError,
+    );
+}
+
+#[test]
+fn test_64_bit_length_with_top_bit_set_is_rejected() {
+    let mut bytes = vec![0x82, 0xFF];
+    bytes.extend((1u64 << 63).to_be_bytes());
+    bytes.extend([0x37, 0xfa, 0x21, 0x3d]);
+    assert_fails_with(&bytes, CloseCode::ProtocolError);
+}
+
+#[test]
+fn test_interleaved_data_frame_is_rejected() {
+    let mut bytes = client_frame(false, OpCode::Text, b"Hel");
+    bytes.extend(client_frame(true, OpCode::Text, b"lo"));
+    assert_fails_with(&bytes, CloseCode::ProtocolError);
+}
+
+#[test]
+fn test_utf8_validator_across_chunks() {
+    let euro = "€".as_bytes(); // 3 bytes: E2 82 AC
+
+    let mut validator = Utf8Validator::new();
+    assert!(validator.feed(&[b'a', euro[0]]));
+    assert!(validator.feed(&euro[1..2]));
+    assert!(validator.feed(&euro[2..]));
+    assert!(validator.finish());
+
+    // Ends in the middle of a code point
+    assert!(validator.feed(&euro[..2]));
+    assert!(!validator.finish());
+
+    // Bytes that can never start or continue a code point
+    let mut validator = Utf8Validator::new();
+    assert!(!validator.feed(&[0xFF]));
+    let mut validator = Utf8Validator::new();
+    assert!(validator.feed(&euro[..1]));
+    assert!(!validator.feed(b"a"));
+}
+
+#[test]
+fn test_text_split_mid_code_point_is_reassembled() {
+    let (mut ws, mut client) = socket_pair();
+    let text = "Price: 12€".as_bytes();
+    let split = text.len() - 2;
+
+    client
+        .write_all(&client_frame(false, OpCode::Text, &text[..split]))
+        .unwrap();
+    client
+        .write_all(&client_frame(true, OpCode::Continuation, &text[split..]))
+        .unwrap();
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message.into_text().unwrap(), "Price: 12€");
+}
+
+#[test]
+fn test_invalid_utf8_fails_with_1007() {
+    assert_fails_with(
+        &client_frame(true, OpCode::Text, &[b'a', 0xFF]),
+        CloseCode::InvalidPayload,
+    );
+
+    // Fails on the first fragment without waiting for the rest of the message
+    assert_fails_with(
+        &client_frame(false, OpCode::Text, &[0xC0, 0x80]),
+        CloseCode::InvalidPayload,
+    );
+
+    // Message ends in the middle of a code point
+    let euro = "€".as_bytes();
+    let mut bytes = client_frame(false, OpCode::Text, &euro[..1]);
+    bytes.extend(client_frame(true, OpCode::Continuation, &euro[1..2]));
+    assert_fails_with(&bytes, CloseCode::InvalidPayload);
+}
+
+#[test]
+fn test_negotiate_permessage_deflate() {
+    let config = DeflateConfig::default();
+
+    let params = config
+        .negotiate(&["permessage-deflate; client_max_window_bits"])
+        .unwrap();
+    assert_eq!(
+        params.to_header(),
+        "permessage-deflate; client_max_window_bits=15"
+    );
+
+    let params = config
+        .negotiate(&["permessage-deflate; server_no_context_takeover; server_max_window_bits=10"])
+        .unwrap();
+    assert_eq!(
+        params,
+        DeflateParams {
+            server_no_context_takeover: true,
+            client_no_context_takeover: false,
+            server_max_window_bits: 10,
+            client_max_window_bits: None,
+        }
+    );
+    assert_eq!(
+        params.to_header(),
+        "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+    );
+
+    // The first offer has an unknown parameter, the fallback one is accepted
+    let params = config
+        .negotiate(&[
+            "permessage-deflate; foo=1",
+            "permessage-deflate; client_no_context_takeover",
+        ])
+        .unwrap();
+    assert!(params.client_no_context_takeover);
+
+    assert!(config.negotiate(&["x-webkit-deflate-frame"]).is_none());
+    assert!(config
+        .negotiate(&["permessage-deflate; server_max_window_bits=8"])
+        .is_none());
+    assert!(config
+        .negotiate(&["permessage-deflate; server_max_window_bits=16"])
+        .is_none());
+    assert!(config
+        .negotiate(&["permessage-deflate; server_no_context_takeover; server_no_context_takeover"])
+        .is_none());
+}
+
+#[test]
+fn test_inflate_rfc_7692_example() {
+    let params = DeflateConfig::default()
+        .negotiate(&["permessage-deflate"])
+        .unwrap();
+    let mut deflate = PerMessageDeflate::new(params, 6);
+
+    // "Hello" compressed, from RFC 7692 section 7.2.3.1
+    let payload = deflate
+        .decompress(
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+            true,
+            usize::MAX,
+        )
+        .unwrap();
+    assert_eq!(payload, b"Hello");
+}
+
+#[test]
+fn test_compress_round_trip() {
+    let config = DeflateConfig {
+        server_no_context_takeover: true,
+        client_no_context_takeover: true,
+        ..DeflateConfig::default()
+    };
+    let params = config.negotiate(&["permessage-deflate"]).unwrap();
+    let mut server = PerMessageDeflate::new(params.clone(), 6);
+    let mut client = PerMessageDeflate::new(params, 6);
+
+    let message = br#"{"symbol":"AAPL","price":189.5},{"symbol":"AAPL","price":189.7}"#;
+    for _ in 0..2 {
+        let compressed = server.compress(message).unwrap();
+        assert!(compressed.len() < message.len());
+        assert!(!compressed.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+
+        // Inflate in two fragments
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mut inflated = client.decompress(first, false, usize::MAX).unwrap();
+        inflated.extend(client.decompress(second, true, usize::MAX).unwrap());
+        assert_eq!(inflated, message);
+    }
+}
+
+#[test]
+fn test_compressed_messages_over_the_wire() {
+    let (mut ws, mut client, response) = handshake(
+        "Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n",
+        &WebSocketConfig::default(),
+    );
+    assert!(response.contains("Sec-WebSocket-Extensions: permessage-deflate"));
+    assert!(ws.is_compressed());
+
+    // The client side codec, compress() uses the same settings in both directions here
+    let params = DeflateConfig::default()
+        .negotiate(&["permessage-deflate"])
+        .unwrap();
+    let mut codec = PerMessageDeflate::new(params, 6);
+
+    let mut bytes = client_frame(true, OpCode::Text, &codec.compress(b"Hello").unwrap());
+    bytes[0] |= 0x40; // RSV1
+    client.write_all(&bytes).unwrap();
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message.into_text().unwrap(), "Hello");
+
+    ws.send(b"Hello back".to_vec()).unwrap();
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0xC1); // FIN, RSV1, text
+    assert_eq!(
+        codec.decompress(&payload, true, usize::MAX).unwrap(),
+        b"Hello back"
+    );
+}
+
+#[test]
+fn test_no_compression_without_offer() {
+    let (ws, _client, response) = handshake("", &WebSocketConfig::default());
+    assert!(!response.contains("Sec-WebSocket-Extensions"));
+    assert!(!ws.is_compressed());
+
+    let config = WebSocketConfig {
+        deflate: None,
+        ..WebSocketConfig::default()
+    };
+    let (ws, _client, response) =
+        handshake("Sec-WebSocket-Extensions: permessage-deflate\r\n", &config);
+    assert!(!response.contains("Sec-WebSocket-Extensions"));
+    assert!(!ws.is_compressed());
+}
+
+#[test]
+fn test_subprotocol_negotiation() {
+    let config = WebSocketConfig {
+        subprotocols: vec!["finance.v2".to_owned(), "finance.v1".to_owned()],
+        ..WebSocketConfig::default()
+    };
+
+    // The client's order decides between protocols we both support
+    let (ws, _client, response) = handshake(
+        "Sec-WebSocket-Protocol: chat, finance.v1, finance.v2\r\n",
+        &config,
+    );
+    assert!(response.contains("Sec-WebSocket-Protocol: finance.v1\r\n"));
+    assert_eq!(ws.subprotocol(), Some("finance.v1"));
+
+    let (ws, _client, response) = handshake("Sec-WebSocket-Protocol: chat\r\n", &config);
+    assert!(!response.contains("Sec-WebSocket-Protocol"));
+    assert_eq!(ws.subprotocol(), None);
+
+    let (ws, _client, response) = handshake("", &config);
+    assert!(!response.contains("Sec-WebSocket-Protocol"));
+    assert_eq!(ws.subprotocol(), None);
+}
+
+#[test]
+fn test_client_connects_and_exchanges_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+
+    // Echo server, accept() fails on any client frame that isn't masked
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::accept(stream).unwrap();
+        let message = ws.read_message().unwrap();
+        ws.send_message(message).unwrap();
+        ws.read_message().unwrap() // close frame
+    });
+
+    let mut ws = WebSocket::connect(&url).unwrap();
+    assert_eq!(ws.state(), ConnectionState::Connected);
+
+    ws.send(b"Hello from the client".to_vec()).unwrap();
+    let reply = ws.read_message().unwrap();
+    assert_eq!(reply.into_text().unwrap(), "Hello from the client");
+
+    ws.close(CloseCode::Normal, "").unwrap();
+    ws.read_message().unwrap();
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    let close = server.join().unwrap();
+    assert_eq!(
+        close,
+        Message::Close(Some(CloseFrame::new(CloseCode::Normal, "")))
+    );
+}
+
+#[test]
+fn test_client_rejects_wrong_accept_key() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\n\
+                Connection: Upgrade\r\n\
+                Upgrade: websocket\r\n\
+                Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+            )
+            .unwrap();
+    });
+
+    assert!(WebSocket::connect(&url).is_err());
+    assert!(WebSocket::connect("http://127.0.0.1:80").is_err());
+    assert!(WebSocket::connect("ws://127.0.0.1:notaport").is_err());
+}
+
+#[test]
+fn test_parse_ws_url() {
+    let parse = |url| crate::websockets::connection::parse_ws_url(url).ok();
+    let parsed = |host: &str, port, path: &str| Some((host.to_owned(), port, path.to_owned()));
+
+    assert_eq!(parse("ws://localhost"), parsed("localhost", 80, "/"));
+    assert_eq!(
+        parse("ws://127.0.0.1:8080/ws?token=1"),
+        parsed("127.0.0.1", 8080, "/ws?token=1")
+    );
+    assert_eq!(parse("ws://[::1]:8080/"), parsed("::1", 8080, "/"));
+    assert_eq!(parse("ws://[::1]/"), parsed("::1", 80, "/"));
+    assert_eq!(parse("ws://[::1]"), parsed("::1", 80, "/"));
+
+    for url in [
+        "http://localhost",
+        "ws://:8080",
+        "ws://127.0.0.1:notaport",
+        "ws://::1/",
+        "ws://[::1/",
+        "ws://[::1]8080/",
+        "ws://[]:8080/",
+    ] {
+        assert_eq!(parse(url), None, "{}", url);
+    }
+}
+
+#[test]
+fn test_client_connects_over_ipv6() {
+    let Ok(listener) = TcpListener::bind("[::1]:0") else {
+        return; // no IPv6 loopback here
+    };
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::with_capacity(1, stream);
+        Request::parse(&mut reader).unwrap()
+    });
+
+    // The server hangs up without answering, the request is all that matters
+    let _ = WebSocket::connect(&format!("ws://[::1]:{}/ws", port));
+    let request = server.join().unwrap();
+    assert_eq!(
+        request.get_header("Host"),
+        Some(format!("[::1]:{}", port).as_str())
+    );
+}
+
+// Accepts one connection, reads the upgrade request and answers with `respond`, given
+// the client's key. Returns the result of connecting to it.
+fn connect_to_fake_server(
+    respond: impl FnOnce(&mut TcpStream, &str) + Send + 'static,
+) -> std::io::Result<WebSocket> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::with_capacity(1, &mut stream);
+        let request = Request::parse(&mut reader).unwrap();
+        let key = request.get_header("Sec-WebSocket-Key").unwrap().to_owned();
+        respond(&mut stream, &key);
+    });
+    WebSocket::connect(&url)
+}
+
+#[test]
+fn test_client_accepts_connection_token_lists() {
+    let ws = connect_to_fake_server(|stream, key| {
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Upgrade: WebSocket\r\n\
+            Sec-WebSocket-Accept: {}\r\n\r\n",
+            crate::websockets::generate_accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+    assert_eq!(ws.unwrap().state(), ConnectionState::Connected);
+}
+
+#[test]
+fn test_client_limits_the_handshake_response() {
+    // Headers that never end
+    let error = connect_to_fake_server(|stream, _| {
+        let _ = stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\n");
+        while stream.write_all(b"X-Padding: aaaaaaaaaaaaaaaa\r\n").is_ok() {}
+    })
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().starts_with("Invalid handshake response"));
+
+    // and a single line that never ends
+    let error = connect_to_fake_server(|stream, _| {
+        let _ = stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nX-Padding: ");
+        while stream.write_all(&[b'a'; 1024]).is_ok() {}
+    })
+    .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_parse_request() {
+    let raw = "GET /ws/prices?symbol=AAPL&range=1%20day&tag=a&tag=b HTTP/1.1\r\n\
+        Host: 127.0.0.1:8080\r\n\
+        sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Key2: not the key\r\n\
+        Sec-WebSocket-Protocol: finance.v2, finance.v1\r\n\
+        Sec-WebSocket-Protocol: chat\r\n\
+        Origin: http://localhost:5173\r\n\r\n\
+        frame bytes";
+    let mut reader = raw.as_bytes();
+    let request = Request::parse(&mut reader).unwrap();
+
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path, "/ws/prices");
+    assert_eq!(request.version, "HTTP/1.1");
+    assert_eq!(request.query_param("symbol"), Some("AAPL"));
+    assert_eq!(request.query_param("range"), Some("1 day"));
+    assert_eq!(request.query.len(), 4);
+
+    // Case-insensitive and exact, Sec-WebSocket-Key2 doesn't match
+    assert_eq!(
+        request.get_header("Sec-WebSocket-Key"),
+        Some("dGhlIHNhbXBsZSBub25jZQ==")
+    );
+    // Values containing colons are kept whole
+    assert_eq!(request.get_header("host"), Some("127.0.0.1:8080"));
+    assert_eq!(request.get_header("Origin"), Some("http://localhost:5173"));
+    assert_eq!(
+        request.headers.get_list("Sec-WebSocket-Protocol"),
+        vec!["finance.v2", "finance.v1", "chat"]
+    );
+    assert!(request.get_header("Cookie").is_none());
+
+    // The parser stops right after the blank line
+    assert_eq!(reader, b"frame bytes");
+}
+
+#[test]
+fn test_parse_malformed_requests() {
+    let parse = |raw: &str| Request::parse(&mut raw.as_bytes());
+
+    assert!(matches!(
+        parse("GET /\r\n\r\n"),
+        Err(RequestError::InvalidRequestLine(_))
+    ));
+    assert!(matches!(
+        parse("GET http://example.com/ HTTP/1.1\r\n\r\n"),
+        Err(RequestError::InvalidRequestLine(_))
+    ));
+    assert!(matches!(
+        parse("GET / HTTX/1.1\r\n\r\n"),
+        Err(RequestError::InvalidRequestLine(_))
+    ));
+    assert!(matches!(
+        parse("GET /?q=%zz HTTP/1.1\r\n\r\n"),
+        Err(RequestError::InvalidRequestLine(_))
+    ));
+    assert!(matches!(
+        parse("GET /?q=%+1 HTTP/1.1\r\n\r\n"),
+        Err(RequestError::InvalidRequestLine(_))
+    ));
+    assert!(matches!(
+        parse("GET / HTTP/1.1\r\nHost 127.0.0.1\r\n\r\n"),
+        Err(RequestError::InvalidHeader(_))
+    ));
+    assert!(matches!(
+        parse("GET / HTTP/1.1\r\nHost : 127.0.0.1\r\n\r\n"),
+        Err(RequestError::InvalidHeader(_))
+    ));
+    assert!(matches!(
+        parse("GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n"),
+        Err(RequestError::UnexpectedEof)
+    ));
+
+    let limits = RequestLimits {
+        max_line_length: 32,
+        max_headers: 2,
+        max_size: 64,
+    };
+    let long_header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(64));
+    assert!(matches!(
+        Request::parse_with_limits(&mut long_header.as_bytes(), limits),
+        Err(RequestError::LineTooLong)
+    ));
+    let many_headers = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+    assert!(matches!(
+        Request::parse_with_limits(&mut many_headers.as_bytes(), limits),
+        Err(RequestError::TooManyHeaders)
+    ));
+    let large_headers =
+        "GET / HTTP/1.1\r\nA: 0123456789012345678901234\r\nB: 0123456789012345678901234\r\n\r\n";
+    assert!(matches!(
+        Request::parse_with_limits(&mut large_headers.as_bytes(), limits),
+        Err(RequestError::TooLarge)
+    ));
+}
+
+const UPGRADE_REQUEST: &str = "GET /ws HTTP/1.1\r\n\
+    Host: 127.0.0.1:8080\r\n\
+    Upgrade: WebSocket\r\n\
+    Connection: keep-alive, Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+// Parses UPGRADE_REQUEST with part of it replaced
+fn upgrade_request_with(from: &str, to: &str) -> Request {
+    Request::parse(&mut UPGRADE_REQUEST.replace(from, to).as_bytes()).unwrap()
+}
+
+#[test]
+fn test_validate_upgrade_request() {
+    let request = Request::parse(&mut UPGRADE_REQUEST.as_bytes()).unwrap();
+    assert_eq!(validate_request(&request), Ok("dGhlIHNhbXBsZSBub25jZQ=="));
+
+    let bad_request = |request: Request| {
+        matches!(
+            validate_request(&request),
+            Err(HandshakeError::BadRequest(_))
+        )
+    };
+    assert!(bad_request(upgrade_request_with(
+        "GET /ws HTTP/1.1",
+        "POST /ws HTTP/1.1"
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "GET /ws HTTP/1.1",
+        "GET /ws HTTP/1.0"
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "Host: 127.0.0.1:8080",
+        ""
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "Upgrade: WebSocket",
+        "Upgrade: h2c"
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "Connection: keep-alive, Upgrade",
+        "Connection: keep-alive"
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==",
+        "Sec-WebSocket-Key: dG9vIHNob3J0"
+    )));
+    assert!(bad_request(upgrade_request_with(
+        "Sec-WebSocket-Version: 13\r\n",
+        ""
+    )));
+    assert_eq!(
+        validate_request(&upgrade_request_with(
+            "Sec-WebSocket-Version: 13",
+            "Sec-WebSocket-Version: 8"
+        )),
+        Err(HandshakeError::UnsupportedVersion)
+    );
+}
+
+#[test]
+fn test_rejected_handshakes_get_http_errors() {
+    let (result, response) = raw_handshake(
+        &UPGRADE_REQUEST.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8"),
+        &WebSocketConfig::default(),
+    );
+    assert!(result.is_err());
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+
+    let (result, response) = raw_handshake(
+        &UPGRADE_REQUEST.replace("Upgrade: WebSocket\r\n", ""),
+        &WebSocketConfig::default(),
+    );
+    assert!(result.is_err());
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let (result, response) = raw_handshake("NOT HTTP\r\n", &WebSocketConfig::default());
+    assert!(result.is_err());
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // The client hangs up mid request: accept returns instead of looping forever
+    let (result, response) = raw_handshake(
+        "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n",
+        &WebSocketConfig::default(),
+    );
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+    assert!(response.is_empty());
+
+    let (result, _) = raw_handshake(UPGRADE_REQUEST, &WebSocketConfig::default());
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_origin_policy() {
+    let policy = OriginPolicy::AllowList(vec![
+        "https://app.finance.example".to_owned(),
+        "https://*.finance.example".to_owned(),
+    ]);
+
+    assert!(policy.allows(Some("https://app.finance.example")));
+    assert!(policy.allows(Some("HTTPS://App.Finance.Example")));
+    assert!(policy.allows(Some("https://beta.app.finance.example")));
+    assert!(policy.allows(None)); // not a browser
+
+    assert!(!policy.allows(Some("https://finance.example")));
+    assert!(!policy.allows(Some("http://app.finance.example")));
+    assert!(!policy.allows(Some("https://app.finance.example:8443")));
+    assert!(!policy.allows(Some("https://evilfinance.example")));
+    assert!(!policy.allows(Some("https://finance.example.evil.com")));
+    assert!(!policy.allows(Some("https://evil.com/.finance.example")));
+    assert!(!policy.allows(Some("null")));
+
+    assert!(OriginPolicy::AllowAny.allows(Some("https://evil.com")));
+    assert!(!OriginPolicy::AllowNone.allows(Some("https://app.finance.example")));
+    assert!(OriginPolicy::AllowNone.allows(None));
+}
+
+#[test]
+fn test_disallowed_origin_gets_403() {
+    let config = WebSocketConfig {
+        origin_policy: OriginPolicy::AllowList(vec!["http://localhost:5173".to_owned()]),
+        ..WebSocketConfig::default()
+    };
+    let with_origin = |origin: &str| {
+        UPGRADE_REQUEST.replace(
+            "Sec-WebSocket-Version: 13\r\n",
+            &format!("Sec-WebSocket-Version: 13\r\nOrigin: {}\r\n", origin),
+        )
+    };
+
+    let request = Request::parse(&mut with_origin("https://evil.com").as_bytes()).unwrap();
+    assert!(matches!(
+        check_origin(&request, &config.origin_policy),
+        Err(HandshakeError::Forbidden(_))
+    ));
+
+    let (result, response) = raw_handshake(&with_origin("https://evil.com"), &config);
+    assert!(result.is_err());
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+    let (result, _) = raw_handshake(&with_origin("http://localhost:5173"), &config);
+    assert!(result.is_ok());
+
+    let (ws, _client, response) = handshake("Origin: http://localhost:5173\r\n", &config);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert_eq!(ws.state(), ConnectionState::Connected);
+}
+
+#[test]
+fn test_wss_handshake_with_self_signed_certificate() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("finance-app-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tls = TlsConfig {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+    };
+    std::fs::write(&tls.cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&tls.key_path, certified.signing_key.serialize_pem()).unwrap();
+    let server_config = tls.load().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let stream = accept_tls(stream, &server_config).unwrap();
+        let mut ws = WebSocket::accept(stream).unwrap();
+        let message = ws.read_message().unwrap();
+        ws.send_message(message).unwrap();
+    });
+
+    // A client that only trusts our self-signed certificate
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let server_name = rustls_pki_types::ServerName::try_from("localhost").unwrap();
+    let connection = rustls::ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+    let stream = rustls::StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+
+    let mut ws = WebSocket::client(stream, "localhost", "/").unwrap();
+    ws.send(b"balance: 1200.50".to_vec()).unwrap();
+    let reply = ws.read_message().unwrap();
+    assert_eq!(reply.into_text().unwrap(), "balance: 1200.50");
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_tls_config_missing_files() {
+    let tls = TlsConfig {
+        cert_path: "/nonexistent/cert.pem".into(),
+        key_path: "/nonexistent/key.pem".into(),
+    };
+    let error = tls.load().unwrap_err();
+    assert!(error.to_string().contains("/nonexistent/cert.pem"));
+}
+
+// A server side protocol past the handshake, fed from memory
+fn open_protocol() -> Protocol {
+    let mut protocol = Protocol::new(Role::Server);
+    protocol.open(None);
+    protocol
+}
+
+#[test]
+fn test_protocol_decodes_frames_split_at_every_byte() {
+    let mut protocol = open_protocol();
+    let mut bytes = client_frame(false, OpCode::Text, b"Hello ");
+    bytes.extend(client_frame(true, OpCode::Continuation, &[b'x'; 300]));
+
+    let (last, rest) = bytes.split_last().unwrap();
+    for byte in rest {
+        protocol.receive(&[*byte]);
+        assert!(protocol.read_message().unwrap().is_none());
+    }
+    protocol.receive(&[*last]);
+
+    let text = protocol
+        .read_message()
+        .unwrap()
+        .unwrap()
+        .into_text()
+        .unwrap();
+    assert_eq!(text.len(), 306);
+    assert!(text.starts_with("Hello x"));
+    assert!(protocol.read_message().unwrap().is_none());
+}
+
+#[test]
+fn test_protocol_decodes_several_frames_from_one_read() {
+    let mut protocol = open_protocol();
+    let mut bytes = client_frame(true, OpCode::Ping, b"1");
+    bytes.extend(client_frame(true, OpCode::Binary, b"2"));
+    protocol.receive(&bytes);
+
+    let ping = protocol.read_message().unwrap().unwrap();
+    assert_eq!(ping, Message::Ping(b"1".to_vec()));
+    let message = protocol.read_message().unwrap().unwrap();
+    assert_eq!(message, Message::binary(b"2".to_vec()));
+    // The pong is queued, waiting to be written
+    assert_eq!(protocol.take_output(), b"\x8a\x011");
+}
+
+#[test]
+fn test_protocol_queues_bytes_to_send() {
+    let mut protocol = open_protocol();
+    protocol.send(b"hi".to_vec()).unwrap();
+    protocol.send_pong(b"p".to_vec());
+    assert_eq!(protocol.take_output(), b"\x81\x02hi\x8a\x01p");
+    assert!(!protocol.has_output());
+
+    // The peer closing is echoed without any socket involved
+    protocol.receive(&client_frame(true, OpCode::ConnectionClosed, &[0x03, 0xe8]));
+    protocol.read_message().unwrap().unwrap();
+    assert_eq!(protocol.state(), ConnectionState::Closed);
+    assert_eq!(protocol.take_output(), b"\x88\x02\x03\xe8");
+    assert!(protocol.send(b"late".to_vec()).is_err());
+}
+
+#[test]
+fn test_protocol_fails_on_header_before_payload_arrives() {
+    let mut protocol = open_protocol();
+    // An unmasked frame announcing 100 bytes, none of which were sent
+    protocol.receive(&[0x81, 100]);
+
+    assert!(protocol.read_message().is_err());
+    assert_eq!(protocol.state(), ConnectionState::Closed);
+    let output = protocol.take_output();
+    assert_eq!(output[0], 0x88);
+    assert_eq!(&output[2..4], &[0x03, 0xea]); // 1002
+}
+
+#[test]
+fn test_client_protocol_masks_frames() {
+    let mut protocol = Protocol::new(Role::Client);
+    protocol.open(None);
+    protocol.send(b"Hello".to_vec()).unwrap();
+
+    let output = protocol.take_output();
+    assert_eq!(output[1], 0x80 | 5);
+    let mask = &output[2..6];
+    let payload: Vec<u8> = output[6..]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    assert_eq!(payload, b"Hello");
+
+    // A server frame is never masked, one that is fails the connection
+    protocol.receive(&client_frame(true, OpCode::Text, b"x"));
+    assert!(protocol.read_message().is_err());
+}
+
+// An in-memory stream: reads come from `input`, writes go to `output`
+struct MemoryStream {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_websocket_over_memory_stream() {
+    let mut input = UPGRADE_REQUEST.as_bytes().to_vec();
+    input.extend(client_frame(true, OpCode::Text, b"ledger"));
+    input.extend(client_frame(true, OpCode::ConnectionClosed, &[0x03, 0xe8]));
+    let stream = MemoryStream {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    };
+
+    let mut ws = WebSocket::accept(stream).unwrap();
+    let message = ws.read_message().unwrap();
+    assert_eq!(message.into_text().unwrap(), "ledger");
+    ws.send(b"ok".to_vec()).unwrap();
+
+    let close = ws.read_message().unwrap();
+    assert!(close.is_close());
+    assert_eq!(ws.state(), ConnectionState::Closed);
+    // Nothing is read after the close
+    assert_eq!(
+        ws.read_message().unwrap_err().kind(),
+        std::io::ErrorKind::NotConnected
+    );
+}
+
+// A server side protocol with small limits, past the handshake
+fn limited_protocol(deflate: Option<PerMessageDeflate>) -> Protocol {
+    let config = WebSocketConfig {
+        max_frame_size: 1024,
+        max_message_size: 2048,
+        max_fragments: 4,
+        ..WebSocketConfig::default()
+    };
+    let mut protocol = Protocol::with_config(Role::Server, &config);
+    protocol.open(deflate);
+    protocol
+}
+
+// The connection must fail with 1009 as soon as the header is in
+fn assert_too_big(protocol: &mut Protocol) {
+    assert!(protocol.read_message().is_err());
+    let output = protocol.take_output();
+    assert_eq!(output[0], 0x88);
+    assert_eq!(&output[2..4], &[0x03, 0xf1]); // 1009
+}
+
+#[test]
+fn test_huge_frame_length_fails_before_allocating() {
+    let mut protocol = limited_protocol(None);
+    // A masked binary frame claiming 2^62 bytes, without any payload
+    let mut header = vec![0x82, 0xff];
+    header.extend((1u64 << 62).to_be_bytes());
+    header.extend([0x37, 0xfa, 0x21, 0x3d]);
+    protocol.receive(&header);
+
+    assert_too_big(&mut protocol);
+    assert_eq!(protocol.state(), ConnectionState::Closed);
+}
+
+#[test]
+fn test_message_and_fragment_limits() {
+    // Each frame fits, the reassembled message doesn't
+    let mut protocol = limited_protocol(None);
+    protocol.receive(&client_frame(false, OpCode::Binary, &[0; 1000]));
+    protocol.receive(&client_frame(false, OpCode::Continuation, &[0; 1000]));
+    protocol.receive(&client_frame(true, OpCode::Continuation, &[0; 1000]));
+    assert_too_big(&mut protocol);
+
+    // Lots of tiny fragments
+    let mut protocol = limited_protocol(None);
+    protocol.receive(&client_frame(false, OpCode::Text, b"a"));
+    for _ in 0..4 {
+        protocol.receive(&client_frame(false, OpCode::Continuation, b"a"));
+    }
+    assert_too_big(&mut protocol);
+
+    // Right at the limits is fine
+    let mut protocol = limited_protocol(None);
+    protocol.receive(&client_frame(false, OpCode::Binary, &[0; 1024]));
+    for fin in [false, false, true] {
+        protocol.receive(&client_frame(fin, OpCode::Continuation, &[0; 341]));
+    }
+    let message = protocol.read_message().unwrap().unwrap();
+    assert_eq!(message.into_data().len(), 2047);
+}
+
+#[test]
+fn test_compressed_message_is_limited_once_inflated() {
+    let params = DeflateParams {
+        server_no_context_takeover: false,
+        client_no_context_takeover: false,
+        server_max_window_bits: 15,
+        client_max_window_bits: None,
+    };
+    // 100 KB of zeros compress to a few hundred bytes, well under the frame limit
+    let payload = PerMessageDeflate::new(params.clone(), 9)
+        .compress(&[0; 100_000])
+        .unwrap();
+    assert!(payload.len() < 1024);
+
+    let mut protocol = limited_protocol(Some(PerMessageDeflate::new(params, 6)));
+    let mut frame = client_frame(true, OpCode::Binary, &payload);
+    frame[0] |= 0x40;
+    protocol.receive(&frame);
+    assert_too_big(&mut protocol);
+}
+
+// A WebSocket past the handshake, reading the given client frames from memory
+fn memory_websocket(frames: &[Vec<u8>]) -> WebSocket<MemoryStream> {
+    let mut input = UPGRADE_REQUEST.as_bytes().to_vec();
+    for frame in frames {
+        input.extend(frame);
+    }
+    let stream = MemoryStream {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    };
+    WebSocket::accept(stream).unwrap()
+}
+
+// Decodes the frames the server wrote after its handshake response
+fn written_frames(ws: &WebSocket<MemoryStream>) -> Vec<Frame> {
+    let output = &ws.get_ref().output;
+    let end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+
+    let mut client = Protocol::new(Role::Client);
+    client.open(None);
+    client.receive(&output[end..]);
+    std::iter::from_fn(|| client.read_frame().unwrap()).collect()
+}
+
+#[test]
+fn test_message_reader_streams_fragments() {
+    let mut ws = memory_websocket(&[
+        client_frame(false, OpCode::Binary, b"abc"),
+        client_frame(true, OpCode::Ping, b"p"),
+        client_frame(false, OpCode::Continuation, b""),
+        client_frame(true, OpCode::Continuation, b"def"),
+        client_frame(true, OpCode::Text, b"next"),
+    ]);
+
+    let mut reader = ws.message_reader().unwrap();
+    assert!(!reader.is_text());
+    let mut first = [0; 2];
+    reader.read_exact(&mut first).unwrap();
+    assert_eq!(&first, b"ab");
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"cdef");
+    drop(reader);
+
+    // The ping in the middle of the message was answered
+    let frames = written_frames(&ws);
+    assert!(matches!(frames[0].op_code, OpCode::Pong));
+    assert_eq!(frames[0].payload, b"p");
+
+    let message = ws.read_message().unwrap();
+    assert_eq!(message.into_text().unwrap(), "next");
+}
+
+#[test]
+fn test_message_reader_rejects_invalid_utf8() {
+    let mut ws = memory_websocket(&[
+        client_frame(false, OpCode::Text, "caf".as_bytes()),
+        client_frame(true, OpCode::Continuation, &[0xff]),
+    ]);
+
+    let mut reader = ws.message_reader().unwrap();
+    assert!(reader.is_text());
+    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    drop(reader);
+    assert_eq!(ws.state(), ConnectionState::Closed);
+}
+
+#[test]
+fn test_streamed_message_ignores_message_size_limit() {
+    let config = WebSocketConfig {
+        max_frame_size: 1024,
+        max_message_size: 1024,
+        max_fragments: 2,
+        ..WebSocketConfig::default()
+    };
+    let mut protocol = Protocol::with_config(Role::Server, &config);
+    protocol.open(None);
+    protocol.receive(&client_frame(false, OpCode::Binary, &[1; 1024]));
+    for fin in [false, false, true] {
+        protocol.receive(&client_frame(fin, OpCode::Continuation, &[1; 1024]));
+    }
+
+    let mut total = 0;
+    while let Some(frame) = protocol.read_fragment().unwrap() {
+        total += frame.payload.len();
+    }
+    assert_eq!(total, 4096);
+}
+
+#[test]
+fn test_dropped_message_reader_fails_the_connection() {
+    let config = WebSocketConfig {
+        max_message_size: 1024,
+        ..WebSocketConfig::default()
+    };
+    let mut input = UPGRADE_REQUEST.as_bytes().to_vec();
+    input.extend(client_frame(false, OpCode::Binary, b"abc"));
+    input.extend(client_frame(true, OpCode::Continuation, &[1; 2048]));
+    let stream = MemoryStream {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    };
+    let mut ws = WebSocket::accept_with_config(stream, &config).unwrap();
+
+    let mut reader = ws.message_reader().unwrap();
+    reader.read_exact(&mut [0; 3]).unwrap();
+    drop(reader);
+
+    // The oversized rest isn't read as a message of its own
+    assert_eq!(ws.state(), ConnectionState::Closed);
+    assert!(ws.read_message().is_err());
+    let frames = written_frames(&ws);
+    assert!(matches!(frames[0].op_code, OpCode::ConnectionClosed));
+    assert_eq!(frames[0].payload[..2], [0x03, 0xF3]);
+}
+
+#[test]
+fn test_rest_of_a_streamed_message_is_limited() {
+    let config = WebSocketConfig {
+        max_message_size: 1024,
+        ..WebSocketConfig::default()
+    };
+    let mut protocol = Protocol::with_config(Role::Server, &config);
+    protocol.open(None);
+    protocol.receive(&client_frame(false, OpCode::Binary, b"abc"));
+    protocol.receive(&client_frame(true, OpCode::Continuation, &[1; 2048]));
+
+    // Streamed no further, read_message buffers what is left and limits it
+    protocol.read_fragment().unwrap().unwrap();
+    assert!(protocol.read_message().is_err());
+    assert_eq!(protocol.state(), ConnectionState::Closed);
+    let mut client = Protocol::new(Role::Client);
+    client.open(None);
+    client.receive(&protocol.take_output());
+    let close = client.read_frame().unwrap().unwrap();
+    assert_eq!(close.payload[..2], [0x03, 0xF1]);
+}
+
+#[test]
+fn test_message_writer_sends_fragments() {
+    let mut ws = memory_websocket(&[]);
+
+    let mut writer = ws.text_writer();
+    let text = "é".repeat(FRAGMENT_SIZE); // 2 bytes per character, split mid code point
+    writer.write_all(text.as_bytes()).unwrap();
+    writer.finish().unwrap();
+
+    let frames = written_frames(&ws);
+    assert_eq!(frames.len(), 3);
+    assert!(matches!(frames[0].op_code, OpCode::Text));
+    assert!(!frames[0].fin && !frames[1].fin && frames[2].fin);
+    assert!(matches!(frames[1].op_code, OpCode::Continuation));
+    assert_eq!(frames[0].payload.len(), FRAGMENT_SIZE);
+    assert!(frames[2].payload.is_empty());
+    let payload: Vec<u8> = frames.into_iter().flat_map(|frame| frame.payload).collect();
+    assert_eq!(payload, text.as_bytes());
+}
+
+#[test]
+fn test_message_writer_finishes_on_drop() {
+    let mut ws = memory_websocket(&[]);
+    {
+        let mut writer = ws.binary_writer();
+        writer.write_all(b"statement").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b".pdf").unwrap();
+    }
+    assert!(ws.text_writer().write_all(&[0xff]).is_err());
+
+    let frames = written_frames(&ws);
+    assert!(matches!(frames[0].op_code, OpCode::Binary));
+    assert_eq!(frames[0].payload, b"statement");
+    assert!(frames[1].fin);
+    assert_eq!(frames[1].payload, b".pdf");
+}
+
+#[test]
+fn test_message_writer_fails_the_connection_on_a_cut_code_point() {
+    // Nothing went out yet, the connection is still usable
+    let mut ws = memory_websocket(&[]);
+    let mut writer = ws.text_writer();
+    writer.write_all(&[0xc3]).unwrap();
+    assert!(writer.finish().is_err());
+    ws.send_message(Message::text("still fine")).unwrap();
+    let frames = written_frames(&ws);
+    assert_eq!(frames.len(), 1);
+    assert!(frames[0].fin);
+
+    // Once a fragment was sent, the message can't be ended anymore
+    let mut ws = memory_websocket(&[]);
+    let mut writer = ws.text_writer();
+    writer.write_all(b"balance: ").unwrap();
+    writer.flush().unwrap();
+    writer.write_all(&[0xc3]).unwrap();
+    assert!(writer.finish().is_err());
+    let error = ws.send_message(Message::text("next")).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    let frames = written_frames(&ws);
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(frames[0].op_code, OpCode::Text));
+    assert!(!frames[0].fin);
+    assert!(matches!(frames[1].op_code, OpCode::ConnectionClosed));
+    assert_eq!(frames[1].payload[..2], [0x03, 0xF3]); // 1011
+}
+
+#[test]
+fn test_message_conversions() {
+    assert_eq!(Message::from("hi"), Message::Text("hi".to_owned()));
+    assert_eq!(Message::from(vec![1, 2]), Message::Binary(vec![1, 2]));
+    assert!(Message::text("hi").is_text());
+    assert!(Message::binary([0u8; 2]).is_binary());
+    assert_eq!(Message::binary(vec![1]).into_text(), None);
+    assert_eq!(
+        Message::Close(Some(CloseFrame::new(CloseCode::Normal, "bye"))).into_data(),
+        b"\x03\xe8bye"
+    );
+    assert!(Message::Close(None).into_data().is_empty());
+}
+
+#[test]
+fn test_send_message_of_every_kind() {
+    let mut ws = memory_websocket(&[]);
+    ws.send_message(Message::text("tick")).unwrap();
+    ws.send_message(Message::binary(vec![0xde, 0xad])).unwrap();
+    ws.send_message(Message::Ping(b"hb".to_vec())).unwrap();
+    ws.send_message(Message::Pong(Vec::new())).unwrap();
+    assert!(ws.send_message(Message::Ping(vec![0; 126])).is_err());
+    ws.send_message(Message::Close(None)).unwrap();
+    assert_eq!(ws.state(), ConnectionState::Closing);
+    assert!(ws.send_message(Message::text("late")).is_err());
+
+    let frames: Vec<(u8, Vec<u8>)> = written_frames(&ws)
+        .into_iter()
+        .map(|frame| (frame.op_code as u8, frame.payload))
+        .collect();
+    assert_eq!(
+        frames,
+        vec![
+            (0x1, b"tick".to_vec()),
+            (0x2, vec![0xde, 0xad]),
+            (0x9, b"hb".to_vec()),
+            (0xA, Vec::new()),
+            (0x8, Vec::new()),
+        ]
+    );
+}
+
+#[test]
+fn test_heartbeat_schedule() {
+    let config = HeartbeatConfig {
+        interval: Duration::from_secs(30),
+        pong_timeout: Duration::from_secs(10),
+    };
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+    let mut heartbeat = Heartbeat::new(config, start);
+
+    assert_eq!(heartbeat.poll(at(29)), HeartbeatAction::Wait);
+    assert_eq!(heartbeat.deadline(), at(30));
+    let HeartbeatAction::Ping(payload) = heartbeat.poll(at(30)) else {
+        panic!("expected a ping");
+    };
+    assert_eq!(heartbeat.deadline(), at(40));
+
+    // A pong that doesn't echo our ping doesn't count as the answer
+    heartbeat.on_pong(b"unsolicited", at(31));
+    assert_eq!(heartbeat.last_pong(), Some(at(31)));
+    assert_eq!(heartbeat.rtt(), None);
+
+    heartbeat.on_pong(&payload, at(32));
+    assert_eq!(heartbeat.rtt(), Some(Duration::from_secs(2)));
+    assert_eq!(heartbeat.poll(at(45)), HeartbeatAction::Wait);
+
+    // The next ping goes unanswered
+    assert!(matches!(heartbeat.poll(at(60)), HeartbeatAction::Ping(_)));
+    assert_eq!(heartbeat.poll(at(69)), HeartbeatAction::Wait);
+    assert_eq!(heartbeat.poll(at(70)), HeartbeatAction::PongTimedOut);
+}
+
+#[test]
+fn test_heartbeat_doesnt_ping_once_closing() {
+    let config = HeartbeatConfig {
+        interval: Duration::from_secs(30),
+        pong_timeout: Duration::from_secs(10),
+    };
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+    let mut heartbeat = Heartbeat::new(config, start);
+    let mut protocol = Protocol::new(Role::Server);
+    protocol.open(None);
+    protocol.close(CloseCode::Normal, "").unwrap();
+    assert_eq!(protocol.take_output()[0], 0x88);
+    assert_eq!(protocol.state(), ConnectionState::Closing);
+
+    // Due, but only the close frame went out, and the peer has pong_timeout to echo it
+    let timeout = heartbeat.drive(&mut protocol, at(30)).unwrap();
+    assert!(!protocol.has_output());
+    assert_eq!(timeout, Duration::from_secs(10));
+    assert_eq!(heartbeat.deadline(), at(40));
+
+    let error = heartbeat.drive(&mut protocol, at(40)).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(protocol.state(), ConnectionState::Closed);
+    assert!(!protocol.has_output());
+}
+
+fn fast_heartbeat() -> HeartbeatConfig {
+    HeartbeatConfig {
+        interval: Duration::from_millis(50),
+        pong_timeout: Duration::from_millis(100),
+    }
+}
+
+#[test]
+fn test_silent_peer_is_disconnected() {
+    let (mut ws, mut client) = socket_pair();
+    ws.enable_heartbeat(fast_heartbeat());
+
+    let started = Instant::now();
+    let error = ws.read_message().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    // The client got the ping it never answered, then a 1001 close
+    let (first_byte, _) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x89);
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(payload[..2], [0x03, 0xE9]);
+}
+
+#[test]
+fn test_silent_peer_is_dropped_after_our_close() {
+    let (mut ws, mut client) = socket_pair();
+    ws.enable_heartbeat(fast_heartbeat());
+
+    ws.close(CloseCode::Normal, "bye").unwrap();
+    let started = Instant::now();
+    let error = ws.read_message().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(elapsed < Duration::from_secs(1));
+    assert_eq!(ws.state(), ConnectionState::Closed);
+
+    // Only the close frame went out, no pings after it
+    let (first_byte, _) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    client.set_nonblocking(true).unwrap();
+    let mut byte = [0; 1];
+    assert_eq!(
+        client.read(&mut byte).unwrap_err().kind(),
+        std::io::ErrorKind::WouldBlock
+    );
+}
+
+#[test]
+fn test_answered_pings_measure_rtt() {
+    let (mut ws, mut client) = socket_pair();
+    ws.enable_heartbeat(fast_heartbeat());
+
+    let peer = thread::spawn(move || {
+        let (first_byte, payload) = read_server_frame(&mut client);
+        assert_eq!(first_byte, 0x89);
+        client
+            .write_all(&client_frame(true, OpCode::Pong, &payload))
+            .unwrap();
+        // Stays quiet past the ping interval, pings keep coming without inbound traffic
+        thread::sleep(Duration::from_millis(60));
+        let (_, payload) = read_server_frame(&mut client);
+        client
+            .write_all(&client_frame(true, OpCode::Pong, &payload))
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OpCode::Text, b"still here"))
+            .unwrap();
+    });
+
+    // The pongs come back as messages, the pings went out in between
+    assert!(matches!(ws.read_message().unwrap(), Message::Pong(_)));
+    assert!(ws.rtt().is_some());
+    assert!(ws.last_pong().is_some());
+    assert!(matches!(ws.read_message().unwrap(), Message::Pong(_)));
+    assert_eq!(ws.read_message().unwrap(), Message::text("still here"));
+    peer.join().unwrap();
+}
+
+#[test]
+fn test_split_writer_pushes_while_reader_blocks() {
+    let (ws, mut client) = socket_pair();
+    let (mut reader, writer) = ws.split().unwrap();
+
+    // The reader is blocked with nothing coming in, the pushes go out anyway
+    let reading = thread::spawn(move || reader.read_message().unwrap());
+    let pushers: Vec<_> = (0..2)
+        .map(|i| {
+            let writer = writer.clone();
+            thread::spawn(move || writer.send_message(Message::text(format!("push {}", i))))
+        })
+        .collect();
+    for pusher in pushers {
+        pusher.join().unwrap().unwrap();
+    }
+
+    let mut pushed: Vec<_> = (0..2)
+        .map(|_| {
+            let (first_byte, payload) = read_server_frame(&mut client);
+            assert_eq!(first_byte, 0x81);
+            String::from_utf8(payload).unwrap()
+        })
+        .collect();
+    pushed.sort();
+    assert_eq!(pushed, ["push 0", "push 1"]);
+
+    client
+        .write_all(&client_frame(true, OpCode::Text, b"reply"))
+        .unwrap();
+    assert_eq!(reading.join().unwrap(), Message::text("reply"));
+}
+
+#[test]
+fn test_split_halves_share_close_state() {
+    let (ws, mut client) = socket_pair();
+    let (mut reader, writer) = ws.split().unwrap();
+
+    client
+        .write_all(&client_frame(true, OpCode::ConnectionClosed, &[0x03, 0xE8]))
+        .unwrap();
+    assert!(reader.read_message().unwrap().is_close());
+
+    // The reader echoed the close, the writer sees the connection is gone
+    let (first_byte, _) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(writer.state(), ConnectionState::Closed);
+    let error = writer.send_message(Message::text("late")).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn test_split_writer_close_completes_on_reader() {
+    let (ws, mut client) = socket_pair();
+    let (mut reader, writer) = ws.split().unwrap();
+
+    writer.close(CloseCode::Normal, "bye").unwrap();
+    assert_eq!(reader.state(), ConnectionState::Closing);
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(payload[..2], [0x03, 0xE8]);
+
+    client
+        .write_all(&client_frame(true, OpCode::ConnectionClosed, &payload))
+        .unwrap();
+    assert!(reader.read_message().unwrap().is_close());
+    assert_eq!(writer.state(), ConnectionState::Closed);
+}
+
+fn queue_of_two(policy: OverflowPolicy) -> SendQueue {
+    let mut queue = SendQueue::new(SendQueueConfig {
+        capacity: 2,
+        policy,
+        ..SendQueueConfig::default()
+    });
+    queue.push(Message::text("one")).unwrap();
+    queue.push(Message::text("two")).unwrap();
+    queue
+}
+
+fn drain(queue: &mut SendQueue) -> Vec<Message> {
+    std::iter::from_fn(|| queue.pop()).collect()
+}
+
+#[test]
+fn test_send_queue_overflow_policies() {
+    let mut queue = queue_of_two(OverflowPolicy::Block);
+    assert!(queue.is_full());
+    assert_eq!(queue.push(Message::text("three")), Err(Overflow::Full));
+
+    let mut queue = queue_of_two(OverflowPolicy::Disconnect);
+    assert_eq!(
+        queue.push(Message::text("three")),
+        Err(Overflow::Disconnect)
+    );
+
+    let mut queue = queue_of_two(OverflowPolicy::DropNewest);
+    queue.push(Message::text("three")).unwrap();
+    assert_eq!(
+        drain(&mut queue),
+        [Message::text("one"), Message::text("two")]
+    );
+    assert_eq!(queue.metrics().dropped, 1);
+
+    // A queued close is never the one dropped, and doesn't take room
+    let mut queue = queue_of_two(OverflowPolicy::DropOldest);
+    queue.push_control(Message::Close(None));
+    queue.push(Message::text("three")).unwrap();
+    assert_eq!(
+        drain(&mut queue),
+        [
+            Message::text("two"),
+            Message::Close(None),
+            Message::text("three")
+        ]
+    );
+    assert_eq!(queue.metrics().dropped, 1);
+}
+
+#[test]
+fn test_send_queue_holds_no_more_than_its_capacity() {
+    let mut queue = queue_of_two(OverflowPolicy::Block);
+    queue.hold(Message::text("three")).unwrap();
+    queue.hold(Message::text("four")).unwrap();
+    assert_eq!(queue.hold(Message::text("five")), Err(Overflow::Disconnect));
+    assert_eq!(queue.metrics().depth, 4);
+    assert_eq!(queue.metrics().bytes, 15);
+
+    // Held messages move up as room is made, in order
+    queue.pop();
+    assert!(queue.is_full());
+    assert!(queue.has_held());
+    assert_eq!(
+        drain(&mut queue),
+        [
+            Message::text("two"),
+            Message::text("three"),
+            Message::text("four")
+        ]
+    );
+    assert!(!queue.has_held());
+    assert_eq!(queue.metrics().depth, 0);
+    assert_eq!(queue.metrics().bytes, 0);
+}
+
+#[test]
+fn test_send_queue_metrics() {
+    let mut queue = queue_of_two(OverflowPolicy::Block);
+    queue.push_control(Message::Close(None));
+    assert_eq!(
+        queue.metrics(),
+        QueueMetrics {
+            depth: 2,
+            bytes: 6,
+            peak_depth: 2,
+            sent: 0,
+            dropped: 0,
+        }
+    );
+
+    queue.pop();
+    assert_eq!(queue.metrics().depth, 1);
+    assert_eq!(queue.metrics().sent, 1);
+
+    // Failing the connection drops the messages, not the close
+    queue.clear();
+    assert_eq!(drain(&mut queue), [Message::Close(None)]);
+    assert_eq!(
+        queue.metrics(),
+        QueueMetrics {
+            depth: 0,
+            bytes: 0,
+            peak_depth: 2,
+            sent: 1,
+            dropped: 1,
+        }
+    );
+}
+
+// A split connection with a send queue of one message, the client reads with a WebSocket
+fn split_with_queue(
+    policy: OverflowPolicy,
+) -> (
+    crate::websockets::WebSocketReader,
+    crate::websockets::WebSocketWriter,
+    WebSocket,
+) {
+    split_with(SendQueueConfig {
+        capacity: 1,
+        policy,
+        ..SendQueueConfig::default()
+    })
+}
+
+fn split_with(
+    send_queue: SendQueueConfig,
+) -> (
+    crate::websockets::WebSocketReader,
+    crate::websockets::WebSocketWriter,
+    WebSocket,
+) {
+    let config = WebSocketConfig {
+        send_queue,
+        ..WebSocketConfig::default()
+    };
+    let (ws, client, _) = handshake("", &config);
+    let (reader, writer) = ws.split().unwrap();
+    (reader, writer, WebSocket::with_role(client, Role::Client))
+}
+
+#[test]
+fn test_split_writer_blocks_until_the_queue_has_room() {
+    let (_reader, writer, mut client) = split_with_queue(OverflowPolicy::Block);
+
+    // Far more than the socket buffers hold, the sender waits for the client instead
+    let sender = thread::spawn(move || {
+        for i in 0..16u8 {
+            writer
+                .send_message(Message::Binary(vec![i; 1 << 20]))
+                .unwrap();
+        }
+        writer
+    });
+
+    for i in 0..16u8 {
+        assert_eq!(
+            client.read_message().unwrap(),
+            Message::Binary(vec![i; 1 << 20])
+        );
+    }
+    let metrics = sender.join().unwrap().queue_metrics();
+    assert_eq!(metrics.peak_depth, 1);
+    assert_eq!((metrics.sent, metrics.dropped), (16, 0));
+}
+
+// Reads what is left on the client's socket, until the server ended the connection
+fn read_until_closed(client: &mut TcpStream) {
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        match client.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                assert!(
+                    !matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ),
+                    "the server never ended the connection"
+                );
+                return;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_split_writer_disconnects_slow_client() {
+    let (mut reader, writer, mut client) = split_with(SendQueueConfig {
+        capacity: 1,
+        policy: OverflowPolicy::Disconnect,
+        disconnect_timeout: Duration::from_millis(100),
+    });
+    // Sent before the client stopped reading
+    client.send_message(Message::text("buy AAPL")).unwrap();
+
+    let error = (0..64)
+        .find_map(|_| writer.send_message(Message::Binary(vec![0; 1 << 20])).err())
+        .expect("the client never read, the queue should have overflowed");
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert_eq!(writer.state(), ConnectionState::Closed);
+    assert_eq!(writer.queue_metrics().dropped, 1);
+
+    // Nothing the client sent is handed out once the connection failed
+    let error = reader.read_message().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+
+    // The client still doesn't read, so the close frame can't get through. The socket is
+    // shut down after the timeout, the write blocked on it returns.
+    thread::sleep(Duration::from_millis(300));
+    read_until_closed(client.get_mut());
+}
+
+#[test]
+fn test_split_writer_disconnect_sends_the_close_frame() {
+    let (_reader, writer, mut client) = split_with_queue(OverflowPolicy::Disconnect);
+
+    (0..64)
+        .find_map(|_| writer.send_message(Message::Binary(vec![0; 1 << 20])).err())
+        .expect("the client never read, the queue should have overflowed");
+
+    // What was being written still arrives, then the close, then the end of the connection
+    let close = loop {
+        if let Message::Close(close_frame) = client.read_message().unwrap() {
+            break close_frame.unwrap();
+        }
+    };
+    assert_eq!(close.code, CloseCode::PolicyViolation);
+    read_until_closed(client.get_mut());
+}
+
+// Keeps what the registry sent it, or fails like a closed connection once `closed` is set
+#[derive(Default)]
+struct RecordingOutbox {
+    sent: Mutex<Vec<Message>>,
+    closed: std::sync::atomic::AtomicBool,
+}
+
+impl Outbox for RecordingOutbox {
+    fn send_message(&self, message: Message) -> std::io::Result<()> {
+        if self.closed.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        }
+        self.sent.lock().unwrap().push(message);
+        Ok(())
+    }
+}
+
+impl RecordingOutbox {
+    fn sent(&self) -> Vec<Message> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_registry_broadcast_and_multicast() {
+    let registry = Registry::new();
+    let outboxes: Vec<Arc<RecordingOutbox>> = (0..3).map(|_| Arc::default()).collect();
+    let address = "127.0.0.1:4000".parse().unwrap();
+    let registrations: Vec<_> = outboxes
+        .iter()
+        .enumerate()
+        .map(|(i, outbox)| {
+            let subprotocol = (i == 0).then(|| "prices".to_owned());
+            registry.register(Some(address), subprotocol, Arc::clone(outbox) as _)
+        })
+        .collect();
+    let ids: Vec<_> = registrations.iter().map(|r| r.id()).collect();
+    assert!(registry.set_user(ids[1], Some("alice".to_owned())));
+
+    let info = registry.get(ids[1]).unwrap();
+    assert_eq!(info.remote_addr, Some(address));
+    assert_eq!(info.user.as_deref(), Some("alice"));
+    assert_eq!(
+        registry.get(ids[0]).unwrap().subprotocol.as_deref(),
+        Some("prices")
+    );
+
+    assert_eq!(registry.broadcast(Message::text("all")), 3);
+    let prices = |info: &crate::websockets::ConnectionInfo| info.subprotocol.is_some();
+    assert_eq!(registry.multicast(prices, Message::text("AAPL 190")), 1);
+    registry.send_to(ids[1], Message::text("hi alice")).unwrap();
+
+    assert_eq!(
+        outboxes[0].sent(),
+        [Message::text("all"), Message::text("AAPL 190")]
+    );
+    assert_eq!(
+        outboxes[1].sent(),
+        [Message::text("all"), Message::text("hi alice")]
+    );
+    assert_eq!(outboxes[2].sent(), [Message::text("all")]);
+}
+
+#[test]
+fn test_registry_forgets_closed_connections() {
+    let registry = Registry::new();
+    let open = Arc::new(RecordingOutbox::default());
+    let closed = Arc::new(RecordingOutbox::default());
+    let _open = registry.register(None, None, Arc::clone(&open) as _);
+    let failing = registry.register(None, None, Arc::clone(&closed) as _);
+    let dropped = registry.register(None, None, Arc::new(RecordingOutbox::default()));
+    assert_eq!(registry.len(), 3);
+
+    // Served connections unregister when they are over
+    let dropped_id = dropped.id();
+    drop(dropped);
+    assert_eq!(registry.get(dropped_id), None);
+
+    // and a connection that can't take messages anymore is removed on the next send
+    closed
+        .closed
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(registry.broadcast(Message::text("tick")), 1);
+    assert_eq!(registry.len(), 1);
+    let error = registry
+        .send_to(failing.id(), Message::text("tick"))
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    assert!(!registry.set_user(failing.id(), None));
+    assert_eq!(open.sent(), [Message::text("tick")]);
+}
+
+// Answers every message with the path it was routed for
+struct PathEcho;
+
+impl WebSocketHandler for PathEcho {
+    fn on_message(&self, session: &mut Session, _message: Message) {
+        let path = session.path().to_owned();
+        session.send(Message::text(path));
+    }
+}
+
+#[test]
+fn test_router_matches_exact_paths() {
+    let router = Router::new();
+    router.route("/ws/prices", PathEcho);
+    router.route("/ws", PathEcho);
+
+    let request = upgrade_request_with("GET /ws ", "GET /ws/prices?symbol=AAPL ");
+    assert!(router.resolve(&request).is_ok());
+    let unrouted = upgrade_request_with("GET /ws ", "GET /ws/prices/AAPL ");
+    assert_eq!(
+        router.resolve(&unrouted).err(),
+        Some(HandshakeError::NotFound("/ws/prices/AAPL".to_owned()))
+    );
+
+    assert!(router.remove("/ws/prices"));
+    assert!(!router.remove("/ws/prices"));
+    assert!(router.resolve(&request).is_err());
+    assert_eq!(router.paths(), ["/ws"]);
+}
+
+#[test]
+fn test_session_queues_what_the_handler_sends() {
+    let registry = Registry::new();
+    let outbox = Arc::new(RecordingOutbox::default());
+    let registration = registry.register(None, None, Arc::clone(&outbox) as _);
+    registry.set_user(registration.id(), Some("alice".to_owned()));
+    let request = upgrade_request_with("GET /ws ", "GET /ws/prices?symbol=AAPL&symbol=MSFT ");
+    let mut session = Session::new(Some(registration.id()), &request, Arc::clone(&registry));
+
+    assert_eq!(session.path(), "/ws/prices");
+    assert_eq!(session.query("symbol"), Some("AAPL"));
+    assert_eq!(session.query("since"), None);
+    assert_eq!(session.info().unwrap().user.as_deref(), Some("alice"));
+
+    PathEcho.on_message(&mut session, Message::text("hello"));
+    session.close(CloseCode::GoingAway, "restarting");
+    let (messages, close) = session.take_outgoing();
+    assert_eq!(messages, [Message::text("/ws/prices")]);
+    assert_eq!(
+        close,
+        Some(CloseFrame::new(CloseCode::GoingAway, "restarting"))
+    );
+    assert_eq!(session.take_outgoing(), (Vec::new(), None));
+    // Nothing went through the registry
+    assert!(outbox.sent().is_empty());
+
+    let unregistered = Session::new(None, &request, registry);
+    assert_eq!(unregistered.info(), None);
+}
+
+// Accepts one async connection, the client has already sent `request`
+#[cfg(feature = "async")]
+async fn async_handshake(
+    request: &[u8],
+    config: &WebSocketConfig,
+) -> (std::io::Result<AsyncWebSocket>, tokio::net::TcpStream) {
+    use tokio::io::AsyncWriteExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+
+    client.write_all(request).await.unwrap();
+    (
+        AsyncWebSocket::accept_with_config(server, config).await,
+        client,
+    )
+}
+
+// Reads the server's handshake response up to the blank line
+#[cfg(feature = "async")]
+async fn async_read_response(client: &mut tokio::net::TcpStream) -> String {
+    use tokio::io::AsyncReadExt;
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(client.read_u8().await.unwrap());
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_handshake_and_echo() {
+    use tokio::io::AsyncReadExt;
+
+    // The first frame comes in the same write as the request, it must not be lost
+    let mut request = UPGRADE_REQUEST.as_bytes().to_vec();
+    request.extend(client_frame(true, OpCode::Text, b"Hello"));
+    let (ws, mut client) = async_handshake(&request, &WebSocketConfig::default()).await;
+    let mut ws = ws.unwrap();
+
+    let response = async_read_response(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert_eq!(ws.state(), ConnectionState::Connected);
+
+    let message = ws.read_message().await.unwrap();
+    assert_eq!(message, Message::text("Hello"));
+    ws.send_message(message).await.unwrap();
+
+    let mut echo = [0; 7];
+    client.read_exact(&mut echo).await.unwrap();
+    assert_eq!(echo, *b"\x81\x05Hello");
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_rejects_bad_requests() {
+    use tokio::io::AsyncReadExt;
+
+    let request = UPGRADE_REQUEST.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8");
+    let (ws, mut client) = async_handshake(request.as_bytes(), &WebSocketConfig::default()).await;
+    assert!(ws.is_err());
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_connections_share_one_thread() {
+    use tokio::io::AsyncWriteExt;
+
+    // Far more connections than the pool has threads, all open at once on a single thread
+    let mut connections = Vec::new();
+    for _ in 0..16 {
+        let (ws, mut client) =
+            async_handshake(UPGRADE_REQUEST.as_bytes(), &WebSocketConfig::default()).await;
+        async_read_response(&mut client).await;
+        connections.push((ws.unwrap(), client));
+    }
+
+    // Every connection waits for a message, the replies come in reverse order
+    let mut clients = Vec::new();
+    let mut readers = Vec::new();
+    for (mut ws, client) in connections {
+        readers.push(tokio::spawn(
+            async move { ws.read_message().await.unwrap() },
+        ));
+        clients.push(client);
+    }
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        let frame = client_frame(true, OpCode::Text, i.to_string().as_bytes());
+        client.write_all(&frame).await.unwrap();
+    }
+
+    for (i, reader) in readers.into_iter().enumerate() {
+        assert_eq!(reader.await.unwrap(), Message::text(i.to_string()));
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_silent_peer_is_disconnected() {
+    let (ws, mut client) =
+        async_handshake(UPGRADE_REQUEST.as_bytes(), &WebSocketConfig::default()).await;
+    let mut ws = ws.unwrap();
+    async_read_response(&mut client).await;
+    ws.enable_heartbeat(fast_heartbeat());
+
+    let error = ws.read_message().await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    assert_eq!(ws.state(), ConnectionState::Closed);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_sends_what_is_pushed_while_reading() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (ws, mut client) =
+        async_handshake(UPGRADE_REQUEST.as_bytes(), &WebSocketConfig::default()).await;
+    let mut ws = ws.unwrap();
+    async_read_response(&mut client).await;
+    let registry = Registry::new();
+    let registration = ws.register(&registry, None, SendQueueConfig::default());
+    let reader = tokio::spawn(async move { ws.read_message().await.unwrap() });
+
+    // Pushed while the connection waits for the client, it doesn't have to speak first
+    registry
+        .send_to(registration.id(), Message::text("tick"))
+        .unwrap();
+    let mut pushed = [0; 6];
+    client.read_exact(&mut pushed).await.unwrap();
+    assert_eq!(pushed, *b"\x81\x04tick");
+
+    let frame = client_frame(true, OpCode::Text, b"Hello");
+    client.write_all(&frame).await.unwrap();
+    assert_eq!(reader.await.unwrap(), Message::text("Hello"));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_disconnects_clients_that_dont_read() {
+    let (ws, mut client) =
+        async_handshake(UPGRADE_REQUEST.as_bytes(), &WebSocketConfig::default()).await;
+    let mut ws = ws.unwrap();
+    async_read_response(&mut client).await;
+    let registry = Registry::new();
+    let config = SendQueueConfig {
+        capacity: 1,
+        policy: OverflowPolicy::Disconnect,
+        ..SendQueueConfig::default()
+    };
+    let registration = ws.register(&registry, None, config);
+
+    // Before the connection got to send any of them
+    let id = registration.id();
+    registry.send_to(id, Message::text("1")).unwrap();
+    let error = registry.send_to(id, Message::text("2")).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert!(registry.is_empty());
+
+    let error = ws.read_message().await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+    assert_eq!(ws.state(), ConnectionState::Closed);
+}
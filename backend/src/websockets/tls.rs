@@ -0,0 +1,203 @@
+//TLS for wss://: loading the certificate and key, and wrapping accepted tcp streams
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+use super::connection::READ_CHUNK_SIZE;
+use super::{ReadTimeout, TryClone};
+
+// A tcp stream with a TLS session on top, what WebSocket runs over for wss
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf, // PEM certificate chain, leaf certificate first
+    pub key_path: PathBuf,  // PEM private key (PKCS#8, PKCS#1 or SEC1)
+}
+
+impl TlsConfig {
+    // Reads TLS_CERT_PATH and TLS_KEY_PATH, None unless both are set
+    pub fn from_env() -> Option<TlsConfig> {
+        let path = |name| std::env::var_os(name).filter(|path| !path.is_empty());
+        Some(TlsConfig {
+            cert_path: path("TLS_CERT_PATH")?.into(),
+            key_path: path("TLS_KEY_PATH")?.into(),
+        })
+    }
+
+    // Loads the certificate and key into a rustls config shared by every connection
+    pub fn load(&self) -> Result<Arc<ServerConfig>, Error> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Failed to read {}: {}", self.cert_path.display(), e),
+                )
+            })?;
+        if certs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("No certificate found in {}", self.cert_path.display()),
+            ));
+        }
+
+        let key = PrivateKeyDer::from_pem_file(&self.key_path).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Failed to read {}: {}", self.key_path.display(), e),
+            )
+        })?;
+
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+// Runs the TLS handshake on an accepted connection. It's done here rather than lazily
+// on the first read so a client failing it never reaches the WebSocket handshake.
+pub fn accept_tls(stream: TcpStream, config: &Arc<ServerConfig>) -> Result<TlsStream, Error> {
+    let connection = ServerConnection::new(Arc::clone(config)).map_err(Error::other)?;
+    let mut tls = StreamOwned::new(connection, stream);
+
+    while tls.conn.is_handshaking() {
+        tls.conn.complete_io(&mut tls.sock)?;
+    }
+
+    Ok(tls)
+}
+
+// A TLS stream that can be opened twice, for the halves of a split wss connection. The
+// handles share the TLS session and each uses its own handle to the socket. The session
+// is locked while rustls encrypts or decrypts and while writing, not while waiting for
+// the client to send something.
+#[derive(Debug)]
+pub struct SharedTlsStream {
+    session: Arc<Mutex<TlsSession>>,
+    sock: TcpStream,
+}
+
+#[derive(Debug)]
+struct TlsSession {
+    conn: ServerConnection,
+    received: Vec<u8>, // decrypted, not read yet
+    closed: bool,      // the client closed its end
+}
+
+impl SharedTlsStream {
+    pub fn new(tls: TlsStream) -> Self {
+        let (mut conn, sock) = tls.into_parts();
+        // A write hands rustls a whole frame, it's sent before the write returns
+        conn.set_buffer_limit(None);
+        SharedTlsStream {
+            session: Arc::new(Mutex::new(TlsSession {
+                conn,
+                received: Vec::new(),
+                closed: false,
+            })),
+            sock,
+        }
+    }
+}
+
+impl TlsSession {
+    // Decrypts what was read from the socket, and sends what rustls answers (alerts, key
+    // updates). rustls holds at most 16 KiB of plaintext, it's taken after every record.
+    fn receive(&mut self, mut encrypted: &[u8], sock: &mut TcpStream) -> Result<(), Error> {
+        while !encrypted.is_empty() {
+            self.conn.read_tls(&mut encrypted)?;
+            let processed = self.conn.process_new_packets();
+            self.send(sock)?;
+            processed.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            match self.conn.reader().read_to_end(&mut self.received) {
+                Ok(_) => self.closed = true,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, sock: &mut TcpStream) -> Result<(), Error> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(sock) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for SharedTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                if !session.received.is_empty() {
+                    let read = buf.len().min(session.received.len());
+                    buf[..read].copy_from_slice(&session.received[..read]);
+                    session.received.drain(..read);
+                    return Ok(read);
+                }
+                if session.closed {
+                    return Ok(0);
+                }
+            }
+
+            let mut encrypted = [0; READ_CHUNK_SIZE];
+            let read = self.sock.read(&mut encrypted)?;
+            let mut session = self.session.lock().unwrap();
+            match read {
+                0 => session.closed = true,
+                read => session.receive(&encrypted[..read], &mut self.sock)?,
+            }
+        }
+    }
+}
+
+impl Write for SharedTlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut session = self.session.lock().unwrap();
+        session.conn.writer().write_all(buf)?;
+        session.send(&mut self.sock)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ReadTimeout for SharedTlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+impl TryClone for SharedTlsStream {
+    fn try_clone(&self) -> Result<Self, Error> {
+        Ok(SharedTlsStream {
+            session: Arc::clone(&self.session),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        self.sock.shutdown(Shutdown::Both)
+    }
+}
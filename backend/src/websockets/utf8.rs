@@ -0,0 +1,60 @@
+//Incremental utf-8 validation for text messages split over several frames
+
+#[derive(Debug, Default)]
+pub struct Utf8Validator {
+    incomplete: Vec<u8>, // start of a code point cut off at the end of the last chunk (at most 3 bytes)
+}
+
+impl Utf8Validator {
+    pub fn new() -> Self {
+        Utf8Validator::default()
+    }
+
+    // Validates the next chunk of a message. Returns false as soon as the bytes can't
+    // be valid utf-8 anymore, a code point cut at the end of the chunk is kept for the next one
+    pub fn feed(&mut self, chunk: &[u8]) -> bool {
+        let mut input = chunk;
+
+        // Finish the code point left over from the previous chunk first
+        if !self.incomplete.is_empty() {
+            let needed = sequence_len(self.incomplete[0]) - self.incomplete.len();
+            let take = needed.min(input.len());
+            self.incomplete.extend(&input[..take]);
+            input = &input[take..];
+
+            match std::str::from_utf8(&self.incomplete) {
+                Ok(_) => self.incomplete.clear(),
+                Err(e) if e.error_len().is_none() => return true, // chunk ended again mid code point
+                Err(_) => return false,
+            }
+        }
+
+        match std::str::from_utf8(input) {
+            Ok(_) => true,
+            // error_len is None when the input just ends in the middle of a code point
+            Err(e) if e.error_len().is_none() => {
+                self.incomplete = input[e.valid_up_to()..].to_vec();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Called after the last chunk of a message, fails if it ended mid code point.
+    // Resets the validator for the next message.
+    pub fn finish(&mut self) -> bool {
+        let complete = self.incomplete.is_empty();
+        self.incomplete.clear();
+        complete
+    }
+}
+
+// Number of bytes in a code point given its leading byte
+fn sequence_len(first_byte: u8) -> usize {
+    match first_byte {
+        0xF0..=0xF7 => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    }
+}
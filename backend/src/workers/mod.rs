@@ -1,9 +1,13 @@
 use std::io::Error;
 
 mod pool;
+mod reactor;
+#[cfg(test)]
+mod tests;
 mod worker;
 
 pub use pool::ThreadPool;
+pub use reactor::Reactor;
 pub use worker::Message;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -3,26 +3,81 @@ use std::{
     sync::{mpsc, Arc, Mutex},
 };
 
+use rustls::ServerConfig;
+
 use super::worker::{Message, Worker};
+use crate::websockets::{Registry, Router, WebSocketConfig};
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message>,
+    router: Arc<Router>,
+    registry: Arc<Registry>,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
+    // Upgrades are served by the handler `router` has for their path, and refused
+    // with a 404 if it has none
+    pub fn new(size: usize, router: Arc<Router>) -> ThreadPool {
+        ThreadPool::with_config(size, router, WebSocketConfig::default())
+    }
+
+    // Every connection handed to the pool is accepted with this config
+    pub fn with_config(size: usize, router: Arc<Router>, config: WebSocketConfig) -> ThreadPool {
+        ThreadPool::build(size, router, config, None)
+    }
+
+    // Same as with_config, but runs a TLS handshake on every connection first (wss)
+    pub fn with_tls(
+        size: usize,
+        router: Arc<Router>,
+        config: WebSocketConfig,
+        tls: Arc<ServerConfig>,
+    ) -> ThreadPool {
+        ThreadPool::build(size, router, config, Some(tls))
+    }
+
+    fn build(
+        size: usize,
+        router: Arc<Router>,
+        config: WebSocketConfig,
+        tls: Option<Arc<ServerConfig>>,
+    ) -> ThreadPool {
         let (sender, receiver) = mpsc::channel();
+        let config = Arc::new(config);
         let receiver = Arc::new(Mutex::new(receiver));
+        let registry = Registry::new();
 
         //todo switch this for 1 receiver and multiple senders
-        
+
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(
+                id,
+                Arc::clone(&receiver),
+                Arc::clone(&config),
+                tls.clone(),
+                Arc::clone(&router),
+                Arc::clone(&registry),
+            ));
+        }
+
+        ThreadPool {
+            workers,
+            sender,
+            router,
+            registry,
         }
+    }
+
+    // The router it was built with, routes added to it serve the next upgrades
+    pub fn router(&self) -> &Arc<Router> {
+        &self.router
+    }
 
-        ThreadPool { workers, sender }
+    // Every upgraded connection, to push messages to them from anywhere
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
     }
 
     pub fn execute(&self, stream: TcpStream) {
@@ -0,0 +1,988 @@
+//Readiness based event loop: a few threads each multiplex many non-blocking connections over
+//epoll (through mio), instead of a worker thread blocked on every connection until it closes
+
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    io::{Error, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use mio::{net::TcpStream, Events, Interest, Poll, Token, Waker};
+use rustls::{ServerConfig, ServerConnection};
+use socket2::SockRef;
+
+use crate::websockets::{
+    CloseCode, CloseFrame, ConnectionState, HandshakeError, Heartbeat, Message, Outbox, Overflow,
+    OverflowPolicy, Protocol, Registration, Registry, Request, Role, Router, SendQueue,
+    SendQueueConfig, Session, WebSocketConfig, WebSocketHandler,
+};
+
+// Wakes an event loop when the reactor hands it a connection or a message was pushed to
+// one of its connections, connections count from 1
+const WAKER: Token = Token(0);
+
+// How much is read from a socket at once, a read loops until the socket would block or
+// the read budget is used up
+const READ_CHUNK_SIZE: usize = 4096;
+
+// How much is read from a socket per event, so one fast sender doesn't hold up the other
+// connections of its loop. What is left is read once the socket is re-armed.
+const READ_BUDGET: usize = 16 * READ_CHUNK_SIZE;
+
+thread_local! {
+    // Set on the event loop threads, they never wait for room in an outbox: the loop
+    // they would wait on may be their own, or waiting on them
+    static EVENT_LOOP: Cell<bool> = const { Cell::new(false) };
+}
+
+pub struct Reactor {
+    loops: Vec<(mpsc::Sender<std::net::TcpStream>, Arc<Waker>)>,
+    next: AtomicUsize, // event loop getting the next connection, round robin
+    router: Arc<Router>,
+    registry: Arc<Registry>,
+}
+
+impl Reactor {
+    // Upgrades are served by the handler `router` has for their path, and refused
+    // with a 404 if it has none
+    pub fn new(threads: usize, router: Arc<Router>) -> Result<Reactor, Error> {
+        Reactor::with_config(threads, router, WebSocketConfig::default())
+    }
+
+    // Every connection handed to the reactor is accepted with this config
+    pub fn with_config(
+        threads: usize,
+        router: Arc<Router>,
+        config: WebSocketConfig,
+    ) -> Result<Reactor, Error> {
+        Reactor::build(threads, router, config, None)
+    }
+
+    // Same as with_config, but runs a TLS handshake on every connection first (wss)
+    pub fn with_tls(
+        threads: usize,
+        router: Arc<Router>,
+        config: WebSocketConfig,
+        tls: Arc<ServerConfig>,
+    ) -> Result<Reactor, Error> {
+        Reactor::build(threads, router, config, Some(tls))
+    }
+
+    fn build(
+        threads: usize,
+        router: Arc<Router>,
+        config: WebSocketConfig,
+        tls: Option<Arc<ServerConfig>>,
+    ) -> Result<Reactor, Error> {
+        let config = Arc::new(config);
+        let registry = Registry::new();
+
+        let mut loops = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let (sender, receiver) = mpsc::channel();
+            let (push_sender, pushed) = mpsc::channel();
+            let poll = Poll::new()?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+            let event_loop = EventLoop {
+                poll,
+                receiver,
+                pushed,
+                connections: HashMap::new(),
+                next_token: WAKER.0 + 1,
+                timers: BinaryHeap::new(),
+                tls: tls.clone(),
+                context: Context {
+                    config: Arc::clone(&config),
+                    router: Arc::clone(&router),
+                    registry: Arc::clone(&registry),
+                    push_sender,
+                    waker: Arc::clone(&waker),
+                },
+            };
+            thread::spawn(move || event_loop.run());
+            loops.push((sender, waker));
+        }
+
+        Ok(Reactor {
+            loops,
+            next: AtomicUsize::new(0),
+            router,
+            registry,
+        })
+    }
+
+    // The router it was built with, routes added to it serve the next upgrades
+    pub fn router(&self) -> &Arc<Router> {
+        &self.router
+    }
+
+    // Every upgraded connection, to push messages to them from anywhere
+    pub fn registry(&self) -> &Arc<Registry> {
+        &self.registry
+    }
+
+    // Hands an accepted connection to one of the event loops
+    pub fn execute(&self, stream: std::net::TcpStream) {
+        if let Err(e) = stream.set_nonblocking(true) {
+            eprintln!("Failed to make the connection non-blocking: {}", e);
+            return;
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.loops.len();
+        let (sender, waker) = &self.loops[index];
+        // The loop stopped after a failure, the connection is closed
+        if sender.send(stream).is_err() {
+            eprintln!("Event loop {} stopped, dropping the connection", index);
+            return;
+        }
+        if let Err(e) = waker.wake() {
+            eprintln!("Failed to wake the event loop: {}", e);
+        }
+    }
+}
+
+impl Drop for Reactor {
+    // The event loops stop once they see their channel closed
+    fn drop(&mut self) {
+        for (sender, waker) in self.loops.drain(..) {
+            drop(sender);
+            let _ = waker.wake();
+        }
+    }
+}
+
+struct EventLoop {
+    poll: Poll,
+    receiver: mpsc::Receiver<std::net::TcpStream>,
+    pushed: mpsc::Receiver<(Token, Pushed)>, // sent through the registry
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    // Handshake, heartbeat and disconnect deadlines of the connections, earliest first. An
+    // entry is stale once the connection's timer moved, see schedule.
+    timers: BinaryHeap<Reverse<(Instant, Token)>>,
+    tls: Option<Arc<ServerConfig>>,
+    context: Context,
+}
+
+// What the connections of an event loop share
+struct Context {
+    config: Arc<WebSocketConfig>,
+    router: Arc<Router>,
+    registry: Arc<Registry>,
+    push_sender: mpsc::Sender<(Token, Pushed)>, // for the outboxes of the connections
+    waker: Arc<Waker>,
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        EVENT_LOOP.set(true);
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let timeout = self
+                .timers
+                .peek()
+                .map(|Reverse((timer, _))| timer.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                eprintln!("Event loop failed: {}", e);
+                return;
+            }
+
+            for event in events.iter() {
+                if event.token() == WAKER {
+                    if !self.add_connections() {
+                        return;
+                    }
+                    self.deliver_pushed();
+                    continue;
+                }
+
+                let token = event.token();
+                let Some(connection) = self.connections.get_mut(&token) else {
+                    continue;
+                };
+                let mut result = Ok(());
+                if event.is_readable() || event.is_read_closed() {
+                    result = connection.on_readable(&self.context);
+                }
+                let result = result
+                    .and_then(|_| connection.flush())
+                    .and_then(|_| connection.resume(&self.context));
+                self.update(token, result);
+            }
+
+            self.run_timers(Instant::now());
+        }
+    }
+
+    // Registers the connections handed over by the reactor, false once it was dropped
+    fn add_connections(&mut self) -> bool {
+        loop {
+            let stream = match self.receiver.try_recv() {
+                Ok(stream) => stream,
+                Err(mpsc::TryRecvError::Empty) => return true,
+                Err(mpsc::TryRecvError::Disconnected) => return false,
+            };
+
+            let tls = match &self.tls {
+                Some(tls) => match ServerConnection::new(Arc::clone(tls)) {
+                    // flush only encodes a message once the one before it went out, rustls
+                    // gets to buffer it whole
+                    Ok(mut tls) => {
+                        tls.set_buffer_limit(None);
+                        Some(Box::new(tls))
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to start the TLS session: {}", e);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let mut connection = Connection::new(
+                TcpStream::from_std(stream),
+                token,
+                tls,
+                &self.context.config,
+            );
+            if let Err(e) =
+                self.poll
+                    .registry()
+                    .register(&mut connection.socket, token, connection.interest)
+            {
+                eprintln!("Failed to register the connection: {}", e);
+                continue;
+            }
+            self.connections.insert(token, connection);
+            self.schedule(token);
+        }
+    }
+
+    // Queues the messages pushed to connections of this loop, then sends them
+    fn deliver_pushed(&mut self) {
+        let mut tokens = Vec::new();
+        while let Ok((token, pushed)) = self.pushed.try_recv() {
+            let Some(connection) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            // Unregistered once closing, this was pushed before that
+            if connection.registration.is_none() || connection.done {
+                continue;
+            }
+            match pushed {
+                Pushed::Messages => {
+                    connection.take_pushed();
+                }
+                Pushed::Overflow => connection.overflow(),
+            }
+            tokens.push(token);
+        }
+
+        tokens.sort_unstable();
+        tokens.dedup();
+        for token in tokens {
+            let connection = self.connections.get_mut(&token).unwrap();
+            let result = connection.flush();
+            self.update(token, result);
+        }
+    }
+
+    // Closes the connection if it failed or is done, otherwise waits for writability
+    // only while there is output the socket didn't take yet
+    fn update(&mut self, token: Token, result: Result<(), Error>) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if result.is_err() || (connection.done && !connection.has_pending()) {
+            let mut connection = self.connections.remove(&token).unwrap();
+            connection.finish(result.err());
+            let _ = self.poll.registry().deregister(&mut connection.socket);
+            return;
+        }
+        // Nothing can be pushed to it once it's closing, and the client gets
+        // disconnect_timeout to take what is left
+        if connection.done {
+            connection.registration = None;
+            connection.backlog.close();
+            let timeout = connection.queue.config().disconnect_timeout;
+            connection
+                .disconnect
+                .get_or_insert_with(|| Instant::now() + timeout);
+        }
+
+        let interest = if connection.has_pending() {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        // Registering again reports what is left to read as a new event, the edge of
+        // edge-triggered readiness was used up by the read that stopped at the budget
+        let rearm = connection.unread && !connection.paused && !connection.done;
+        if interest != connection.interest || rearm {
+            connection.interest = interest;
+            let registered =
+                self.poll
+                    .registry()
+                    .reregister(&mut connection.socket, token, interest);
+            if registered.is_err() {
+                self.update(token, registered);
+                return;
+            }
+        }
+
+        self.schedule(token);
+    }
+
+    // Adds the deadline of a connection to the timers, unless it already has an earlier
+    // one there. A timer that fires early finds nothing due and schedules the real one.
+    fn schedule(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let Some(deadline) = connection.deadline() else {
+            return;
+        };
+        if connection.timer.is_none_or(|timer| deadline < timer) {
+            connection.timer = Some(deadline);
+            self.timers.push(Reverse((deadline, token)));
+        }
+    }
+
+    // Times out handshakes and runs the heartbeats that are due, only the connections
+    // whose timer fired are visited. What they schedule next waits for the next round.
+    fn run_timers(&mut self, now: Instant) {
+        let mut due = Vec::new();
+        while let Some(&Reverse((deadline, token))) = self.timers.peek() {
+            if deadline > now {
+                break;
+            }
+            self.timers.pop();
+            // Closed since, or replaced by an earlier timer
+            if let Some(connection) = self.connections.get_mut(&token) {
+                if connection.timer == Some(deadline) {
+                    connection.timer = None;
+                    due.push(token);
+                }
+            }
+        }
+
+        for token in due {
+            let connection = self.connections.get_mut(&token).unwrap();
+            let result = connection
+                .on_timer(now)
+                .and_then(|_| connection.flush())
+                .and_then(|_| connection.resume(&self.context));
+            self.update(token, result);
+        }
+    }
+}
+
+// What the outbox of a connection hands its event loop
+enum Pushed {
+    Messages, // the backlog has messages, the ones after the first don't wake the loop
+    Overflow, // more was pushed than the loop took, disconnect the client
+}
+
+// Shared by a connection and its outbox
+#[derive(Default)]
+struct Backlog {
+    pending: Mutex<Pending>,
+    taken: Condvar, // the loop took a message, or the connection closed
+}
+
+#[derive(Default)]
+struct Pending {
+    messages: VecDeque<Message>, // pushed through the registry, the loop didn't take them yet
+    closed: bool,                // nothing more is taken, the connection is closing
+}
+
+impl Backlog {
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap()
+    }
+
+    fn take(&self) -> Option<Message> {
+        let message = self.lock().messages.pop_front()?;
+        self.taken.notify_all();
+        Some(message)
+    }
+
+    fn close(&self) {
+        let mut pending = self.lock();
+        pending.closed = true;
+        pending.messages.clear();
+        self.taken.notify_all();
+    }
+}
+
+// Hands messages pushed from other threads to the event loop serving the connection. The
+// send queue policy applies to what piles up before the loop takes it: DropOldest and
+// DropNewest discard a message, Disconnect fails the connection, and Block waits for the
+// loop to take some, as the writer of a split connection waits for room. Event loop threads
+// can't wait, a handler pushing through the registry holds as many more before the client
+// is disconnected, as SendQueue::hold does.
+struct LoopOutbox {
+    token: Token,
+    sender: mpsc::Sender<(Token, Pushed)>,
+    waker: Arc<Waker>,
+    backlog: Arc<Backlog>,
+    config: SendQueueConfig,
+}
+
+impl LoopOutbox {
+    fn hand_over(&self, pushed: Pushed) -> Result<(), Error> {
+        self.sender
+            .send((self.token, pushed))
+            .map_err(|_| Error::new(ErrorKind::NotConnected, "Event loop stopped"))?;
+        self.waker.wake()
+    }
+}
+
+impl Outbox for LoopOutbox {
+    fn send_message(&self, message: Message) -> Result<(), Error> {
+        let capacity = self.config.capacity;
+        let mut pending = self.backlog.lock();
+        loop {
+            if pending.closed {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "The connection is closed",
+                ));
+            }
+            if pending.messages.len() < capacity {
+                break;
+            }
+
+            match self.config.policy {
+                OverflowPolicy::DropNewest => return Ok(()),
+                OverflowPolicy::DropOldest => {
+                    pending.messages.pop_front();
+                    break;
+                }
+                OverflowPolicy::Block if !EVENT_LOOP.get() => {
+                    pending = self.backlog.taken.wait(pending).unwrap();
+                }
+                OverflowPolicy::Block if pending.messages.len() < 2 * capacity => break,
+                OverflowPolicy::Block | OverflowPolicy::Disconnect => {
+                    pending.closed = true;
+                    pending.messages.clear();
+                    drop(pending);
+                    self.backlog.taken.notify_all();
+                    self.hand_over(Pushed::Overflow)?;
+                    return Err(Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "Send queue full, the client was disconnected (close code 1008)",
+                    ));
+                }
+            }
+        }
+
+        pending.messages.push_back(message);
+        let first = pending.messages.len() == 1;
+        drop(pending);
+        if first {
+            self.hand_over(Pushed::Messages)?;
+        }
+        Ok(())
+    }
+}
+
+// One client connection, from the upgrade request to the close handshake
+struct Connection {
+    socket: TcpStream,
+    token: Token,
+    interest: Interest,                 // what the socket is registered for
+    tls: Option<Box<ServerConnection>>, // set for wss, buffers its own output
+    outgoing: Vec<u8>,                  // encoded bytes the socket didn't take yet, without TLS
+    protocol: Protocol,
+    queue: SendQueue, // messages to send, only encoded once the socket takes them
+    paused: bool,     // reading stopped because the queue is full (Block policy)
+    unread: bool,     // the last read stopped at READ_BUDGET before the socket was empty
+    backlog: Arc<Backlog>, // of what was pushed through the registry
+    handshake: Option<(Vec<u8>, Option<Instant>)>, // request received so far and its deadline
+    heartbeat: Option<Heartbeat>,
+    registration: Option<Registration>, // from the end of the handshake until it's closing
+    handler: Option<(Arc<dyn WebSocketHandler>, Session)>, // once upgraded
+    closing: Option<CloseFrame>,        // asked for by the handler, sent once the queue is empty
+    close_frame: Option<CloseFrame>,    // received from the client, for on_close
+    done: bool,                         // close the connection once the output is flushed
+    disconnect: Option<Instant>,        // once done, when it's closed even with output left
+    timer: Option<Instant>,             // earliest deadline it has among the loop's timers
+}
+
+impl Connection {
+    fn new(
+        socket: TcpStream,
+        token: Token,
+        tls: Option<Box<ServerConnection>>,
+        config: &WebSocketConfig,
+    ) -> Self {
+        let deadline = config
+            .handshake_timeout
+            .map(|timeout| Instant::now() + timeout);
+
+        Connection {
+            socket,
+            token,
+            interest: Interest::READABLE,
+            tls,
+            outgoing: Vec::new(),
+            protocol: Protocol::with_config(Role::Server, config),
+            queue: SendQueue::new(config.send_queue),
+            paused: false,
+            unread: false,
+            backlog: Arc::default(),
+            handshake: Some((Vec::new(), deadline)),
+            heartbeat: None,
+            registration: None,
+            handler: None,
+            closing: None,
+            close_frame: None,
+            done: false,
+            disconnect: None,
+            timer: None,
+        }
+    }
+
+    fn on_readable(&mut self, context: &Context) -> Result<(), Error> {
+        // Messages left undecoded while the queue was full come before reading any more
+        if self.handshake.is_none() && !self.done {
+            self.read_messages()?;
+            if self.paused {
+                return Ok(());
+            }
+        }
+        let (received, eof) = self.read()?;
+
+        match self.handshake.as_mut() {
+            Some((request, _)) => {
+                request.extend_from_slice(&received);
+                match Request::parse_partial(request, context.config.request_limits()) {
+                    Ok(None) => {}
+                    Ok(Some((request, len))) => {
+                        let (received, _) = self.handshake.take().unwrap();
+                        self.upgrade(&request, context)?;
+                        self.protocol.receive(&received[len..]);
+                    }
+                    Err(e) => match HandshakeError::from_request_error(&e) {
+                        Some(error) => self.reject(&error)?,
+                        None => return Err(e.into()),
+                    },
+                }
+            }
+            None => self.protocol.receive(&received),
+        }
+
+        if self.handshake.is_none() && !self.done {
+            self.read_messages()?;
+        }
+        if eof {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    // Answers the upgrade request, the same way WebSocket::accept_routed does, registers
+    // the connection and opens it with the handler of its path
+    fn upgrade(&mut self, request: &Request, context: &Context) -> Result<(), Error> {
+        let routed = context.router.resolve(request).and_then(|handler| {
+            crate::websockets::negotiate(request, &context.config)
+                .map(|negotiated| (handler, negotiated))
+        });
+        let (handler, negotiated) = match routed {
+            Ok(routed) => routed,
+            Err(error) => return self.reject(&error),
+        };
+        self.write(negotiated.response.as_bytes())?;
+        self.protocol.open(negotiated.deflate);
+        self.heartbeat = context
+            .config
+            .heartbeat
+            .map(|heartbeat| Heartbeat::new(heartbeat, Instant::now()));
+
+        let outbox = LoopOutbox {
+            token: self.token,
+            sender: context.push_sender.clone(),
+            waker: Arc::clone(&context.waker),
+            backlog: Arc::clone(&self.backlog),
+            config: context.config.send_queue,
+        };
+        let registration = context.registry.register(
+            self.socket.peer_addr().ok(),
+            negotiated.subprotocol,
+            Arc::new(outbox),
+        );
+
+        let mut session = Session::new(
+            Some(registration.id()),
+            request,
+            Arc::clone(&context.registry),
+        );
+        self.registration = Some(registration);
+        handler.on_open(&mut session);
+        self.handler = Some((handler, session));
+        self.send_outgoing();
+        Ok(())
+    }
+
+    // Tells the handler the connection is over and takes it out of the registry
+    fn finish(&mut self, error: Option<Error>) {
+        if let Some((handler, session)) = self.handler.take() {
+            if let Some(error) = &error {
+                handler.on_error(&session, error);
+            }
+            handler.on_close(&session, self.close_frame.as_ref());
+        }
+        self.registration = None;
+    }
+
+    // Queues what the handler sent, false if that failed the connection
+    fn send_outgoing(&mut self) -> bool {
+        let Some((_, session)) = self.handler.as_mut() else {
+            return true;
+        };
+        let (messages, close) = session.take_outgoing();
+        if close.is_some() {
+            self.closing = close;
+        }
+        messages.into_iter().all(|message| self.push(message))
+    }
+
+    fn reject(&mut self, error: &HandshakeError) -> Result<(), Error> {
+        self.write(error.to_response().as_bytes())?;
+        self.protocol.abort();
+        self.done = true;
+        Ok(())
+    }
+
+    // Decodes every message received so far, pings and closes are answered by the protocol.
+    // With the Block policy, decoding stops while the queue is full: the client isn't read
+    // from until it reads what was sent to it.
+    fn read_messages(&mut self) -> Result<(), Error> {
+        loop {
+            self.paused = self.queue.policy() == OverflowPolicy::Block && self.queue.is_full();
+            if self.paused {
+                break;
+            }
+
+            match self.protocol.read_message() {
+                Ok(Some(message)) => {
+                    if !self.on_message(message) {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                // The protocol queued the close frame failing the connection
+                Err(e) => {
+                    eprintln!("Failed to read message: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if self.protocol.state() == ConnectionState::Closed {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    // Picks reading back up once the queue has room again. The socket won't report what
+    // arrived while paused a second time, so this goes on until reading stops on its own,
+    // the read budget runs out and update re-arms the socket, or the socket is full and a
+    // writable event will come back here.
+    fn resume(&mut self, context: &Context) -> Result<(), Error> {
+        while self.paused && !self.done && !self.queue.is_full() {
+            self.on_readable(context)?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // Hands data messages to the handler, false once the peer closed the connection
+    fn on_message(&mut self, message: Message) -> bool {
+        match message {
+            Message::Text(_) | Message::Binary(_) => {
+                if let Some((handler, session)) = self.handler.as_mut() {
+                    handler.on_message(session, message);
+                }
+                return self.send_outgoing();
+            }
+            Message::Pong(payload) => {
+                if let Some(heartbeat) = self.heartbeat.as_mut() {
+                    heartbeat.on_pong(&payload, Instant::now());
+                }
+            }
+            Message::Ping(_) => {}
+            Message::Close(frame) => {
+                self.close_frame = frame;
+                return false;
+            }
+        }
+        true
+    }
+
+    // Queues a data message, false if that failed the connection (Disconnect policy).
+    // With Block, a message that finds the queue full is held until there is room, and
+    // the connection fails once as many are held as the queue takes.
+    fn push(&mut self, message: Message) -> bool {
+        let queued = if self.queue.policy() == OverflowPolicy::Block && self.queue.is_full() {
+            self.queue.hold(message)
+        } else {
+            self.queue.push(message)
+        };
+
+        match queued {
+            Ok(()) => true,
+            Err(Overflow::Full) => unreachable!("full queues hold the message back"),
+            Err(Overflow::Disconnect) => {
+                self.overflow();
+                false
+            }
+        }
+    }
+
+    // Fails the connection with 1008, the client isn't reading what is sent to it
+    fn overflow(&mut self) {
+        self.queue.clear();
+        self.protocol
+            .fail(CloseCode::PolicyViolation, "Send queue full");
+        self.done = true;
+        self.backlog.close();
+    }
+
+    // Moves what was pushed through the registry to the send queue, with Block only while
+    // it has room: the rest waits in the backlog, and so do the threads pushing more
+    fn take_pushed(&mut self) {
+        let block = self.queue.policy() == OverflowPolicy::Block;
+        while !(block && self.queue.is_full()) {
+            let Some(message) = self.backlog.take() else {
+                break;
+            };
+            if !self.push(message) {
+                break;
+            }
+        }
+    }
+
+    fn on_timer(&mut self, now: Instant) -> Result<(), Error> {
+        if self.done {
+            if self.disconnect.is_some_and(|deadline| deadline <= now) {
+                // Resets the connection, the kernel doesn't keep trying to send what is left
+                let _ = SockRef::from(&self.socket).set_linger(Some(Duration::ZERO));
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Client did not read the close frame in time",
+                ));
+            }
+            return Ok(());
+        }
+        if let Some((_, deadline)) = &self.handshake {
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Client did not send its upgrade request in time",
+                ));
+            }
+            return Ok(());
+        }
+
+        let Some(heartbeat) = self.heartbeat.as_mut() else {
+            return Ok(());
+        };
+        match heartbeat.drive(&mut self.protocol, now) {
+            Ok(_) => Ok(()),
+            // The client didn't echo our close frame, there is nothing left to send it
+            Err(e) if !self.protocol.has_output() => Err(e),
+            // On a pong timeout the protocol queued the 1001 close frame, it goes out first
+            Err(_) => {
+                self.done = true;
+                Ok(())
+            }
+        }
+    }
+
+    // When on_timer has something to do next
+    fn deadline(&self) -> Option<Instant> {
+        if self.done {
+            return self.disconnect;
+        }
+        match &self.handshake {
+            Some((_, deadline)) => *deadline,
+            None => self.heartbeat.as_ref().map(Heartbeat::deadline),
+        }
+    }
+
+    // Reads what is available without blocking, up to READ_BUDGET bytes, returns it and
+    // whether the peer closed its end
+    fn read(&mut self) -> Result<(Vec<u8>, bool), Error> {
+        let mut received = Vec::new();
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        let mut budget = READ_BUDGET;
+        self.unread = false;
+
+        let Some(tls) = self.tls.as_mut() else {
+            loop {
+                if budget == 0 {
+                    self.unread = true;
+                    return Ok((received, false));
+                }
+                match self.socket.read(&mut buffer) {
+                    Ok(0) => return Ok((received, true)),
+                    Ok(read) => {
+                        budget = budget.saturating_sub(read);
+                        received.extend_from_slice(&buffer[..read]);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((received, false)),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        // rustls holds at most 16 KiB of plaintext, what it decrypted is taken after
+        // every read or it refuses to read any more
+        loop {
+            if budget == 0 {
+                self.unread = true;
+                return Ok((received, false));
+            }
+            match tls.read_tls(&mut self.socket) {
+                Ok(0) => return Ok((received, true)),
+                Ok(read) => {
+                    budget = budget.saturating_sub(read);
+                    if let Err(e) = tls.process_new_packets() {
+                        // Sends the alert telling the client why
+                        let _ = tls.write_tls(&mut self.socket);
+                        return Err(Error::new(ErrorKind::InvalidData, e));
+                    }
+                    if read_plaintext(tls, &mut received)? {
+                        return Ok((received, true));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok((received, false)),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Queues bytes for the socket, flush sends them
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.tls.as_mut() {
+            Some(tls) => tls.writer().write_all(data),
+            None => {
+                self.outgoing.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    // Writes as much as the socket takes without blocking, encoding the next message
+    // each time everything before it went out
+    fn flush(&mut self) -> Result<(), Error> {
+        while self.write_encoded()? {
+            // The frames the protocol queued itself (pongs, pings, close frames) go first
+            let output = if self.protocol.has_output() {
+                self.protocol.take_output()
+            } else {
+                let Some(message) = self.queue.pop() else {
+                    // Everything the handler sent before asking to close went out
+                    let Some(frame) = self.closing.take() else {
+                        break;
+                    };
+                    let _ = self.protocol.close(frame.code, &frame.reason);
+                    if let Some(heartbeat) = self.heartbeat.as_mut() {
+                        heartbeat.on_close(Instant::now());
+                    }
+                    continue;
+                };
+                // The room it made goes to what is waiting in the backlog
+                if !self.done {
+                    self.take_pushed();
+                }
+                // Fails once the connection is closed, what is still queued is dropped then
+                let _ = self.protocol.send_message(message);
+                self.protocol.take_output()
+            };
+            self.write(&output)?;
+        }
+        Ok(())
+    }
+
+    // Writes the bytes already encoded, false if the socket would block before the end
+    fn write_encoded(&mut self) -> Result<bool, Error> {
+        match self.tls.as_mut() {
+            Some(tls) => {
+                while tls.wants_write() {
+                    match tls.write_tls(&mut self.socket) {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            None => {
+                while !self.outgoing.is_empty() {
+                    match self.socket.write(&self.outgoing) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(written) => {
+                            self.outgoing.drain(..written);
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Anything left to send, flush only leaves something behind when the socket is full
+    fn has_pending(&self) -> bool {
+        let encoded = match &self.tls {
+            Some(tls) => tls.wants_write(),
+            None => !self.outgoing.is_empty(),
+        };
+        encoded || self.protocol.has_output() || !self.queue.is_empty()
+    }
+}
+
+impl Drop for Connection {
+    // Wakes the threads waiting for room in its backlog, also when the event loop stops
+    fn drop(&mut self) {
+        self.backlog.close();
+    }
+}
+
+// Moves what rustls decrypted so far to `received`, true once the client closed its end
+fn read_plaintext(tls: &mut ServerConnection, received: &mut Vec<u8>) -> Result<bool, Error> {
+    let mut buffer = [0; READ_CHUNK_SIZE];
+    loop {
+        match tls.reader().read(&mut buffer) {
+            Ok(0) => return Ok(true),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            // Closed without a close_notify
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(true),
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,937 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustls::ServerConfig;
+use serde_json::{json, Value};
+
+use crate::pubsub::{Broker, PubSubHandler, SessionConfig};
+use crate::rpc::{Dispatcher, RpcHandler};
+use crate::websockets::{
+    CloseCode, CloseFrame, Frame, HeartbeatConfig, Message, OpCode, OverflowPolicy, Registry,
+    Router, SendQueueConfig, Session, TlsConfig, WebSocket, WebSocketConfig, WebSocketHandler,
+};
+use crate::workers::{Reactor, ThreadPool};
+
+const UPGRADE_REQUEST: &str = "GET /ws HTTP/1.1\r\n\
+    Host: 127.0.0.1:8080\r\n\
+    Upgrade: websocket\r\n\
+    Connection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+    Sec-WebSocket-Version: 13\r\n\r\n";
+
+// Starts a reactor behind a listener on a free port
+fn serve(threads: usize, config: WebSocketConfig, tls: Option<Arc<ServerConfig>>) -> SocketAddr {
+    serve_reactor(threads, config, tls).0
+}
+
+// Serves JSON-RPC and pub/sub on /ws, the way the server binary does
+fn route(router: &Router, registry: &Arc<Registry>) -> (Arc<Broker>, Arc<Dispatcher>) {
+    let broker = Broker::new(Arc::clone(registry));
+    let dispatcher = Dispatcher::new();
+    router.route(
+        "/ws",
+        RpcHandler::with_fallback(
+            Arc::clone(&dispatcher),
+            PubSubHandler::new(Arc::clone(&broker)),
+        ),
+    );
+    (broker, dispatcher)
+}
+
+// Same as serve, also returns the broker and the dispatcher of the reactor
+fn serve_reactor(
+    threads: usize,
+    config: WebSocketConfig,
+    tls: Option<Arc<ServerConfig>>,
+) -> (SocketAddr, Arc<Broker>, Arc<Dispatcher>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let reactor = match tls {
+        Some(tls) => Reactor::with_tls(threads, Router::new(), config, tls),
+        None => Reactor::with_config(threads, Router::new(), config),
+    }
+    .unwrap();
+    let (broker, dispatcher) = route(reactor.router(), reactor.registry());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            reactor.execute(stream.unwrap());
+        }
+    });
+    (address, broker, dispatcher)
+}
+
+// Sends the upgrade request, returns the socket and the response headers
+fn upgrade(address: SocketAddr, request: &str) -> (TcpStream, String) {
+    let mut client = TcpStream::connect(address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.write_all(request.as_bytes()).unwrap();
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        client.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    (client, String::from_utf8(response).unwrap())
+}
+
+fn client_frame(op_code: OpCode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Frame::new(op_code, payload.to_vec());
+    frame.mask = true;
+    frame.mask_key = Some([0x37, 0xfa, 0x21, 0x3d]);
+    frame.to_bytes()
+}
+
+// Reads an unmasked frame sent by the server, returns (first byte, payload)
+fn read_server_frame(client: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).unwrap();
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            client.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            client.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    client.read_exact(&mut payload).unwrap();
+    (header[0], payload)
+}
+
+// Reads a JSON text frame, of pub/sub or JSON-RPC
+fn read_json(client: &mut TcpStream) -> Value {
+    let (first_byte, payload) = read_server_frame(client);
+    assert_eq!(first_byte, 0x81);
+    serde_json::from_slice(&payload).unwrap()
+}
+
+fn subscribe_frame(id: u64, topic: &str) -> Vec<u8> {
+    let request = json!({"type": "subscribe", "id": id, "topic": topic});
+    client_frame(OpCode::Text, request.to_string().as_bytes())
+}
+
+#[test]
+fn test_reactor_serves_more_connections_than_threads() {
+    let address = serve(2, WebSocketConfig::default(), None);
+
+    // All open at once, a thread per connection would have run out long ago
+    let mut clients: Vec<TcpStream> = (0..50)
+        .map(|_| {
+            let (mut client, response) = upgrade(address, UPGRADE_REQUEST);
+            assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            assert_eq!(read_json(&mut client), json!({"type": "welcome"}));
+            client
+        })
+        .collect();
+
+    for (i, client) in clients.iter_mut().enumerate() {
+        let frame = subscribe_frame(i as u64, &format!("prices:T{}", i));
+        client.write_all(&frame).unwrap();
+    }
+    for (i, client) in clients.iter_mut().enumerate() {
+        assert_eq!(
+            read_json(client),
+            json!({"type": "ack", "id": i, "topic": format!("prices:T{}", i)})
+        );
+    }
+}
+
+#[test]
+fn test_reactor_reassembles_frames_split_across_reads() {
+    let address = serve(1, WebSocketConfig::default(), None);
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+
+    // One byte at a time, each arriving as a separate readiness event. The topic makes
+    // the frames long enough for a 16 bit payload length.
+    let topic = format!("prices:{}", "A".repeat(200));
+    client.set_nodelay(true).unwrap();
+    for byte in subscribe_frame(1, &topic) {
+        client.write_all(&[byte]).unwrap();
+        thread::sleep(Duration::from_micros(100));
+    }
+
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "ack", "id": 1, "topic": topic})
+    );
+}
+
+#[test]
+fn test_reactor_rejects_bad_requests() {
+    let address = serve(1, WebSocketConfig::default(), None);
+    let request = UPGRADE_REQUEST.replace("Sec-WebSocket-Version: 13", "Sec-WebSocket-Version: 8");
+
+    let (mut client, response) = upgrade(address, &request);
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    // The body follows, then the connection is closed
+    let mut body = String::new();
+    client.read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("Unsupported WebSocket version"));
+}
+
+#[test]
+fn test_reactor_refuses_unrouted_paths() {
+    let address = serve(1, WebSocketConfig::default(), None);
+    let request = UPGRADE_REQUEST.replace("GET /ws ", "GET /ws/missing ");
+
+    let (mut client, response) = upgrade(address, &request);
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let mut body = String::new();
+    client.read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("No WebSocket endpoint at /ws/missing"));
+}
+
+#[test]
+fn test_thread_pool_refuses_unrouted_paths() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // Nothing routed at all
+    let pool = ThreadPool::new(1, Router::new());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            pool.execute(stream.unwrap());
+        }
+    });
+
+    let (mut client, response) = upgrade(address, UPGRADE_REQUEST);
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    let mut body = String::new();
+    client.read_to_string(&mut body).unwrap();
+    assert!(body.starts_with("No WebSocket endpoint at /ws"));
+}
+
+// Records the callbacks it gets, echoes messages and closes on "bye"
+struct Recorder {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl WebSocketHandler for Recorder {
+    fn on_open(&self, session: &mut Session) {
+        let symbol = session.query("symbol").unwrap_or("none").to_owned();
+        self.events.lock().unwrap().push(format!("open {}", symbol));
+        session.send(Message::text(symbol));
+    }
+
+    fn on_message(&self, session: &mut Session, message: Message) {
+        let text = message.into_text().unwrap();
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("message {}", text));
+        session.send(Message::text(text.clone()));
+        if text == "bye" {
+            session.close(CloseCode::Normal, "done");
+        }
+    }
+
+    fn on_close(&self, _session: &Session, frame: Option<&CloseFrame>) {
+        let code = frame.map(|frame| u16::from(frame.code));
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("close {:?}", code));
+    }
+}
+
+#[test]
+fn test_reactor_drives_routed_handlers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let reactor = Reactor::new(1, Router::new()).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    reactor.router().route(
+        "/ws/recorder",
+        Recorder {
+            events: Arc::clone(&events),
+        },
+    );
+    let registry = Arc::clone(reactor.registry());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            reactor.execute(stream.unwrap());
+        }
+    });
+
+    let request = UPGRADE_REQUEST.replace("GET /ws ", "GET /ws/recorder?symbol=AAPL ");
+    let (mut client, response) = upgrade(address, &request);
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert_eq!(read_server_frame(&mut client), (0x81, b"AAPL".to_vec()));
+    wait_for_registered(&registry, 1);
+
+    client
+        .write_all(&client_frame(OpCode::Text, b"hello"))
+        .unwrap();
+    assert_eq!(read_server_frame(&mut client), (0x81, b"hello".to_vec()));
+    // The echo goes out before the close the handler asked for
+    client
+        .write_all(&client_frame(OpCode::Text, b"bye"))
+        .unwrap();
+    assert_eq!(read_server_frame(&mut client), (0x81, b"bye".to_vec()));
+    let (op_code, payload) = read_server_frame(&mut client);
+    assert_eq!(op_code, 0x88);
+    assert_eq!(payload, b"\x03\xE8done".to_vec());
+    client
+        .write_all(&client_frame(OpCode::ConnectionClosed, &payload[..2]))
+        .unwrap();
+
+    wait_for_registered(&registry, 0);
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "open AAPL",
+            "message hello",
+            "message bye",
+            "close Some(1000)"
+        ]
+    );
+}
+
+#[test]
+fn test_reactor_times_out_silent_handshakes() {
+    let config = WebSocketConfig {
+        handshake_timeout: Some(Duration::from_millis(50)),
+        ..WebSocketConfig::default()
+    };
+    let address = serve(1, config, None);
+
+    let mut client = TcpStream::connect(address).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn test_reactor_heartbeat_disconnects_silent_peers() {
+    let config = WebSocketConfig {
+        heartbeat: Some(HeartbeatConfig {
+            interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(100),
+        }),
+        ..WebSocketConfig::default()
+    };
+    let address = serve(1, config, None);
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+
+    // A ping that goes unanswered, then a 1001 close and the end of the connection
+    let (first_byte, _) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x89);
+    let (first_byte, payload) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+    assert_eq!(payload[..2], [0x03, 0xE9]);
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn test_reactor_drops_clients_that_dont_echo_the_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let config = WebSocketConfig {
+        heartbeat: Some(HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_millis(100),
+        }),
+        ..WebSocketConfig::default()
+    };
+    let reactor = Reactor::with_config(1, Router::new(), config).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    reactor.router().route(
+        "/ws",
+        Recorder {
+            events: Arc::clone(&events),
+        },
+    );
+    let registry = Arc::clone(reactor.registry());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            reactor.execute(stream.unwrap());
+        }
+    });
+
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    client
+        .write_all(&client_frame(OpCode::Text, b"bye"))
+        .unwrap();
+    read_server_frame(&mut client);
+    let (first_byte, _) = read_server_frame(&mut client);
+    assert_eq!(first_byte, 0x88);
+
+    // Long before the next ping is due, the connection is closed without the echo
+    let started = std::time::Instant::now();
+    assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    wait_for_registered(&registry, 0);
+    assert_eq!(events.lock().unwrap().last().unwrap(), "close None");
+}
+
+#[test]
+fn test_reactor_reads_past_the_read_budget() {
+    let (address, _broker, dispatcher) = serve_reactor(1, WebSocketConfig::default(), None);
+    dispatcher.register("echo", |(text,): (String,), _| Ok(json!(text)));
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+
+    // Far more than one event reads, the rest is read after re-arming the socket
+    let text = "7".repeat(1 << 20);
+    let call = json!({"jsonrpc": "2.0", "method": "echo", "params": [text], "id": 1});
+    let mut frames = client_frame(OpCode::Text, call.to_string().as_bytes());
+    frames.extend(subscribe_frame(2, "prices:AAPL"));
+    client.write_all(&frames).unwrap();
+
+    let response = read_json(&mut client);
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["result"], text);
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "ack", "id": 2, "topic": "prices:AAPL"})
+    );
+}
+
+// A self-signed certificate for localhost, the server config serving it and a client
+// config trusting it
+fn tls_configs() -> (Arc<ServerConfig>, Arc<rustls::ClientConfig>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!(
+        "finance-app-reactor-tls-{}-{:?}",
+        std::process::id(),
+        thread::current().id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let tls = TlsConfig {
+        cert_path: dir.join("cert.pem"),
+        key_path: dir.join("key.pem"),
+    };
+    std::fs::write(&tls.cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&tls.key_path, certified.signing_key.serialize_pem()).unwrap();
+    let server_config = tls.load().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    (server_config, Arc::new(client_config))
+}
+
+type TlsClient = WebSocket<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>;
+
+// Connects over TLS and upgrades, the greeting is read
+fn wss_client(address: SocketAddr, config: Arc<rustls::ClientConfig>) -> TlsClient {
+    let server_name = rustls_pki_types::ServerName::try_from("localhost").unwrap();
+    let connection = rustls::ClientConnection::new(config, server_name).unwrap();
+    let stream = rustls::StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+
+    let mut ws = WebSocket::client(stream, "localhost", "/ws").unwrap();
+    assert_eq!(
+        ws.read_message().unwrap().into_text().unwrap(),
+        r#"{"type":"welcome"}"#
+    );
+    ws
+}
+
+#[test]
+fn test_reactor_serves_wss() {
+    let (server_config, client_config) = tls_configs();
+    let address = serve(1, WebSocketConfig::default(), Some(server_config));
+
+    let mut ws = wss_client(address, client_config);
+    ws.send_message(Message::text(
+        r#"{"type":"subscribe","id":1,"topic":"account:7:balance"}"#,
+    ))
+    .unwrap();
+    assert_eq!(
+        ws.read_message().unwrap().into_text().unwrap(),
+        r#"{"type":"ack","id":1,"topic":"account:7:balance"}"#
+    );
+}
+
+#[test]
+fn test_reactor_serves_wss_messages_larger_than_tls_buffers() {
+    let (server_config, client_config) = tls_configs();
+    let (address, _broker, dispatcher) =
+        serve_reactor(1, WebSocketConfig::default(), Some(server_config));
+    dispatcher.register("echo", |(text,): (String,), _| Ok(json!(text)));
+
+    // rustls holds 16 KiB of plaintext, these take several reads of records to arrive
+    let mut ws = wss_client(address, client_config);
+    for len in [1 << 10, 100 << 10, 1 << 20] {
+        let text = "7".repeat(len);
+        let call = json!({"jsonrpc": "2.0", "method": "echo", "params": [text], "id": len});
+        ws.send_message(Message::text(call.to_string())).unwrap();
+
+        let response = ws.read_message().unwrap().into_text().unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], len);
+        assert_eq!(response["result"], text);
+    }
+}
+
+// A client subscribed to prices:AAPL that doesn't read while `count` large events are
+// published to it
+fn flood(config: WebSocketConfig, count: usize) -> (TcpStream, Arc<Broker>) {
+    let (address, broker, _) = serve_reactor(1, config, None);
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    client
+        .write_all(&subscribe_frame(1, "prices:AAPL"))
+        .unwrap();
+    read_json(&mut client);
+
+    let data = Value::String("7".repeat(1 << 20));
+    for _ in 0..count {
+        broker.publish("prices:AAPL", data.clone()).unwrap();
+    }
+    (client, broker)
+}
+
+fn queue_of(capacity: usize, policy: OverflowPolicy) -> WebSocketConfig {
+    WebSocketConfig {
+        send_queue: SendQueueConfig {
+            capacity,
+            policy,
+            ..SendQueueConfig::default()
+        },
+        ..WebSocketConfig::default()
+    }
+}
+
+#[test]
+fn test_reactor_stops_reading_while_the_queue_is_full() {
+    let (mut client, _broker) = flood(queue_of(16, OverflowPolicy::Block), 32);
+
+    // Not read before the events ahead of its answer went out
+    thread::sleep(Duration::from_millis(100));
+    client
+        .write_all(&subscribe_frame(2, "prices:MSFT"))
+        .unwrap();
+
+    // Far more than the socket buffers hold, every event still arrives
+    for _ in 0..32 {
+        let event = read_json(&mut client);
+        assert_eq!(event["type"], "event");
+        assert_eq!(event["topic"], "prices:AAPL");
+        assert_eq!(event["data"].as_str().unwrap().len(), 1 << 20);
+    }
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "ack", "id": 2, "topic": "prices:MSFT"})
+    );
+}
+
+#[test]
+fn test_reactor_disconnects_clients_that_dont_read() {
+    let (mut client, broker) = flood(queue_of(1, OverflowPolicy::Disconnect), 64);
+
+    // What was already being written still arrives, then the close
+    let payload = loop {
+        let (first_byte, payload) = read_server_frame(&mut client);
+        if first_byte == 0x88 {
+            break payload;
+        }
+        assert_eq!(first_byte, 0x81);
+    };
+    assert_eq!(payload[..2], [0x03, 0xF0]);
+
+    // Its subscriptions went with it
+    wait_for_registered(broker.registry(), 0);
+    assert_eq!(broker.publish("prices:AAPL", json!(1)), Ok(0));
+}
+
+#[test]
+fn test_reactor_resets_disconnected_clients_after_the_timeout() {
+    let mut config = queue_of(1, OverflowPolicy::Disconnect);
+    config.send_queue.disconnect_timeout = Duration::from_millis(100);
+    let (address, broker, _) = serve_reactor(1, config, None);
+    let registry = broker.registry();
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    wait_for_registered(registry, 1);
+
+    // Far more than the socket buffers hold is being written when the client is disconnected
+    let message = Message::text("7".repeat(16 << 20));
+    registry.broadcast(message.clone());
+    thread::sleep(Duration::from_millis(100));
+    while registry.broadcast(message.clone()) > 0 {}
+
+    // The close frame never got out, the server didn't wait for it past the timeout
+    thread::sleep(Duration::from_millis(300));
+    let mut buffer = vec![0; 1 << 16];
+    let error = loop {
+        match client.read(&mut buffer) {
+            Ok(0) => panic!("closed gracefully, the output should have been discarded"),
+            Ok(_) => {}
+            Err(e) => break e,
+        }
+    };
+    assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn test_reactor_holds_broadcasts_back_until_the_client_reads() {
+    let (address, broker, _) = serve_reactor(1, queue_of(4, OverflowPolicy::Block), None);
+    let registry = Arc::clone(broker.registry());
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    wait_for_registered(&registry, 1);
+
+    // Far more than the queue and the socket buffers hold, the broadcaster waits for room
+    let sent = Arc::new(AtomicUsize::new(0));
+    let broadcaster = {
+        let (registry, sent) = (Arc::clone(&registry), Arc::clone(&sent));
+        thread::spawn(move || {
+            for i in 0..64 {
+                let message = Message::text(format!("{:02}{}", i, "7".repeat(1 << 20)));
+                assert_eq!(registry.broadcast(message), 1);
+                sent.fetch_add(1, Ordering::Relaxed);
+            }
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    assert!(sent.load(Ordering::Relaxed) < 64);
+    assert_eq!(registry.len(), 1);
+
+    // Nothing was lost or reordered once the client reads
+    for i in 0..64 {
+        let (first_byte, payload) = read_server_frame(&mut client);
+        assert_eq!(first_byte, 0x81);
+        assert_eq!(payload[..2], *format!("{:02}", i).as_bytes());
+    }
+    broadcaster.join().unwrap();
+}
+
+#[test]
+fn test_reactor_drops_the_oldest_broadcasts_of_slow_clients() {
+    let (address, broker, _) = serve_reactor(1, queue_of(4, OverflowPolicy::DropOldest), None);
+    let registry = broker.registry();
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    wait_for_registered(registry, 1);
+
+    for i in 0..64 {
+        let message = Message::text(format!("{:02}{}", i, "7".repeat(1 << 20)));
+        assert_eq!(registry.broadcast(message), 1);
+    }
+
+    // Some in the middle were dropped, the newest always arrives last
+    let mut received = Vec::new();
+    while received.last() != Some(&63) {
+        let (first_byte, payload) = read_server_frame(&mut client);
+        assert_eq!(first_byte, 0x81);
+        let i: usize = std::str::from_utf8(&payload[..2]).unwrap().parse().unwrap();
+        received.push(i);
+    }
+    assert!(received.len() < 64);
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(registry.len(), 1);
+}
+
+// Connections register once their handshake is done, right after the greeting went out
+fn wait_for_registered(registry: &Registry, count: usize) {
+    for _ in 0..500 {
+        if registry.len() == count {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!(
+        "{} connections registered, expected {}",
+        registry.len(),
+        count
+    );
+}
+
+#[test]
+fn test_reactor_registry_reaches_connections() {
+    let (address, broker, _) = serve_reactor(2, WebSocketConfig::default(), None);
+    let registry = broker.registry();
+    let mut clients: Vec<TcpStream> = (0..3)
+        .map(|_| {
+            let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+            read_server_frame(&mut client);
+            client
+        })
+        .collect();
+    wait_for_registered(registry, 3);
+
+    assert_eq!(registry.broadcast(Message::text("rates changed")), 3);
+    for client in &mut clients {
+        assert_eq!(read_server_frame(client), (0x81, b"rates changed".to_vec()));
+    }
+
+    // Found by the address it connects from
+    let local_addr = clients[1].local_addr().unwrap();
+    let info = registry
+        .connections()
+        .into_iter()
+        .find(|info| info.remote_addr == Some(local_addr))
+        .unwrap();
+    registry
+        .send_to(info.id, Message::text("just you"))
+        .unwrap();
+    assert_eq!(
+        read_server_frame(&mut clients[1]),
+        (0x81, b"just you".to_vec())
+    );
+
+    // Closing takes the connection out of the registry
+    clients[1]
+        .write_all(&client_frame(OpCode::ConnectionClosed, &[0x03, 0xE8]))
+        .unwrap();
+    wait_for_registered(registry, 2);
+    assert_eq!(registry.get(info.id), None);
+    assert_eq!(registry.broadcast(Message::text("still here")), 2);
+    assert_eq!(
+        read_server_frame(&mut clients[0]),
+        (0x81, b"still here".to_vec())
+    );
+}
+
+#[test]
+fn test_thread_pool_registry_reaches_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let pool = ThreadPool::new(2, Router::new());
+    let registry = Arc::clone(pool.registry());
+    let (broker, _) = route(pool.router(), &registry);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            pool.execute(stream.unwrap());
+        }
+    });
+
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+    wait_for_registered(&registry, 1);
+
+    // Pushed while the worker waits for the client, which still gets answers
+    assert_eq!(registry.broadcast(Message::text("pushed")), 1);
+    assert_eq!(read_server_frame(&mut client), (0x81, b"pushed".to_vec()));
+    client.write_all(&subscribe_frame(1, "prices:*")).unwrap();
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "ack", "id": 1, "topic": "prices:*"})
+    );
+    assert_eq!(broker.publish("prices:AAPL", json!(187.5)), Ok(1));
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "event", "topic": "prices:AAPL", "data": 187.5})
+    );
+
+    drop(client);
+    wait_for_registered(&registry, 0);
+    assert!(broker.subscriptions(1).is_empty());
+}
+
+#[test]
+fn test_thread_pool_registry_reaches_wss_connections() {
+    let (server_config, client_config) = tls_configs();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let pool = ThreadPool::with_tls(2, Router::new(), WebSocketConfig::default(), server_config);
+    let registry = Arc::clone(pool.registry());
+    let (broker, dispatcher) = route(pool.router(), &registry);
+    dispatcher.register("echo", |(text,): (String,), _| Ok(json!(text)));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            pool.execute(stream.unwrap());
+        }
+    });
+
+    let mut ws = wss_client(address, client_config);
+    wait_for_registered(&registry, 1);
+
+    // Pushed while the worker waits for the client, which still gets answers
+    assert_eq!(registry.broadcast(Message::text("pushed")), 1);
+    assert_eq!(ws.read_message().unwrap(), Message::text("pushed"));
+    ws.send_message(Message::text(
+        r#"{"type":"subscribe","id":1,"topic":"prices:*"}"#,
+    ))
+    .unwrap();
+    assert_eq!(
+        ws.read_message().unwrap().into_text().unwrap(),
+        r#"{"type":"ack","id":1,"topic":"prices:*"}"#
+    );
+    // Larger than a TLS record, both ways
+    let text = "7".repeat(100 << 10);
+    let call = json!({"jsonrpc": "2.0", "method": "echo", "params": [text], "id": 2});
+    ws.send_message(Message::text(call.to_string())).unwrap();
+    let response: Value =
+        serde_json::from_str(&ws.read_message().unwrap().into_text().unwrap()).unwrap();
+    assert_eq!(response["result"], text);
+    assert_eq!(broker.publish("prices:AAPL", json!(text)), Ok(1));
+    let event: Value =
+        serde_json::from_str(&ws.read_message().unwrap().into_text().unwrap()).unwrap();
+    assert_eq!(event["data"], text);
+
+    drop(ws);
+    wait_for_registered(&registry, 0);
+}
+
+#[test]
+fn test_reactor_resumes_sessions_on_new_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let reactor = Reactor::new(1, Router::new()).unwrap();
+    let registry = Arc::clone(reactor.registry());
+    let broker = Broker::with_sessions(Arc::clone(&registry), SessionConfig::default());
+    reactor
+        .router()
+        .route("/ws", PubSubHandler::new(Arc::clone(&broker)));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            reactor.execute(stream.unwrap());
+        }
+    });
+
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    let welcome = read_json(&mut client);
+    let session = welcome["session"].as_str().unwrap().to_owned();
+    client
+        .write_all(&subscribe_frame(1, "account:7:transactions"))
+        .unwrap();
+    read_json(&mut client);
+    assert_eq!(broker.publish("account:7:transactions", json!(1)), Ok(1));
+    assert_eq!(read_json(&mut client)["seq"], 1);
+
+    // Published while the client is away
+    drop(client);
+    wait_for_registered(&registry, 0);
+    assert_eq!(broker.publish("account:7:transactions", json!(2)), Ok(0));
+
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    assert_ne!(read_json(&mut client)["session"], session.as_str());
+    let resume = json!({"type": "resume", "session": session, "last_seq": 1});
+    client
+        .write_all(&client_frame(OpCode::Text, resume.to_string().as_bytes()))
+        .unwrap();
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "resumed", "session": session, "seq": 2})
+    );
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "event", "topic": "account:7:transactions", "data": 2, "seq": 2})
+    );
+    assert_eq!(broker.publish("account:7:transactions", json!(3)), Ok(1));
+    assert_eq!(read_json(&mut client)["seq"], 3);
+}
+
+#[test]
+fn test_reactor_publishes_to_subscribers() {
+    let (address, broker, _) = serve_reactor(2, WebSocketConfig::default(), None);
+    broker.authorize("account", |info, pattern| {
+        info.user.as_deref() == pattern.split(':').nth(1)
+    });
+    let mut clients: Vec<TcpStream> = (0..2)
+        .map(|_| {
+            let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+            read_server_frame(&mut client);
+            client
+        })
+        .collect();
+    wait_for_registered(broker.registry(), 2);
+
+    clients[0]
+        .write_all(&subscribe_frame(1, "prices:*"))
+        .unwrap();
+    read_json(&mut clients[0]);
+    clients[1]
+        .write_all(&subscribe_frame(1, "prices:AAPL"))
+        .unwrap();
+    read_json(&mut clients[1]);
+
+    assert_eq!(broker.publish("prices:AAPL", json!(187.5)), Ok(2));
+    assert_eq!(broker.publish("prices:MSFT", json!(402.1)), Ok(1));
+    let event = json!({"type": "event", "topic": "prices:AAPL", "data": 187.5});
+    assert_eq!(read_json(&mut clients[0]), event);
+    assert_eq!(read_json(&mut clients[1]), event);
+    assert_eq!(read_json(&mut clients[0])["topic"], "prices:MSFT");
+
+    // Nobody set a user on the connection, so the account namespace is closed to it
+    clients[1]
+        .write_all(&subscribe_frame(2, "account:42:#"))
+        .unwrap();
+    assert_eq!(read_json(&mut clients[1])["code"], "forbidden");
+
+    // Closing drops the subscriptions
+    clients[0]
+        .write_all(&client_frame(OpCode::ConnectionClosed, &[0x03, 0xE8]))
+        .unwrap();
+    wait_for_registered(broker.registry(), 1);
+    assert_eq!(broker.publish("prices:MSFT", json!(402.3)), Ok(0));
+}
+
+#[test]
+fn test_reactor_serves_rpc_and_pushes_on_one_connection() {
+    let (address, broker, dispatcher) = serve_reactor(1, WebSocketConfig::default(), None);
+    let publisher = Arc::clone(&broker);
+    dispatcher.register(
+        "transactions.create",
+        move |(account, amount): (u64, f64), _| {
+            let topic = format!("account:{}:transactions", account);
+            publisher
+                .publish(&topic, json!({"amount": amount}))
+                .unwrap();
+            Ok(json!({"created": true}))
+        },
+    );
+    let (mut client, _) = upgrade(address, UPGRADE_REQUEST);
+    read_server_frame(&mut client);
+
+    client
+        .write_all(&subscribe_frame(1, "account:42:#"))
+        .unwrap();
+    assert_eq!(read_json(&mut client)["type"], "ack");
+
+    // Events published by the call reach the event loop through the registry, after
+    // the response it queues right away
+    let call = json!({
+        "jsonrpc": "2.0",
+        "method": "transactions.create",
+        "params": [42, 12.5],
+        "id": "t1"
+    });
+    client
+        .write_all(&client_frame(OpCode::Text, call.to_string().as_bytes()))
+        .unwrap();
+    assert_eq!(
+        read_json(&mut client),
+        json!({"jsonrpc": "2.0", "result": {"created": true}, "id": "t1"})
+    );
+    assert_eq!(
+        read_json(&mut client),
+        json!({"type": "event", "topic": "account:42:transactions", "data": {"amount": 12.5}})
+    );
+
+    let batch = json!([
+        {"jsonrpc": "2.0", "method": "transactions.delete", "id": 1},
+        {"jsonrpc": "2.0", "method": "transactions.create", "params": [7, 1.0]}
+    ]);
+    client
+        .write_all(&client_frame(OpCode::Text, batch.to_string().as_bytes()))
+        .unwrap();
+    let responses = read_json(&mut client);
+    assert_eq!(responses.as_array().unwrap().len(), 1);
+    assert_eq!(responses[0]["error"]["code"], crate::rpc::METHOD_NOT_FOUND);
+}
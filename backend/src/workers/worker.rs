@@ -1,11 +1,17 @@
 use std::{
-    net::TcpStream,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
     sync::{mpsc, Arc, Mutex},
     thread,
-    time::{Duration, Instant},
 };
 
-use crate::websockets::{OpCode, WebSocket};
+use rustls::ServerConfig;
+
+use crate::websockets::{
+    accept_tls, Message as WsMessage, ReadTimeout, Registration, Registry, Request, Router,
+    Session, SharedTlsStream, TryClone, WebSocket, WebSocketConfig, WebSocketHandler,
+    WebSocketReader, WebSocketWriter,
+};
 pub enum Message {
     NewConnection(TcpStream),
     Terminate,
@@ -20,14 +26,40 @@ pub struct Worker {
     thread: thread::JoinHandle<()>,
 }
 impl Worker {
-    pub fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    pub fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+        config: Arc<WebSocketConfig>,
+        tls: Option<Arc<ServerConfig>>,
+        router: Arc<Router>,
+        registry: Arc<Registry>,
+    ) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv().unwrap();
 
             match message {
                 Message::NewConnection(stream) => {
                     println!("Worker {} handling connection", id);
-                    handle_connection(stream);
+                    // Covers the TLS and WebSocket handshakes, a client that never
+                    // sends its request doesn't get to hold the worker
+                    if let Err(e) = stream.set_read_timeout(config.handshake_timeout) {
+                        println!("Failed to set the handshake timeout: {}", e);
+                        continue;
+                    }
+                    let remote_addr = stream.peer_addr().ok();
+                    match &tls {
+                        Some(tls) => match accept_tls(stream, tls) {
+                            Ok(stream) => handle_connection(
+                                SharedTlsStream::new(stream),
+                                remote_addr,
+                                &config,
+                                &router,
+                                &registry,
+                            ),
+                            Err(e) => println!("TLS handshake failed: {}", e),
+                        },
+                        None => handle_connection(stream, remote_addr, &config, &router, &registry),
+                    }
                 }
                 Message::Terminate => {
                     println!("Worker {} terminating", id);
@@ -39,53 +71,106 @@ impl Worker {
         Worker { id, thread }
     }
 }
-fn handle_connection(stream: std::net::TcpStream) {
-    println!("Handling connection: {:?}", stream);
-
-    // Create WebSocket connection
-    let mut ws = match WebSocket::accept(stream) {
-        Ok(ws) => ws,
+// Runs the handshake, None if the connection is already over. Upgrades to a path the
+// router has no handler for are refused with a 404.
+fn accept<S: Read + Write + ReadTimeout>(
+    stream: S,
+    config: &WebSocketConfig,
+    router: &Router,
+) -> Option<(WebSocket<S>, Request, Arc<dyn WebSocketHandler>)> {
+    let (mut ws, request, handler) = match WebSocket::accept_routed(stream, config, router) {
+        Ok(accepted) => accepted,
         Err(e) => {
             println!("{}", e);
+            return None;
+        }
+    };
+
+    // Pings the client while waiting for its messages, replacing the handshake timeout
+    let keepalive = match config.heartbeat {
+        Some(heartbeat) => {
+            ws.enable_heartbeat(heartbeat);
+            Ok(())
+        }
+        None => ws.get_ref().set_read_timeout(None),
+    };
+    if let Err(e) = keepalive {
+        println!("Failed to set the read timeout: {}", e);
+        return None;
+    }
+    Some((ws, request, handler))
+}
+
+// Serves the connection over split halves, so messages can be pushed to the client
+// through the registry while the worker waits for its messages
+fn handle_connection<S: Read + Write + ReadTimeout + TryClone + Send + 'static>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    config: &WebSocketConfig,
+    router: &Router,
+    registry: &Arc<Registry>,
+) {
+    let Some((ws, request, handler)) = accept(stream, config, router) else {
+        return;
+    };
+    let subprotocol = ws.subprotocol().map(str::to_owned);
+    let (reader, writer) = match ws.split() {
+        Ok(halves) => halves,
+        Err(e) => {
+            println!("Failed to split the connection: {}", e);
             return;
         }
     };
+    let registration = registry.register(remote_addr, subprotocol, Arc::new(writer.clone()));
+    serve(reader, writer, &request, handler, registration, registry);
+}
 
-    let ping_interval = Duration::from_secs(30); // Send ping every 30 seconds
-    let mut last_ping = Instant::now();
+// Drives the handler until the connection is over, it's unregistered once on_close returned
+fn serve<S: Read + Write>(
+    mut reader: WebSocketReader<S>,
+    writer: WebSocketWriter,
+    request: &Request,
+    handler: Arc<dyn WebSocketHandler>,
+    registration: Registration,
+    registry: &Arc<Registry>,
+) {
+    let mut session = Session::new(Some(registration.id()), request, Arc::clone(registry));
+    handler.on_open(&mut session);
 
-    ws.send("Hello from the server!".as_bytes().to_vec())
-        .expect("Failed to send message");
+    let frame = loop {
+        // What the handler queued goes out before reading the next message
+        let (messages, close) = session.take_outgoing();
+        let sent = messages
+            .into_iter()
+            .try_for_each(|message| writer.send_message(message))
+            .and_then(|_| match close {
+                Some(frame) => writer.close(frame.code, &frame.reason),
+                None => Ok(()),
+            });
+        if let Err(e) = sent {
+            eprintln!("Failed to send message: {}", e);
+            handler.on_error(&session, &e);
+            break None;
+        }
 
-    loop {
-        match ws.read_frame() {
-            Ok(frame) => {
-                println!("Received frame: {:?}", frame);
-                match frame.op_code {
-                    OpCode::Text => {
-                        let message = String::from_utf8(frame.payload.clone()).unwrap();
-                        println!("Received message: {}", message);
-                        ws.send(frame.payload).expect("Failed to send message");
-                    }
-                    OpCode::Ping => {
-                        println!("Received ping");
-                        ws.send_pong(frame.payload).expect("Failed to send pong");
-                    }
-                    OpCode::ConnectionClosed => {
-                        println!("Connection closed");
-                        break;
-                    }
-                    _ => {}
-                }
+        match reader.read_message() {
+            Ok(message @ (WsMessage::Text(_) | WsMessage::Binary(_))) => {
+                handler.on_message(&mut session, message);
+            }
+            // read_message already answered the close frame
+            Ok(WsMessage::Close(frame)) => {
+                println!("Connection closed");
+                break frame;
             }
+            // and the pings
+            Ok(_) => {}
             Err(e) => {
-                eprintln!("Failed to read frame: {}", e);
-                break;
+                eprintln!("Failed to read message: {}", e);
+                handler.on_error(&session, &e);
+                break None;
             }
         }
-        if last_ping.elapsed() >= ping_interval {
-            ws.send_ping(vec![]).expect("Failed to send ping");
-            last_ping = Instant::now();
-        }
-    }
+    };
+    handler.on_close(&session, frame.as_ref());
+    drop(registration);
 }